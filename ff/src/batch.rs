@@ -0,0 +1,252 @@
+//! Batch mode, run a file of ff commands over a single connection
+//!
+//! Each line holds an ff command without the program name, as it would be
+//! typed in the shell. Empty lines and lines starting with `#` are ignored.
+//! The output of a command can be saved in a variable
+//!
+//!     NAME = COMMAND ARGS...
+//!
+//! and used in later lines as `$NAME` or `${NAME}`. Unknown variables are
+//! looked up in the environment.
+//!
+//! `ff pool` runs the same script for a list of URLs on a `ff::Pool` of
+//! browsers, with `$URL` set to the URL of each run.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use clap::ArgMatches;
+use marionette::Result;
+use shell_words;
+
+use output::{Format, Output};
+use super::{browser_builder, invalid_input, parse_command, run_command, Connector};
+
+/// Browsers started by `ff pool` unless --size is given
+const DEFAULT_POOL_SIZE: usize = 4;
+
+/// Replace `$NAME` and `${NAME}` in a word
+fn expand(word: &str, vars: &HashMap<String, String>) -> Result<String> {
+    let mut res = String::new();
+    let mut chars = word.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            res.push(c);
+            continue;
+        }
+
+        let mut name = String::new();
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => name.push(c),
+                    None => return Err(invalid_input("Unterminated variable", word)),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+        }
+
+        if name.is_empty() {
+            res.push('$');
+            continue;
+        }
+        match vars.get(&name).cloned().or_else(|| env::var(&name).ok()) {
+            Some(val) => res.push_str(&val),
+            None => return Err(invalid_input("Undefined variable", name)),
+        }
+    }
+    Ok(res)
+}
+
+fn is_identifier(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Run a single line, if the line assigns a variable the output is
+/// captured into `vars`. Otherwise the output is printed, or appended to
+/// `captured` when given.
+fn run_step(line: &str,
+            conn: &mut Connector,
+            format: Format,
+            vars: &mut HashMap<String, String>,
+            captured: Option<&mut String>) -> Result<()> {
+    let mut words = shell_words::split(line).map_err(|err| invalid_input("Invalid line", err))?;
+    let target = if words.len() > 2 && words[1] == "=" && is_identifier(&words[0]) {
+        let name = words.remove(0);
+        words.remove(0);
+        Some(name)
+    } else {
+        None
+    };
+
+    let words = words.iter()
+        .map(|word| expand(word, vars))
+        .collect::<Result<Vec<_>>>()?;
+    let (argv, matches) = parse_command(words).map_err(|msg| invalid_input("Invalid command", msg))?;
+    let (name, args) = matches.subcommand();
+    if name == "shell" || name == "run" || name == "pool" {
+        return Err(invalid_input("Unsupported command", name));
+    }
+
+    let format = matches.value_of("FORMAT")
+        .and_then(|f| f.parse().ok())
+        .unwrap_or(format);
+    let mut out = match (&target, &captured) {
+        (None, None) => Output::new(format),
+        _ => Output::capture(format),
+    };
+    let res = run_command(name, args.unwrap(), &argv, conn, &mut out);
+    out.finish();
+    res?;
+
+    let value = out.captured().unwrap_or_default();
+    match (target, captured) {
+        (Some(target), _) => {
+            vars.insert(target, value.trim_end_matches('\n').to_owned());
+        }
+        (None, Some(captured)) => captured.push_str(&value),
+        (None, None) => (),
+    }
+    Ok(())
+}
+
+/// A batch script and the options of the run command
+struct Batch {
+    source: String,
+    format: Format,
+    continue_on_error: bool,
+    timing: bool,
+}
+
+impl Batch {
+    /// The script in `path`, or stdin
+    fn load(path: Option<&str>, args: &ArgMatches, format: Format) -> io::Result<Self> {
+        let source = match path {
+            None | Some("-") => {
+                let mut source = String::new();
+                io::stdin().read_to_string(&mut source)?;
+                source
+            }
+            Some(path) => fs::read_to_string(path)?,
+        };
+        Ok(Batch {
+            source,
+            format,
+            continue_on_error: args.is_present("CONTINUE-ON-ERROR"),
+            timing: args.is_present("TIMING"),
+        })
+    }
+
+    /// Run every line, output goes to `captured` when given
+    fn run(&self, conn: &mut Connector, mut vars: HashMap<String, String>, mut captured: Option<&mut String>) -> Result<()> {
+        let mut failed = 0;
+        for (idx, line) in self.source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let start = Instant::now();
+            let res = run_step(line, conn, self.format, &mut vars, captured.as_deref_mut());
+            if self.timing {
+                eprintln!("{:>4} {:>9.3}s {}", idx + 1, start.elapsed().as_secs_f64(), line);
+            }
+
+            if let Err(err) = res {
+                let msg = format!("line {}: {}", idx + 1, err);
+                if !self.continue_on_error {
+                    return Err(invalid_input("Batch failed", msg));
+                }
+                eprintln!("{}", msg);
+                failed += 1;
+            }
+        }
+
+        if failed > 0 {
+            return Err(invalid_input("Batch failed", format!("{} commands failed", failed)));
+        }
+        Ok(())
+    }
+}
+
+/// Run the batch script given in SCRIPT, or stdin
+pub fn run(args: &ArgMatches, format: Format) -> Result<()> {
+    let batch = Batch::load(args.value_of("SCRIPT"), args, format)?;
+
+    let mut conn = Connector::new();
+    if args.is_present("PORT") {
+        conn.connection(args)?;
+    }
+    batch.run(&mut conn, HashMap::new(), None)
+}
+
+/// Run the batch script once for every URL, with `$URL` set, on a pool of
+/// browsers. The output of each run is printed once the run is done.
+pub fn run_pool(args: &ArgMatches, format: Format) -> Result<()> {
+    let batch = Batch::load(args.value_of("SCRIPT"), args, format)?;
+    let urls = match args.value_of("URLS") {
+        None | Some("-") => {
+            let mut text = String::new();
+            io::stdin().read_to_string(&mut text)?;
+            text
+        }
+        Some(path) => fs::read_to_string(path)?,
+    };
+    let urls: Vec<&str> = urls.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+
+    let size = match args.value_of("SIZE") {
+        Some(s) => usize::from_str(s).ok().filter(|&n| n > 0).ok_or_else(|| invalid_input("Invalid pool size", s))?,
+        None => DEFAULT_POOL_SIZE,
+    };
+    let mut pool = ff::Pool::new(browser_builder(args, Some(0), None, None)?, size.min(urls.len().max(1)))?;
+    if let Some(s) = args.value_of("MAX-USES") {
+        let uses = usize::from_str(s).ok().filter(|&n| n > 0).ok_or_else(|| invalid_input("Invalid number of uses", s))?;
+        pool = pool.max_uses(uses);
+    }
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..pool.size() {
+            scope.spawn(|| while let Some(url) = urls.get(next.fetch_add(1, Ordering::SeqCst)) {
+                let mut captured = String::new();
+                let res = pool.get().and_then(|mut pooled| {
+                    let port = pooled.port();
+                    let mut conn = Connector::borrow(&mut pooled, port);
+                    let vars = HashMap::from([("URL".to_owned(), url.to_string())]);
+                    batch.run(&mut conn, vars, Some(&mut captured))
+                });
+
+                print!("{}", captured);
+                if let Err(err) = res {
+                    eprintln!("{}: {}", url, err);
+                    failed.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+        }
+    });
+
+    match failed.into_inner() {
+        0 => Ok(()),
+        n => Err(invalid_input("Pool failed", format!("{} of {} URLs failed", n, urls.len()))),
+    }
+}
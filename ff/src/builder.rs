@@ -0,0 +1,275 @@
+//! Launch browsers with named options
+//!
+//! ```no_run
+//! let (browser, mut conn) = ff::BrowserBuilder::new()
+//!     .headless(true)
+//!     .pref("browser.startup.homepage", "about:blank")
+//!     .launch()
+//!     .unwrap();
+//! conn.get("https://www.mozilla.org").unwrap();
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use marionette::{MarionetteConnection, Result};
+use mozprofile::preferences::{Pref, PrefValue};
+
+use super::{check_tcp_port, logs, Browser, LaunchOptions, ShutdownTimeouts};
+use super::channels::{Channel, Version};
+
+/// Where the marionette port comes from
+#[derive(Debug, Clone, Copy)]
+enum PortChoice {
+    /// Pick a free port before starting firefox
+    Free,
+    /// Let firefox pick the port, see `FirefoxRunner::tmp()`
+    Firefox,
+    Fixed(u16),
+    Disabled,
+}
+
+/// Builder for `Browser`, see the module documentation
+#[derive(Debug, Clone)]
+pub struct BrowserBuilder {
+    port: PortChoice,
+    profile: Option<PathBuf>,
+    firefox_bin: Option<PathBuf>,
+    user_js: Option<PathBuf>,
+    extra_prefs: Vec<PathBuf>,
+    session: Option<String>,
+    addons: Vec<PathBuf>,
+    capture_log: bool,
+    bidi: bool,
+    devtools: bool,
+    options: LaunchOptions,
+}
+
+impl Default for BrowserBuilder {
+    fn default() -> Self {
+        BrowserBuilder {
+            port: PortChoice::Free,
+            profile: None,
+            firefox_bin: None,
+            user_js: None,
+            extra_prefs: Vec::new(),
+            session: None,
+            addons: Vec::new(),
+            capture_log: false,
+            bidi: false,
+            devtools: false,
+            options: LaunchOptions::default(),
+        }
+    }
+}
+
+impl BrowserBuilder {
+    /// A browser with a temporary profile, listening on a free port
+    pub fn new() -> Self {
+        BrowserBuilder::default()
+    }
+
+    /// Marionette port, with port 0 firefox picks the port itself
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = match port {
+            0 => PortChoice::Firefox,
+            port => PortChoice::Fixed(port),
+        };
+        self
+    }
+
+    /// Start firefox without marionette, only `start()` can be used
+    pub fn no_marionette(mut self) -> Self {
+        self.port = PortChoice::Disabled;
+        self
+    }
+
+    /// Use a persistent profile, it is created if it does not exist
+    pub fn profile<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.profile = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Remove the profile given with `profile()` when the browser exits
+    pub fn remove_profile(mut self, remove: bool) -> Self {
+        self.options.remove_profile = remove;
+        self
+    }
+
+    pub fn firefox_bin<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.firefox_bin = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Use the firefox executable of a channel, see `channels::find_firefox()`
+    pub fn channel(mut self, channel: Channel) -> Self {
+        self.options.channel = Some(channel);
+        self
+    }
+
+    /// Refuse to start firefox versions older than `version`
+    pub fn min_version(mut self, version: Version) -> Self {
+        self.options.min_version = Some(version);
+        self
+    }
+
+    /// A user.js file copied into the profile
+    pub fn user_js<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.user_js = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// An additional file copied into the profile
+    pub fn extra_prefs<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.extra_prefs.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Set a preference in the profile
+    pub fn pref<V: Into<PrefValue>>(mut self, name: &str, value: V) -> Self {
+        self.options.prefs.push((name.to_owned(), Pref::new(value)));
+        self
+    }
+
+    /// Name the instance, see `ff::find_instance()`
+    pub fn session(mut self, name: &str) -> Self {
+        self.session = Some(name.to_owned());
+        self
+    }
+
+    pub fn headless(mut self, headless: bool) -> Self {
+        self.options.headless = headless;
+        self
+    }
+
+    /// Run firefox on a private Xvfb display
+    pub fn xvfb(mut self, xvfb: bool) -> Self {
+        self.options.xvfb = xvfb;
+        self
+    }
+
+    pub fn window_size(mut self, width: u32, height: u32) -> Self {
+        self.options.window_size = Some((width, height));
+        self
+    }
+
+    /// An additional firefox command line argument
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.options.args.push(arg.to_owned());
+        self
+    }
+
+    /// An additional environment variable for firefox
+    pub fn env(mut self, name: &str, value: &str) -> Self {
+        self.options.env.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Enable WebDriver BiDi on a free port, see `Browser::bidi_port()`
+    pub fn bidi(mut self, bidi: bool) -> Self {
+        self.bidi = bidi;
+        self
+    }
+
+    /// Enable WebDriver BiDi on the given port
+    pub fn bidi_port(mut self, port: u16) -> Self {
+        self.options.bidi_port = Some(port);
+        self
+    }
+
+    /// Start the devtools server on a free port, see `ff::connect_devtools()`
+    pub fn devtools(mut self, devtools: bool) -> Self {
+        self.devtools = devtools;
+        self
+    }
+
+    /// Start the devtools server on the given port
+    pub fn devtools_port(mut self, port: u16) -> Self {
+        self.options.devtools_port = Some(port);
+        self
+    }
+
+    /// An addon installed by `launch()`
+    pub fn addon<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.addons.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Write firefox output to the instance log, see `ff::logs`
+    pub fn capture_log(mut self, capture: bool) -> Self {
+        self.capture_log = capture;
+        self
+    }
+
+    /// Write firefox output to a file
+    pub fn log_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.options.log_file = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// How long to wait for each step when the browser is shut down
+    pub fn shutdown_timeouts(mut self, quit: Duration, term: Duration) -> Self {
+        self.options.shutdown = ShutdownTimeouts { quit, term };
+        self
+    }
+
+    /// The options for another browser of a pool, with its own temporary
+    /// profile. Firefox picks the port, so browsers started at the same
+    /// time can not race for one.
+    pub(crate) fn pool_member(&self) -> Self {
+        BrowserBuilder {
+            port: PortChoice::Firefox,
+            profile: None,
+            session: None,
+            ..self.clone()
+        }
+    }
+
+    /// Start firefox, addons are not installed
+    pub fn start(self) -> Result<Browser> {
+        let port = match self.port {
+            PortChoice::Free => Some(check_tcp_port(None)?),
+            PortChoice::Firefox => Some(0),
+            PortChoice::Fixed(port) => Some(port),
+            PortChoice::Disabled => None,
+        };
+
+        let mut options = self.options;
+        if self.bidi && options.bidi_port.is_none() {
+            options.bidi_port = Some(check_tcp_port(None)?);
+        }
+        if self.devtools && options.devtools_port.is_none() {
+            options.devtools_port = Some(check_tcp_port(None)?);
+        }
+        if self.capture_log && options.log_file.is_none() {
+            options.log_file = match port {
+                Some(0) => Some(logs::next_startup_log_path()?),
+                Some(port) => Some(logs::log_path(port)?),
+                None => None,
+            };
+        }
+
+        let extra_prefs = match self.extra_prefs.is_empty() {
+            true => None,
+            false => Some(self.extra_prefs.iter().collect()),
+        };
+        Ok(Browser::start_with(port,
+                               self.profile.as_ref(),
+                               self.firefox_bin.as_ref(),
+                               self.user_js.as_ref(),
+                               extra_prefs,
+                               self.session.as_deref(),
+                               options)?)
+    }
+
+    /// Start firefox, wait for marionette and install the addons
+    pub fn launch(self) -> Result<(Browser, MarionetteConnection)> {
+        let addons = self.addons.clone();
+        let mut browser = self.start()?;
+        let mut conn = browser.connect()?;
+        for addon in &addons {
+            conn.addon_install(addon)?;
+        }
+        Ok((browser, conn))
+    }
+}
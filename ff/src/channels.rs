@@ -0,0 +1,234 @@
+//! Firefox release channels and versions
+//!
+//! Several channels can be installed side by side, e.g. release from the
+//! distribution and nightly in `/opt`. The channel of an executable is
+//! taken from the version it reports with `--version`, beta and developer
+//! edition are told apart by their install.
+
+use std::cmp::Ordering;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use runner::firefox_version;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    Release,
+    Esr,
+    Beta,
+    Nightly,
+    DevEdition,
+}
+
+pub const CHANNELS: &[&str] = &["release", "esr", "beta", "nightly", "devedition"];
+
+impl FromStr for Channel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "release" => Ok(Channel::Release),
+            "esr" => Ok(Channel::Esr),
+            "beta" => Ok(Channel::Beta),
+            "nightly" => Ok(Channel::Nightly),
+            "devedition" => Ok(Channel::DevEdition),
+            other => Err(format!("Unknown firefox channel: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Channel::Release => "release",
+            Channel::Esr => "esr",
+            Channel::Beta => "beta",
+            Channel::Nightly => "nightly",
+            Channel::DevEdition => "devedition",
+        })
+    }
+}
+
+impl Channel {
+    /// True if a build with this version belongs to the channel. Developer
+    /// edition builds report beta versions, see `is_devedition()`.
+    pub fn matches(self, version: &Version) -> bool {
+        match self {
+            Channel::Release => version.pre.is_empty(),
+            Channel::Esr => version.pre == "esr",
+            Channel::Beta | Channel::DevEdition => version.pre.starts_with('b'),
+            Channel::Nightly => version.pre.starts_with('a'),
+        }
+    }
+
+    /// False for a developer edition install when looking for beta and the
+    /// other way around
+    fn matches_install(self, firefox_bin: &Path) -> bool {
+        match self {
+            Channel::Beta => !is_devedition(firefox_bin),
+            Channel::DevEdition => is_devedition(firefox_bin),
+            _ => true,
+        }
+    }
+
+    /// Executable names to look for in `$PATH`
+    fn commands(self) -> &'static [&'static str] {
+        match self {
+            Channel::Release => &["firefox"],
+            Channel::Esr => &["firefox-esr", "firefox"],
+            Channel::Beta => &["firefox-beta", "firefox"],
+            Channel::Nightly => &["firefox-nightly", "firefox-trunk", "firefox"],
+            Channel::DevEdition => &["firefox-developer-edition", "firefox-devedition", "firefox"],
+        }
+    }
+
+    /// Usual install locations, for builds unpacked from mozilla.org and
+    /// distribution packages
+    fn install_paths(self) -> &'static [&'static str] {
+        match self {
+            Channel::Release => &["/usr/lib/firefox/firefox", "/opt/firefox/firefox", "/snap/bin/firefox"],
+            Channel::Esr => &["/usr/lib/firefox-esr/firefox-esr", "/opt/firefox-esr/firefox"],
+            Channel::Beta => &["/opt/firefox-beta/firefox"],
+            Channel::Nightly => &["/opt/firefox-nightly/firefox", "/usr/lib/firefox-trunk/firefox-trunk"],
+            Channel::DevEdition => &["/opt/firefox-developer-edition/firefox", "/opt/firefox-dev/firefox"],
+        }
+    }
+}
+
+/// A firefox version as reported by `firefox --version`, e.g. `115.0.2esr`
+/// or `130.0a1`. Versions are compared by number only.
+#[derive(Clone, Debug)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    /// `esr`, `b3` for betas, `a1` for nightly, empty for release builds
+    pub pre: String,
+}
+
+impl Version {
+    fn number(&self) -> (u32, u32, u32) {
+        (self.major, self.minor, self.patch)
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+        let (number, pre) = s.split_at(split);
+        let mut parts = number.split('.').map(u32::from_str);
+        let mut next = || parts.next().unwrap_or(Ok(0));
+        match (next(), next(), next()) {
+            (Ok(major), Ok(minor), Ok(patch)) if !number.is_empty() => Ok(Version {
+                major,
+                minor,
+                patch,
+                pre: pre.to_owned(),
+            }),
+            _ => Err(format!("Invalid firefox version: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)?;
+        if self.patch > 0 {
+            write!(f, ".{}", self.patch)?;
+        }
+        f.write_str(&self.pre)
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Version) -> bool {
+        self.number() == other.number()
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Version) -> Option<Ordering> {
+        Some(self.number().cmp(&other.number()))
+    }
+}
+
+/// Developer edition builds use the `aurora` update channel, it is set in
+/// `defaults/pref/channel-prefs.js` next to the executable. Without that
+/// file the install name decides, e.g. `/opt/firefox-developer-edition`.
+pub fn is_devedition(firefox_bin: &Path) -> bool {
+    let bin = firefox_bin.canonicalize().unwrap_or_else(|_| firefox_bin.to_owned());
+    let prefs = bin.with_file_name("defaults").join("pref").join("channel-prefs.js");
+    if let Some(channel) = fs::read_to_string(prefs).ok().as_deref().and_then(update_channel) {
+        return channel == "aurora";
+    }
+    let name = bin.to_string_lossy().to_lowercase();
+    ["devedition", "developer-edition", "firefox-dev", "aurora"].iter().any(|part| name.contains(part))
+}
+
+/// The value of `app.update.channel` in a prefs file
+fn update_channel(prefs: &str) -> Option<&str> {
+    const PREF: &str = "\"app.update.channel\"";
+    let rest = &prefs[prefs.find(PREF)? + PREF.len()..];
+    let start = rest.find('"')? + 1;
+    let len = rest[start..].find('"')?;
+    Some(&rest[start..start + len])
+}
+
+fn find_in_path(command: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(command))
+        .find(|path| path.is_file())
+}
+
+/// Find the executable of a channel, candidates are checked with
+/// `firefox --version`
+#[cfg(target_os = "linux")]
+pub fn find_firefox(channel: Channel) -> io::Result<PathBuf> {
+    let candidates = channel.commands().iter()
+        .filter_map(|command| find_in_path(command))
+        .chain(channel.install_paths().iter().map(PathBuf::from).filter(|path| path.is_file()));
+
+    let mut found = Vec::new();
+    for path in candidates {
+        match firefox_version(&path) {
+            Some(text) => match text.parse() {
+                Ok(ref version) if channel.matches(version) && channel.matches_install(&path) => return Ok(path),
+                _ => found.push(format!("{} ({})", path.display(), text)),
+            },
+            None => found.push(format!("{} (unknown version)", path.display())),
+        }
+    }
+
+    let msg = match found.is_empty() {
+        true => format!("No firefox {} installation found", channel),
+        false => format!("No firefox {} installation found, other builds: {}", channel, found.join(", ")),
+    };
+    Err(io::Error::new(io::ErrorKind::NotFound, msg))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn find_firefox(channel: Channel) -> io::Result<PathBuf> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+                       format!("Looking up the firefox {} channel is only supported on Linux", channel)))
+}
+
+/// Fail if `version` is older than `min`. An unknown version is accepted,
+/// e.g. on Windows `--version` prints nothing.
+pub fn check_version(firefox_bin: &Path, version: Option<&str>, min: &Version) -> io::Result<()> {
+    let (text, version) = match version.and_then(|text| Some((text, text.parse::<Version>().ok()?))) {
+        Some(version) => version,
+        None => {
+            warn!("Unable to determine the version of {:?}", firefox_bin);
+            return Ok(());
+        }
+    };
+    if version < *min {
+        return Err(io::Error::other(format!("Firefox {} ({}) is older than the minimum supported version {}",
+                                            text, firefox_bin.display(), min)));
+    }
+    Ok(())
+}
@@ -0,0 +1,230 @@
+//! Firefox crash reports
+//!
+//! When firefox crashes it writes a minidump to the `minidumps` folder of
+//! the profile, with metadata in a `.extra` file next to it. Temporary
+//! profiles are removed when the browser exits, so the reports are moved
+//! to `~/.ff/crashes` first.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use marionette::{JsonValue, MarionetteError};
+use serde_json::{self, Map};
+
+/// Folder with the crash reports collected by ff
+pub fn crash_dir() -> io::Result<PathBuf> {
+    let mut path = super::ff_dir()?;
+    path.push("crashes");
+    Ok(path)
+}
+
+/// Folder where firefox writes the crash reports for a profile
+pub fn minidumps_dir(profile: &Path) -> PathBuf {
+    profile.join("minidumps")
+}
+
+/// A minidump and its metadata
+#[derive(Debug, Clone)]
+pub struct CrashReport {
+    pub id: String,
+    pub dump: PathBuf,
+    /// The contents of the `.extra` file, `Null` if it is missing
+    pub extra: JsonValue,
+}
+
+impl CrashReport {
+    fn load(dump: PathBuf) -> Option<Self> {
+        let id = dump.file_stem()?.to_string_lossy().into_owned();
+        let extra = fs::read_to_string(dump.with_extension("extra"))
+            .map(|text| parse_extra(&text))
+            .unwrap_or(JsonValue::Null);
+        Some(CrashReport { id, dump, extra })
+    }
+
+    pub fn extra_path(&self) -> PathBuf {
+        self.dump.with_extension("extra")
+    }
+
+    fn extra_str(&self, key: &str) -> Option<&str> {
+        self.extra.get(key).and_then(JsonValue::as_str)
+    }
+
+    /// Firefox version that crashed
+    pub fn version(&self) -> Option<&str> {
+        self.extra_str("Version")
+    }
+
+    /// Seconds since the unix epoch
+    pub fn crash_time(&self) -> Option<u64> {
+        self.extra_str("CrashTime").and_then(|time| time.parse().ok())
+    }
+
+    /// Crashed at or after `since`, in seconds since the unix epoch. The
+    /// file time is used for reports without a crash time.
+    fn is_newer(&self, since: u64) -> bool {
+        let time = self.crash_time().or_else(|| {
+            let modified = fs::metadata(&self.dump).and_then(|meta| meta.modified()).ok()?;
+            modified.duration_since(UNIX_EPOCH).ok().map(|time| time.as_secs())
+        });
+        time.is_some_and(|time| time >= since)
+    }
+
+    /// The reason given by firefox, e.g. for assertion failures
+    pub fn reason(&self) -> Option<&str> {
+        self.extra_str("MozCrashReason")
+    }
+
+    /// Copy the dump and metadata to a folder
+    pub fn copy_to(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        copy_file(&self.dump, dir)?;
+        if self.extra_path().exists() {
+            copy_file(&self.extra_path(), dir)?;
+        }
+        Ok(())
+    }
+}
+
+fn copy_file(file: &Path, dir: &Path) -> io::Result<()> {
+    let name = file.file_name().ok_or(io::Error::other("Invalid crash report file name"))?;
+    fs::copy(file, dir.join(name))?;
+    Ok(())
+}
+
+/// Older firefox versions write `Key=Value` lines instead of JSON
+fn parse_extra(text: &str) -> JsonValue {
+    if let Ok(value) = serde_json::from_str(text) {
+        return value;
+    }
+    let map: Map<String, JsonValue> = text.lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_owned(), JsonValue::from(value)))
+        .collect();
+    JsonValue::Object(map)
+}
+
+/// The crash reports in a folder, oldest first. A missing folder is not an
+/// error.
+pub fn reports_in(dir: &Path) -> io::Result<Vec<CrashReport>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut reports = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension() == Some(OsStr::new("dmp")) {
+            reports.extend(CrashReport::load(path));
+        }
+    }
+    reports.sort_by_key(|report| (report.crash_time(), report.id.clone()));
+    Ok(reports)
+}
+
+/// Move the crash reports of a profile to `crash_dir()`
+pub fn collect(profile: &Path) -> io::Result<Vec<CrashReport>> {
+    let reports = reports_in(&minidumps_dir(profile))?;
+    if reports.is_empty() {
+        return Ok(reports);
+    }
+
+    let dir = crash_dir()?;
+    let mut moved = Vec::new();
+    for report in reports {
+        report.copy_to(&dir)?;
+        fs::remove_file(&report.dump)?;
+        let _ = fs::remove_file(report.extra_path());
+        let dump = dir.join(report.dump.file_name().unwrap_or(OsStr::new("")));
+        moved.extend(CrashReport::load(dump));
+    }
+    Ok(moved)
+}
+
+/// File with the status of a browser that exited with an error, it is
+/// kept after the instance file is removed
+fn exit_status_path(port: u16) -> io::Result<PathBuf> {
+    let mut path = super::instance_root_path()?;
+    path.push(format!("{}.exit", port));
+    Ok(path)
+}
+
+/// Record how the browser on `port` exited, for `detect()`
+pub fn record_exit(port: u16, status: &ExitStatus) -> io::Result<()> {
+    let path = exit_status_path(port)?;
+    if !status.success() {
+        return fs::write(path, status.to_string());
+    }
+    match fs::remove_file(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+/// The status recorded for a browser on `port` that exited after `since`
+fn exit_status(port: u16, since: u64) -> Option<String> {
+    let path = exit_status_path(port).ok()?;
+    let modified = fs::metadata(&path).and_then(|meta| meta.modified()).ok()?;
+    if modified.duration_since(UNIX_EPOCH).ok()?.as_secs() < since {
+        return None;
+    }
+    fs::read_to_string(path).ok()
+}
+
+/// Turn a lost connection into `BrowserCrashed` if the browser on `port`
+/// exited with an error or left a crash report, other errors are returned
+/// as is
+pub fn detect(port: u16, err: MarionetteError) -> MarionetteError {
+    let err = match err {
+        MarionetteError::ConnectionLost(err) => err,
+        err => return err,
+    };
+
+    let find_instance = || super::instances().ok()
+        .and_then(|instances| instances.into_iter().find(|instance| instance.port == port));
+    let instance = match find_instance() {
+        Some(instance) => instance,
+        None => return MarionetteError::ConnectionLost(err),
+    };
+
+    // The connection may close before the process is gone
+    let start = Instant::now();
+    while instance.is_alive() {
+        if start.elapsed() > Duration::from_secs(2) {
+            return MarionetteError::ConnectionLost(err);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    // The ff process that started firefox collects the crash reports and
+    // records the exit status before removing the instance
+    let start = Instant::now();
+    while find_instance().is_some() && start.elapsed() < Duration::from_secs(2) {
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    let since = instance.started.unwrap_or(0);
+    let mut dirs: Vec<PathBuf> = crash_dir().into_iter().collect();
+    dirs.extend(instance.profile.as_ref().map(|profile| minidumps_dir(profile)));
+    let reports = dirs.iter()
+        .filter_map(|dir| reports_in(dir).ok())
+        .flatten()
+        .filter(|report| report.is_newer(since))
+        .count();
+
+    let mut evidence: Vec<String> = exit_status(port, since).into_iter().collect();
+    if reports > 0 {
+        evidence.push(format!("{} crash reports, see ff crashes", reports));
+    }
+    if evidence.is_empty() {
+        return MarionetteError::ConnectionLost(err);
+    }
+    debug!("Lost the connection to firefox on port {}: {}", port, err);
+    MarionetteError::BrowserCrashed(format!("firefox on port {} exited: {}", port, evidence.join(", ")))
+}
@@ -0,0 +1,264 @@
+//! Share one marionette session between many clients
+//!
+//! Firefox only accepts a single marionette session at a time. The daemon
+//! holds that session and listens on a unix socket next to the instance
+//! file. It speaks the marionette protocol, so clients use
+//! `MarionetteConnection::connect_unix()` as if they were talking to the
+//! browser. Requests from all clients are serialized over the shared
+//! session.
+//!
+//! Marionette has a single current window, frame and context per session.
+//! The daemon remembers them for each client and switches the session back
+//! before running a request of another client than the previous one.
+//!
+//! Session commands are answered by the daemon itself, new sessions reuse
+//! the existing one and deleting a session is a no-op.
+
+use std::fs;
+use std::io::{self, BufReader};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use marionette::{self, commands, Compatibility, JsonValue, MarionetteConnection, MarionetteError, Result};
+use marionette::commands::{Command, CommandInfo};
+use marionette::messages::{Empty, ErrorObject, FrameSwitch, ServerInfo};
+use serde_json::{self, Value};
+
+/// Path to the unix socket for the instance on the given port
+pub fn socket_path(port: u16) -> io::Result<PathBuf> {
+    let mut path = super::instance_root_path()?;
+    path.push(format!("{}.sock", port));
+    Ok(path)
+}
+
+/// A running daemon, the socket is removed on drop
+pub struct Daemon {
+    path: PathBuf,
+}
+
+impl Daemon {
+    /// Start serving `conn` on the socket for `port`
+    pub fn start(port: u16, mut conn: MarionetteConnection) -> Result<Self> {
+        let window = conn.send::<commands::GetWindowHandle>(&Empty {})?.value;
+        let context = conn.send::<commands::GetContext>(&Empty {})?;
+        let state = ClientState {
+            window: serde_json::to_value(window)?,
+            context: serde_json::to_value(context)?,
+            frames: Vec::new(),
+        };
+
+        let path = socket_path(port)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // A leftover socket from an instance that did not exit cleanly
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        let session = Arc::new(Mutex::new(Session { conn, state: state.clone(), initial: state }));
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let session = session.clone();
+                        thread::spawn(move || {
+                            if let Err(err) = serve_client(stream, &session) {
+                                debug!("Daemon client disconnected: {}", err);
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        warn!("Daemon failed to accept client: {}", err);
+                        break;
+                    }
+                }
+            }
+        });
+
+        info!("Daemon listening on {:?}", path);
+        Ok(Daemon { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        debug!("Removing daemon socket");
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Wait until the daemon for `port` accepts connections
+pub fn wait(port: u16, timeout: Duration) -> Result<MarionetteConnection> {
+    let path = socket_path(port)?;
+    let start = Instant::now();
+    loop {
+        if path.exists() {
+            return MarionetteConnection::connect_unix(&path);
+        }
+        if start.elapsed() > timeout {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for the ff daemon").into());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn error_value(err: &MarionetteError) -> Value {
+    match *err {
+        MarionetteError::Call(ref obj) => serde_json::to_value(obj).unwrap_or(Value::Null),
+        ref err => serde_json::to_value(ErrorObject {
+            error: "unknown error".to_owned(),
+            message: err.to_string(),
+            stacktrace: String::new(),
+        }).unwrap_or(Value::Null),
+    }
+}
+
+fn unknown_command(name: &str) -> MarionetteError {
+    MarionetteError::Call(ErrorObject {
+        error: "unknown command".to_owned(),
+        message: name.to_owned(),
+        stacktrace: String::new(),
+    })
+}
+
+/// The shared connection and the state it is switched to
+struct Session {
+    conn: MarionetteConnection,
+    state: ClientState,
+    initial: ClientState,
+}
+
+/// The window, context and frames a client switched to, as the parameters
+/// of the commands that switch back to them
+#[derive(Debug, Clone, PartialEq)]
+struct ClientState {
+    window: JsonValue,
+    context: JsonValue,
+    /// Frames from the top level one
+    frames: Vec<JsonValue>,
+}
+
+impl ClientState {
+    /// Record the effect of a successful command
+    fn update(&mut self, name: &str, params: &JsonValue) {
+        let is = |info: CommandInfo| info.marionette == Some(name) || info.webdriver == Some(name);
+        if is(commands::SwitchToWindow::INFO) {
+            self.window = params.clone();
+            self.frames.clear();
+        } else if is(commands::SetContext::INFO) {
+            self.context = params.clone();
+        } else if is(commands::SwitchToFrame::INFO) {
+            let top = ["element", "id"].iter().all(|key| params.get(key).is_none_or(Value::is_null));
+            if top {
+                self.frames.clear();
+            } else {
+                self.frames.push(params.clone());
+            }
+        } else if is(commands::SwitchToParentFrame::INFO) {
+            self.frames.pop();
+        } else if is(commands::Navigate::INFO) || is(commands::Refresh::INFO) {
+            // Navigation switches to the top level frame
+            self.frames.clear();
+        }
+    }
+}
+
+impl Session {
+    /// Switch the session to the window, context and frames of a client
+    fn switch_to(&mut self, state: &ClientState) -> Result<()> {
+        if self.state == *state {
+            return Ok(());
+        }
+        let compatibility = self.conn.compatibility();
+        let name = |info: CommandInfo| info.name(compatibility).unwrap_or("");
+        // The frames are unknown until all switches succeeded, so a failure
+        // makes the next request switch again
+        self.state.frames = vec![JsonValue::Null];
+
+        if self.state.window != state.window {
+            self.conn.call_raw(name(commands::SwitchToWindow::INFO), state.window.clone())?;
+            self.state.window = state.window.clone();
+        }
+        if self.state.context != state.context {
+            self.conn.call_raw(name(commands::SetContext::INFO), state.context.clone())?;
+            self.state.context = state.context.clone();
+        }
+        self.conn.call_raw(name(commands::SwitchToFrame::INFO), serde_json::to_value(FrameSwitch::top(false))?)?;
+        for frame in &state.frames {
+            self.conn.call_raw(name(commands::SwitchToFrame::INFO), frame.clone())?;
+        }
+        self.state.frames = state.frames.clone();
+        Ok(())
+    }
+}
+
+/// Handle a single request of a client on the shared session
+fn handle(session: &Mutex<Session>, state: &mut ClientState, name: &str, params: JsonValue) -> Result<JsonValue> {
+    let mut session = session.lock().unwrap_or_else(|err| err.into_inner());
+    let webdriver = session.conn.compatibility() == Compatibility::Webdriver;
+    match name {
+        // Clients detect the protocol dialect from the new session command
+        "WebDriver:NewSession" | "newSession" => {
+            if webdriver != name.starts_with("WebDriver:") {
+                return Err(unknown_command(name));
+            }
+            let conn = &session.conn;
+            let timeouts = serde_json::to_value(conn.timeouts())?;
+            let version = Value::from(conn.browser_version());
+            Ok(json_object(vec![
+                ("sessionId", Value::from(conn.session_id())),
+                ("capabilities", json_object(vec![("timeouts", timeouts), ("browserVersion", version)])),
+            ]))
+        }
+        "WebDriver:DeleteSession" | "deleteSession" => Ok(json_object(Vec::new())),
+        _ => {
+            session.switch_to(state)?;
+            let res = session.conn.call_raw(name, params.clone());
+            if res.is_ok() {
+                state.update(name, &params);
+                session.state = state.clone();
+            }
+            res
+        }
+    }
+}
+
+fn json_object(fields: Vec<(&str, Value)>) -> Value {
+    Value::Object(fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+}
+
+fn serve_client(stream: UnixStream, session: &Mutex<Session>) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    // New clients start where the browser session started
+    let mut state = session.lock().unwrap_or_else(|err| err.into_inner()).initial.clone();
+
+    let info = serde_json::to_string(&ServerInfo { marionetteProtocol: 3 })?;
+    marionette::sendframe(&mut writer, &info)?;
+
+    loop {
+        let frame = marionette::readframe(&mut reader)?;
+        let (msgid, name, params) = match serde_json::from_str(&frame)? {
+            Value::Array(ref arr) if arr.len() == 4 && arr[0] == 0 => {
+                let name = arr[2].as_str().ok_or(MarionetteError::UnexpectedType)?;
+                (arr[1].clone(), name.to_owned(), arr[3].clone())
+            }
+            _ => return Err(MarionetteError::UnexpectedType),
+        };
+
+        let resp = match handle(session, &mut state, &name, params) {
+            Ok(value) => Value::Array(vec![Value::from(1), msgid, Value::Null, value]),
+            // Pass on the lost connection, the client checks for a crash
+            Err(err @ MarionetteError::ConnectionLost(_)) => return Err(err),
+            Err(err) => Value::Array(vec![Value::from(1), msgid, error_value(&err), Value::Null]),
+        };
+        marionette::sendframe(&mut writer, &resp.to_string())?;
+    }
+}
@@ -3,7 +3,7 @@ use std::net::TcpListener;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 extern crate mozrunner;
 extern crate mozprofile;
@@ -11,50 +11,191 @@ extern crate mozprofile;
 extern crate log;
 extern crate mktemp;
 extern crate marionette;
-use marionette::{Result, MarionetteConnection};
+use marionette::{Compatibility, Result, MarionetteConnection};
+#[cfg(feature = "bidi")]
+use marionette::bidi::BidiConnection;
+use marionette::rdp::RdpConnection;
 extern crate dirs;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
+#[cfg(unix)]
+extern crate libc;
 
 mod runner;
+mod builder;
+pub use builder::BrowserBuilder;
+mod pool;
+pub use pool::{Pool, PooledConnection};
+pub mod logs;
 use runner::FirefoxRunner;
+pub use runner::{LaunchOptions, ShutdownTimeouts, ACTIVE_PORT_TIMEOUT};
+#[cfg(unix)]
+pub use runner::Xvfb;
 
 pub struct Browser {
     pub runner: FirefoxRunner,
     session_file: Option<PathBuf>,
+    /// Dropped after the runner, so the display outlives the browser
+    #[cfg(unix)]
+    xvfb: Option<Xvfb>,
 }
 
 impl Browser {
+    /// Start firefox, `BrowserBuilder` is easier to use and has more options
     pub fn start<P: AsRef<Path>>(port: Option<u16>,
                                  profile_path: Option<P>,
                                  firefox_path: Option<P>,
                                  userjs_path: Option<P>,
                                  extraprefs_paths: Option<Vec<P>>,
                                  session_name: Option<&str>) -> io::Result<Self> {
+        Self::start_with(port, profile_path, firefox_path, userjs_path, extraprefs_paths, session_name,
+                         LaunchOptions::default())
+    }
+
+    /// Like `start()`, with the options for launching the process
+    pub fn start_with<P: AsRef<Path>>(port: Option<u16>,
+                                      profile_path: Option<P>,
+                                      firefox_path: Option<P>,
+                                      userjs_path: Option<P>,
+                                      extraprefs_paths: Option<Vec<P>>,
+                                      session_name: Option<&str>,
+                                      options: LaunchOptions) -> io::Result<Self> {
+        #[cfg(unix)]
+        let (xvfb, options) = if options.xvfb {
+            let xvfb = Xvfb::start(options.window_size)?;
+            let display = Some(xvfb.display().to_owned());
+            (Some(xvfb), LaunchOptions { display, ..options })
+        } else {
+            (None, options)
+        };
+        #[cfg(not(unix))]
+        {
+            if options.xvfb {
+                return Err(io::Error::other("Xvfb is only supported on unix"));
+            }
+        }
+
         let runner = match profile_path {
-            None => FirefoxRunner::tmp(port, firefox_path, userjs_path, extraprefs_paths)?,
-            Some(path) => FirefoxRunner::from_path(path, port, firefox_path, userjs_path, extraprefs_paths)?,
+            None => FirefoxRunner::tmp(port, firefox_path, userjs_path, extraprefs_paths, options)?,
+            Some(path) => FirefoxRunner::from_path(path, port, firefox_path, userjs_path, extraprefs_paths, options)?,
         };
 
-        let session_file = match port {
-            Some(port) => Some(create_instance_file(session_name, port)?),
+        // Name the startup log after the port firefox picked
+        if let (Some(0), Some(port), Some(log)) = (port, runner.port(), runner.log_file()) {
+            if logs::is_startup_log(&log) {
+                runner.move_log(logs::log_path(port)?)?;
+            }
+        }
+
+        let session_file = match runner.port() {
+            Some(port) => {
+                let instance = Instance {
+                    port,
+                    name: session_name.unwrap_or("").to_string(),
+                    pid: Some(runner.pid()),
+                    ff_pid: Some(std::process::id()),
+                    profile: Some(runner.profile.path.clone()),
+                    firefox_bin: Some(runner.firefox_bin().to_owned()),
+                    version: runner.version().map(str::to_owned),
+                    started: SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs()),
+                    owns_profile: runner.owns_profile(),
+                    bidi_port: runner.options().bidi_port,
+                    devtools_port: runner.options().devtools_port,
+                };
+                Some(create_instance_file(&instance)?)
+            }
             None => None,
         };
 
         Ok(Browser {
             runner: runner,
             session_file: session_file,
+            #[cfg(unix)]
+            xvfb,
         })
     }
 
     pub fn session_file(&self) -> Option<&Path> {
         self.session_file.as_ref().map(|p| p.as_path())
     }
+
+    /// The remote agent port for WebDriver BiDi, if enabled
+    pub fn bidi_port(&self) -> Option<u16> {
+        self.runner.options().bidi_port
+    }
+
+    /// The devtools server port, if enabled
+    pub fn devtools_port(&self) -> Option<u16> {
+        self.runner.options().devtools_port
+    }
+
+    /// Connect to marionette once the browser is ready, fails early if the
+    /// browser exits
+    pub fn connect(&mut self) -> Result<MarionetteConnection> {
+        let port = self.runner.port()
+            .ok_or(io::Error::other("Browser was started without marionette"))?;
+        let start = Instant::now();
+        loop {
+            if let Some(status) = self.runner.process.try_wait()? {
+                return Err(io::Error::other(format!("Firefox exited ({})", status)).into());
+            }
+            match connect_tcp(port, self.runner.version()) {
+                Ok(conn) => return Ok(conn),
+                Err(err) => {
+                    if start.elapsed() > Duration::new(30, 0) {
+                        return Err(err);
+                    }
+                    debug!("Failed to connect to firefox({}): {}", port, err);
+                }
+            }
+            thread::sleep(Duration::from_millis(250));
+        }
+    }
+
+    /// Stop the browser, see `FirefoxRunner::shutdown()`. Marionette quit is
+    /// sent through the ff daemon when one is running.
+    pub fn shutdown(&mut self, timeouts: &ShutdownTimeouts) -> io::Result<std::process::ExitStatus> {
+        let conn = self.runner.port().and_then(|port| connect(port).ok());
+        self.runner.shutdown(conn, timeouts)
+    }
+
+    /// The private X server the browser runs on, see `LaunchOptions::xvfb`
+    #[cfg(unix)]
+    pub fn xvfb(&self) -> Option<&Xvfb> {
+        self.xvfb.as_ref()
+    }
 }
 
 impl Drop for Browser {
     fn drop(&mut self) {
+        // Firefox may write crash reports until it is gone
+        let status = match self.runner.kills_on_drop() {
+            true => {
+                let timeouts = self.runner.options().shutdown;
+                self.runner.shutdown(None, &timeouts)
+                    .map_err(|err| warn!("Failed to stop firefox: {}", err))
+                    .ok()
+            }
+            false => None,
+        };
+
+        // Keep crash reports from profiles that are about to be removed
+        if self.runner.owns_profile() {
+            match crashes::collect(&self.runner.profile.path) {
+                Ok(reports) => for report in reports {
+                    warn!("Firefox crashed, the crash report is in {:?}", report.dump);
+                },
+                Err(err) => warn!("Unable to save crash reports: {}", err),
+            }
+        }
+
+        if let (Some(status), Some(port)) = (status, self.runner.port()) {
+            if let Err(err) = crashes::record_exit(port, &status) {
+                warn!("Unable to record the firefox exit status: {}", err);
+            }
+        }
         if let Some(file) = &self.session_file {
             debug!("Removing session file");
             let _ = fs::remove_file(&file);
@@ -73,37 +214,445 @@ pub fn check_tcp_port(port: Option<u16>) -> io::Result<u16> {
         .map(|x| x.port())
 }
 
-fn instance_root_path() -> io::Result<PathBuf> {
+/// The folder where ff keeps its state, `~/.ff`
+pub fn ff_dir() -> io::Result<PathBuf> {
     let mut path = dirs::home_dir()
         .ok_or(io::Error::new(io::ErrorKind::Other, "Could not determine your HOME folder"))?;
     path.push(".ff");
+    Ok(path)
+}
+
+fn instance_root_path() -> io::Result<PathBuf> {
+    let mut path = ff_dir()?;
     path.push("instances");
     Ok(path)
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+/// A browser instance started by ff, the fields after `name` are missing
+/// in instance files created by older versions of ff.
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Instance {
     pub port: u16,
     pub name: String,
+    /// Firefox process id
+    #[serde(default)]
+    pub pid: Option<u32>,
+    /// Process id for the ff process that started firefox
+    #[serde(default)]
+    pub ff_pid: Option<u32>,
+    #[serde(default)]
+    pub profile: Option<PathBuf>,
+    #[serde(default)]
+    pub firefox_bin: Option<PathBuf>,
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Start time, in seconds since the unix epoch
+    #[serde(default)]
+    pub started: Option<u64>,
+    /// The profile was created by ff, or is removed when the browser exits
+    #[serde(default)]
+    pub owns_profile: bool,
+    /// Remote agent port for WebDriver BiDi, see `BrowserBuilder::bidi()`
+    #[serde(default)]
+    pub bidi_port: Option<u16>,
+    /// Devtools server port, see `BrowserBuilder::devtools()`
+    #[serde(default)]
+    pub devtools_port: Option<u16>,
+}
+
+impl Instance {
+    /// Path to the instance file
+    pub fn path(&self) -> io::Result<PathBuf> {
+        let mut path = instance_root_path()?;
+        path.push(format!("{}", self.port));
+        Ok(path)
+    }
+
+    /// Wait until the browser has exited and released the profile lock
+    pub fn wait_for_exit(&self, timeout: Duration) -> io::Result<()> {
+        let start = Instant::now();
+        loop {
+            let locked = self.profile.as_ref().is_some_and(|profile| profiles::is_locked(profile));
+            if !self.is_alive() && !locked {
+                return Ok(());
+            }
+            if start.elapsed() > timeout {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for firefox to exit"));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// False if the firefox process is known to have exited, or its pid
+    /// belongs to a process started after the instance. Instances without
+    /// a pid are assumed to be alive.
+    pub fn is_alive(&self) -> bool {
+        match self.pid {
+            Some(pid) => process_exists(pid) && !self.pid_reused(pid),
+            None => true,
+        }
+    }
+
+    fn pid_reused(&self, pid: u32) -> bool {
+        // Firefox starts before the instance is created, allow for start
+        // times rounded to the second
+        match (process_start_time(pid), self.started) {
+            (Some(process), Some(started)) => process > started + 1,
+            _ => false,
+        }
+    }
+
+    /// Remove the instance file and any files associated with it
+    pub fn remove(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            let _ = fs::remove_file(daemon::socket_path(self.port)?);
+        }
+        if let Some(profile) = self.owned_profile() {
+            if !self.is_alive() {
+                info!("Removing profile {:?}", profile);
+                remove_dir(profile)?;
+            }
+        }
+        fs::remove_file(self.path()?)
+    }
+
+    /// The profile, if it should be removed with the instance
+    pub fn owned_profile(&self) -> Option<&Path> {
+        match self.owns_profile {
+            true => self.profile.as_deref(),
+            false => None,
+        }
+    }
+}
+
+/// Check if a process is running
+#[cfg(unix)]
+pub fn process_exists(pid: u32) -> bool {
+    // Signal 0 performs error checking only, EPERM means the process
+    // exists but belongs to someone else
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    let exists = res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    exists && !is_zombie(pid)
+}
+
+/// Exited processes that were not reaped yet, e.g. in containers without
+/// an init process
+#[cfg(target_os = "linux")]
+fn is_zombie(pid: u32) -> bool {
+    // The state follows the command name, which is in parentheses
+    fs::read_to_string(format!("/proc/{}/stat", pid))
+        .ok()
+        .and_then(|stat| stat.rsplit(')').next().map(|rest| rest.trim_start().starts_with('Z')))
+        .unwrap_or(false)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_zombie(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(windows)]
+pub fn process_exists(pid: u32) -> bool {
+    match win32::Process::open(pid) {
+        Ok(process) => process.exit_code() == Some(win32::STILL_ACTIVE),
+        // The process exists but belongs to someone else
+        Err(err) => err.raw_os_error() == Some(win32::ERROR_ACCESS_DENIED),
+    }
+}
+
+/// Start time of a process, in seconds since the unix epoch
+#[cfg(target_os = "linux")]
+fn process_start_time(pid: u32) -> Option<u64> {
+    // The start time is the 22nd field, in clock ticks since boot
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let ticks: u64 = stat.rsplit(')').next()?.split_whitespace().nth(19)?.parse().ok()?;
+    let boot: u64 = fs::read_to_string("/proc/stat").ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if hz <= 0 {
+        return None;
+    }
+    Some(boot + ticks / hz as u64)
+}
+
+#[cfg(windows)]
+fn process_start_time(pid: u32) -> Option<u64> {
+    win32::Process::open(pid).ok()?.creation_time()
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn process_start_time(_pid: u32) -> Option<u64> {
+    None
 }
 
-fn create_instance_file(name: Option<&str>, port: u16) -> io::Result<PathBuf> {
-    let mut path = instance_root_path()?;
-    fs::create_dir_all(&path)?;
+/// The few kernel32 functions needed to inspect processes
+#[cfg(windows)]
+mod win32 {
+    use std::io;
+    use std::os::raw::c_void;
+
+    pub const STILL_ACTIVE: u32 = 259;
+    pub const ERROR_ACCESS_DENIED: i32 = 5;
+    const PROCESS_QUERY_LIMITED_INFORMATION: u32 = 0x1000;
+    /// Seconds from 1601, the start of file times, to the unix epoch
+    const EPOCH_OFFSET: u64 = 11_644_473_600;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct FileTime {
+        low: u32,
+        high: u32,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn OpenProcess(access: u32, inherit: i32, pid: u32) -> *mut c_void;
+        fn GetExitCodeProcess(process: *mut c_void, code: *mut u32) -> i32;
+        fn GetProcessTimes(process: *mut c_void, creation: *mut FileTime, exit: *mut FileTime,
+                           kernel: *mut FileTime, user: *mut FileTime) -> i32;
+        fn CloseHandle(handle: *mut c_void) -> i32;
+    }
+
+    /// A process handle, closed on drop
+    pub struct Process(*mut c_void);
+
+    impl Process {
+        pub fn open(pid: u32) -> io::Result<Self> {
+            let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+            if handle.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Process(handle))
+        }
+
+        pub fn exit_code(&self) -> Option<u32> {
+            let mut code = 0;
+            match unsafe { GetExitCodeProcess(self.0, &mut code) } {
+                0 => None,
+                _ => Some(code),
+            }
+        }
+
+        /// Seconds since the unix epoch
+        pub fn creation_time(&self) -> Option<u64> {
+            let (mut creation, mut exit, mut kernel, mut user) = Default::default();
+            if unsafe { GetProcessTimes(self.0, &mut creation, &mut exit, &mut kernel, &mut user) } == 0 {
+                return None;
+            }
+            let FileTime { low, high } = creation;
+            // File times count 100ns intervals
+            let secs = (((high as u64) << 32) | low as u64) / 10_000_000;
+            secs.checked_sub(EPOCH_OFFSET)
+        }
+    }
+
+    impl Drop for Process {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+}
+
+/// Terminate a process, if it is still running after `timeout` it is
+/// killed
+#[cfg(unix)]
+pub fn kill_process(pid: u32, timeout: Duration) -> io::Result<()> {
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let start = Instant::now();
+    while process_exists(pid) {
+        if start.elapsed() > timeout {
+            debug!("Process {} did not exit, sending SIGKILL", pid);
+            if unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn kill_process(pid: u32, _timeout: Duration) -> io::Result<()> {
+    let status = std::process::Command::new("taskkill")
+        .args(&["/F", "/PID", &pid.to_string()])
+        .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, format!("taskkill failed for {}", pid)))
+    }
+}
+
+fn create_instance_file(instance: &Instance) -> io::Result<PathBuf> {
+    let path = instance.path()?;
+    fs::create_dir_all(instance_root_path()?)?;
 
-    path.push(format!("{}", port));
     let f = fs::File::create(&path)?;
-    serde_json::to_writer(f, &Instance { port: port, name: name.unwrap_or("").to_string()})?;
+    serde_json::to_writer(f, instance)?;
     Ok(path)
 }
 
+/// Register a browser that was not started by ff, e.g. one started with
+/// `firefox --marionette`, so it can be used like other instances
+pub fn attach(profile: &Path, session_name: Option<&str>) -> Result<Instance> {
+    let port = profiles::active_port(profile)?;
+    // Only register browsers that answer
+    MarionetteConnection::connect(port)?;
+
+    let instance = Instance {
+        port,
+        name: session_name.unwrap_or("").to_string(),
+        pid: profiles::lock_pid(profile),
+        ff_pid: None,
+        profile: Some(profile.to_owned()),
+        firefox_bin: None,
+        version: profiles::last_version(profile),
+        started: SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs()),
+        owns_profile: false,
+        bidi_port: None,
+        devtools_port: None,
+    };
+    create_instance_file(&instance)?;
+    Ok(instance)
+}
+
+/// Remove a folder, it is fine if it is already gone
+fn remove_dir(path: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+/// Profiles younger than this are not collected, firefox may not have
+/// locked them yet
+const GC_MIN_AGE: Duration = Duration::from_secs(60);
+
+/// Remove instances whose browser has exited together with their profiles,
+/// and temporary profiles that no running browser uses. Returns the
+/// profiles that were removed, with `dry_run` nothing is removed.
+pub fn collect_garbage(dry_run: bool) -> io::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    let mut in_use = Vec::new();
+    for instance in instances()? {
+        if instance.is_alive() {
+            in_use.extend(instance.profile.clone());
+            continue;
+        }
+        let profile = instance.owned_profile().filter(|profile| profile.exists()).map(Path::to_owned);
+        if !dry_run {
+            if let Err(err) = instance.remove() {
+                warn!("Unable to remove instance {}: {}", instance.port, err);
+                continue;
+            }
+        }
+        removed.extend(profile);
+    }
+
+    let entries = match fs::read_dir(profiles::tmp_profile_root()) {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(removed),
+        Err(err) => return Err(err),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        let recent = entry.metadata()?.modified()?
+            .elapsed()
+            .map(|age| age < GC_MIN_AGE)
+            .unwrap_or(true);
+        if !path.is_dir() || recent || in_use.contains(&path) || profiles::is_locked(&path) {
+            continue;
+        }
+        if !dry_run {
+            if let Err(err) = remove_dir(&path) {
+                warn!("Unable to remove {:?}: {}", path, err);
+                continue;
+            }
+        }
+        removed.push(path);
+    }
+    Ok(removed)
+}
+
+/// Match a session name against a pattern, `*` matches any text and `?` a
+/// single character
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Position of the last `*` and the name position it was tried with
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The running instances, sorted by port. With a pattern only the instances
+/// with a matching session name, see `matches_pattern()`.
+pub fn running_instances(pattern: Option<&str>) -> io::Result<Vec<Instance>> {
+    let mut found: Vec<Instance> = instances()?
+        .into_iter()
+        .filter(|instance| instance.is_alive())
+        .filter(|instance| pattern.is_none_or(|pattern| matches_pattern(pattern, &instance.name)))
+        .collect();
+    found.sort_by_key(|instance| instance.port);
+    Ok(found)
+}
+
+/// Find an instance by port number or session name
+pub fn find_instance(key: &str) -> io::Result<Instance> {
+    let mut found: Vec<Instance> = instances()?
+        .into_iter()
+        .filter(|instance| instance.name == key || instance.port.to_string() == key)
+        .collect();
+    match found.len() {
+        0 => Err(io::Error::new(io::ErrorKind::NotFound, format!("No ff instance named {}", key))),
+        1 => Ok(found.remove(0)),
+        _ => {
+            let ports: Vec<String> = found.iter().map(|instance| instance.port.to_string()).collect();
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                               format!("More than one ff instance named {}, use one of the ports {}",
+                                       key, ports.join(", "))))
+        }
+    }
+}
+
 /// List available instances
 pub fn instances() -> io::Result<Vec<Instance>> {
     let mut res = Vec::new();
-    for entry in fs::read_dir(&instance_root_path()?)? {
+    let entries = match fs::read_dir(&instance_root_path()?) {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(res),
+        Err(err) => return Err(err),
+    };
+    for entry in entries {
         let entry = entry?;
         let path = entry.path();
-        if path.is_file() {
+        // Sockets and exit statuses are named after the port as well
+        if path.is_file() && path.extension().is_none() {
             let f = fs::File::open(&path)?;
             if let Ok(instance) = serde_json::from_reader(f) {
                 res.push(instance);
@@ -113,12 +662,63 @@ pub fn instances() -> io::Result<Vec<Instance>> {
     Ok(res)
 }
 
+/// Connect to the instance listening on `port`. If the instance is managed
+/// by an ff daemon the connection goes through the daemon, allowing many
+/// clients to share the browser.
+pub fn connect(port: u16) -> Result<MarionetteConnection> {
+    #[cfg(unix)]
+    {
+        if let Ok(path) = daemon::socket_path(port) {
+            if path.exists() {
+                match MarionetteConnection::connect_unix(&path) {
+                    Ok(conn) => return Ok(conn),
+                    Err(err) => debug!("Unable to use ff daemon for {}: {}", port, err),
+                }
+            }
+        }
+    }
+    let version = instances().ok()
+        .and_then(|instances| instances.into_iter().find(|instance| instance.port == port))
+        .and_then(|instance| instance.version);
+    connect_tcp(port, version.as_deref())
+}
+
+/// Start a WebDriver BiDi session with the instance on `port`, the browser
+/// must have been started with `BrowserBuilder::bidi()`
+#[cfg(feature = "bidi")]
+pub fn connect_bidi(port: u16) -> Result<BidiConnection> {
+    let instance = instances()?.into_iter().find(|instance| instance.port == port)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No ff instance on port {}", port)))?;
+    let bidi_port = instance.bidi_port
+        .ok_or_else(|| io::Error::other(format!("The browser on port {} was started without BiDi", port)))?;
+    BidiConnection::connect(bidi_port)
+}
+
+/// Connect to the devtools server of the instance on `port`, the browser
+/// must have been started with `BrowserBuilder::devtools()`
+pub fn connect_devtools(port: u16) -> Result<RdpConnection> {
+    let instance = instances()?.into_iter().find(|instance| instance.port == port)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No ff instance on port {}", port)))?;
+    let devtools_port = instance.devtools_port
+        .ok_or_else(|| io::Error::other(format!("The browser on port {} was started without devtools", port)))?;
+    RdpConnection::connect(devtools_port)
+}
+
+/// Connect to marionette, with the protocol dialect of the firefox version
+/// when it is known
+fn connect_tcp(port: u16, version: Option<&str>) -> Result<MarionetteConnection> {
+    match version.and_then(Compatibility::from_version) {
+        Some(compatibility) => MarionetteConnection::connect_as(port, compatibility),
+        None => MarionetteConnection::connect(port),
+    }
+}
+
 /// Test the marionette connection by attempting to connect multiple times
 pub fn check_connection(port: u16) -> Result<MarionetteConnection> {
     let mut retry = 1;
     loop {
         thread::sleep(Duration::new(retry*2, 0));
-        match marionette::MarionetteConnection::connect(port) {
+        match connect(port) {
             Ok(conn) => return Ok(conn),
             Err(err) => {
                 debug!("#{} Failed to connect to firefox({}): {}", retry, port, err);
@@ -131,4 +731,11 @@ pub fn check_connection(port: u16) -> Result<MarionetteConnection> {
     }
 }
 
+pub mod channels;
+pub mod crashes;
 pub mod downloads;
+pub mod profiles;
+pub mod scripts;
+pub mod webdriver;
+#[cfg(unix)]
+pub mod daemon;
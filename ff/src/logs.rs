@@ -0,0 +1,165 @@
+//! Firefox output logs
+//!
+//! The stdout and stderr of a browser started with a port are written to
+//! `~/.ff/instances/<port>.log`. The file is rotated when the browser starts
+//! and when it grows too large, older logs are kept as `<port>.log.1`,
+//! `<port>.log.2`, ...
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Size after which the log is rotated
+pub const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+/// Number of rotated logs that are kept
+pub const KEEP_LOGS: usize = 3;
+
+/// Path to the log file for the instance on the given port
+pub fn log_path(port: u16) -> io::Result<PathBuf> {
+    let mut path = super::instance_root_path()?;
+    path.push(format!("{}.log", port));
+    Ok(path)
+}
+
+/// Log file for a browser started with port 0 by the process `pid`, it is
+/// renamed to `log_path()` once the port is known
+pub fn startup_log_path(pid: u32) -> io::Result<PathBuf> {
+    let mut path = super::instance_root_path()?;
+    path.push(format!("starting-{}.log", pid));
+    Ok(path)
+}
+
+/// Browsers started with port 0 by this process
+static STARTUP_LOGS: AtomicUsize = AtomicUsize::new(0);
+
+/// Log file for the next browser this process starts with port 0. The
+/// first one is `startup_log_path()`, later ones are numbered so browsers
+/// starting at the same time do not share a log.
+pub fn next_startup_log_path() -> io::Result<PathBuf> {
+    let pid = std::process::id();
+    match STARTUP_LOGS.fetch_add(1, Ordering::SeqCst) {
+        0 => startup_log_path(pid),
+        n => {
+            let mut path = super::instance_root_path()?;
+            path.push(format!("starting-{}-{}.log", pid, n));
+            Ok(path)
+        }
+    }
+}
+
+/// True for the log of a browser that does not know its port yet
+pub fn is_startup_log(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with("starting-") && name.ends_with(".log"))
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// A log file that is rotated once it reaches `max_size` bytes
+pub struct RotatingLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl RotatingLog {
+    /// Start a new log at `path`, an existing log is rotated
+    pub fn create<P: AsRef<Path>>(path: P, max_size: u64, keep: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        rotate(&path, keep)?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(RotatingLog { path, file, size: 0, max_size, keep })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rename the log, a log already at `path` is rotated
+    pub fn move_to<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref().to_owned();
+        rotate(&path, self.keep)?;
+        fs::rename(&self.path, &path)?;
+        self.path = path;
+        Ok(())
+    }
+}
+
+impl Write for RotatingLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            rotate(&self.path, self.keep)?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            self.size = 0;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Shift `path` to `path.1`, `path.1` to `path.2` and so on, dropping the
+/// oldest log
+fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    if keep == 0 {
+        return fs::remove_file(path);
+    }
+    for n in (1..keep).rev() {
+        let from = rotated_path(path, n);
+        if from.exists() {
+            fs::rename(&from, rotated_path(path, n + 1))?;
+        }
+    }
+    fs::rename(path, rotated_path(path, 1))
+}
+
+/// Copy lines from `input` to the log until the end of input
+pub fn capture<R: Read + Send + 'static>(input: R, log: Arc<Mutex<RotatingLog>>) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(input);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    let mut log = log.lock().unwrap_or_else(|err| err.into_inner());
+                    if let Err(err) = log.write_all(&line).and_then(|_| log.flush()) {
+                        warn!("Unable to write firefox log: {}", err);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// The last `n` lines of a log file
+pub fn tail<P: AsRef<Path>>(path: P, n: usize) -> io::Result<Vec<String>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    let text = String::from_utf8_lossy(&data);
+    let lines: Vec<&str> = text.lines().collect();
+    let start = lines.len().saturating_sub(n);
+    Ok(lines[start..].iter().map(|line| line.to_string()).collect())
+}
@@ -1,13 +1,19 @@
 use std::str::FromStr;
 use std::env;
-use std::process::{Command, Stdio, exit};
-use std::io::{self, Read, Write};
-use std::path::Path;
+use std::process::{Child, Command, Stdio, exit};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::fs::{self, File};
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::panic;
+use std::time::{Duration, Instant};
+use std::thread;
+use std::sync::{mpsc, Arc};
+use std::net::TcpListener;
 
 extern crate ff;
 extern crate marionette;
-use marionette::{MarionetteConnection, Element, JsonValue, WindowHandle, Result, Script, Cookie};
+use marionette::{MarionetteConnection, MarionetteError, Context, Element, JsonValue, WindowHandle, Result, Script, Cookie};
 use marionette::QueryMethod::CssSelector;
 #[macro_use]
 extern crate clap;
@@ -18,9 +24,23 @@ extern crate stderrlog;
 extern crate url;
 #[cfg(unix)]
 extern crate chan_signal;
+extern crate serde_json;
+extern crate rustyline;
+extern crate shell_words;
+
+mod output;
+use output::{Format, Output, Record};
+mod shell;
+mod batch;
 
 const ISSUES_URL: &'static str = "https://github.com/equalsraf/ffcli/issues";
 
+/// Exit code when the browser crashed during a command
+const EXIT_CRASHED: i32 = 3;
+
+/// The port geckodriver listens on by default
+const DEFAULT_WEBDRIVER_PORT: u16 = 4444;
+
 trait ExitOnError<T>: Sized {
     fn exit(code: i32, msg: Option<&str>) -> ! {
         if let Some(msg) = msg {
@@ -70,49 +90,68 @@ impl<T> ExitOnError<T> for Option<T> {
 }
 
 
+/// SIGINT and SIGTERM are reported on the returned channel, so the browser
+/// can be shut down cleanly
 #[cfg(unix)]
-fn setup_signals() {
-    // For now Ignore SIGINT
-    chan_signal::notify(&[chan_signal::Signal::INT]);
+fn setup_signals() -> mpsc::Receiver<()> {
+    let signals = chan_signal::notify(&[chan_signal::Signal::INT, chan_signal::Signal::TERM]);
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        while let Some(signal) = signals.recv() {
+            info!("Received {:?}", signal);
+            if tx.send(()).is_err() {
+                break;
+            }
+        }
+    });
+    rx
 }
 #[cfg(not(unix))]
-fn setup_signals() {}
+fn setup_signals() -> mpsc::Receiver<()> {
+    mpsc::channel().1
+}
 
-fn cmd_start(args: &ArgMatches) -> Result<()> {
+fn cmd_start(args: &ArgMatches, argv: &[String], out: &mut Output) -> Result<()> {
 
-    let port_arg = args.value_of("PORT")
-        .map(|val| val.to_owned())
-        .map(|ref s| u16::from_str(s).unwrap_or_exitmsg(-1, "Invalid port argument"));
+    let port_arg = match args.value_of("PORT") {
+        Some(s) => Some(u16::from_str(s).map_err(|err| invalid_input("Invalid port argument", err))?),
+        None => None,
+    };
 
-    // Check TCP port availability
-    let portnum = match args.is_present("no-marionette") {
-        false => Some(ff::check_tcp_port(port_arg)?),
-        true => None,
+    // Check TCP port availability, with port 0 firefox picks the port
+    let portnum = match (args.is_present("no-marionette"), port_arg) {
+        (true, _) => None,
+        (false, Some(0)) => Some(0),
+        (false, port_arg) => Some(ff::check_tcp_port(port_arg)?),
     };
+    let (bidi_port_arg, bidi_port) = service_port(args, "bidi", "BIDI-PORT")?;
+    let (devtools_port_arg, devtools_port) = service_port(args, "devtools", "DEVTOOLS-PORT")?;
 
     if args.is_present("no-fork") {
-        setup_signals();
+        let signals = setup_signals();
 
-        let mut browser = ff::Browser::start(portnum,
-                                             args.value_of("PROFILE"),
-                                             args.value_of("FIREFOX-BIN"),
-                                             args.value_of("PREFSFILE"),
-                                             args.values_of("EXTRAPREFS").map(|v| v.collect()),
-                                             args.value_of("SESSION"))?;
+        let mut browser = browser_builder(args, portnum, bidi_port, devtools_port)?.start()?;
 
-        let status = browser.runner.process.wait()?;
-        info!("Firefox exited with status {}", status);
+        #[cfg(unix)]
+        let _daemon = match browser.runner.port() {
+            Some(port) if !args.is_present("no-daemon") => start_daemon(&mut browser, port),
+            _ => None,
+        };
 
-        if args.is_present("rm-profile") {
-            if  let Some(profile_dir) = args.value_of("PROFILE") {
-                if let Err(err) = std::fs::remove_dir_all(profile_dir) {
-                    eprintln!("Failed to remove profile dir: {}", err);
-                }
+        let status = loop {
+            if let Some(status) = browser.runner.wait_timeout(Duration::from_millis(200))? {
+                break status;
             }
-        }
+            if signals.try_recv().is_ok() {
+                info!("Stopping firefox");
+                let timeouts = browser.runner.options().shutdown;
+                break browser.shutdown(&timeouts)?;
+            }
+        };
+        info!("Firefox exited with status {}", status);
 
     } else {
-        let mut child_args: Vec<_> = env::args().collect();
+        let mut child_args: Vec<_> = argv[1..].to_vec();
         child_args.push("--no-fork".to_owned());
         if port_arg.is_none() {
             if let Some(portnum) = portnum {
@@ -120,43 +159,430 @@ fn cmd_start(args: &ArgMatches) -> Result<()> {
                 child_args.push(format!("{}", portnum));
             }
         }
+        if let (None, Some(bidi_port)) = (bidi_port_arg, bidi_port) {
+            child_args.push("--bidi-port".to_owned());
+            child_args.push(bidi_port.to_string());
+        }
+        if let (None, Some(devtools_port)) = (devtools_port_arg, devtools_port) {
+            child_args.push("--devtools-port".to_owned());
+            child_args.push(devtools_port.to_string());
+        }
 
         debug!("Spawning ff process {:?}", child_args);
-        Command::new(&child_args[0])
-            .args(&child_args[1..])
+        let mut child = Command::new(env::current_exe()?)
+            .args(&child_args)
             .stdin(Stdio::null())
             .stdout(Stdio::null())
             .spawn()?;
         if let Some(portnum) = portnum {
-            let mut conn = ff::check_connection(portnum)?;
+            let portnum = match portnum {
+                0 => wait_for_instance(&mut child)
+                    .map_err(|err| quote_log(err, ff::logs::startup_log_path(child.id())))?,
+                port => port,
+            };
+            let mut conn = wait_for_browser(portnum, !args.is_present("no-daemon"), &mut child)
+                .map_err(|err| quote_log(err, ff::logs::log_path(portnum)))?;
             if let Some(url) = args.value_of("URL") {
                 conn.get(&convert_url(url))?;
             }
-            println!("{}", portnum);
+            let text = portnum.to_string();
+            out.record(Some(&text), Record::new()
+                       .field("port", portnum)
+                       .field("bidi_port", bidi_port)
+                       .field("devtools_port", devtools_port));
         }
     }
 
     Ok(())
 }
 
-fn cmd_install(args: &ArgMatches) -> Result<()> {
-    connect_to_port(args)
+/// The port given with the `port` option, and the port to use: the given
+/// one or a free port if `flag` is set
+fn service_port(args: &ArgMatches, flag: &str, port: &str) -> Result<(Option<u16>, Option<u16>)> {
+    let port_arg = match args.value_of(port) {
+        Some(s) => Some(u16::from_str(s).map_err(|err| invalid_input("Invalid port argument", err))?),
+        None => None,
+    };
+    let port = match (args.is_present(flag), port_arg) {
+        (_, Some(port)) => Some(ff::check_tcp_port(Some(port))?),
+        (true, None) => Some(ff::check_tcp_port(None)?),
+        (false, None) => None,
+    };
+    Ok((port_arg, port))
+}
+
+/// The browser configured by the options of `start`
+fn browser_builder(args: &ArgMatches, port: Option<u16>, bidi_port: Option<u16>, devtools_port: Option<u16>)
+                   -> Result<ff::BrowserBuilder> {
+    let mut builder = match port {
+        Some(port) => ff::BrowserBuilder::new().port(port).capture_log(true),
+        None => ff::BrowserBuilder::new().no_marionette(),
+    };
+    if let Some(port) = bidi_port {
+        builder = builder.bidi_port(port);
+    }
+    if let Some(port) = devtools_port {
+        builder = builder.devtools_port(port);
+    }
+
+    if let Some(path) = args.value_of("PROFILE") {
+        builder = builder.profile(path);
+    }
+    if let Some(path) = args.value_of("FIREFOX-BIN") {
+        builder = builder.firefox_bin(path);
+    }
+    if let Some(channel) = args.value_of("CHANNEL") {
+        builder = builder.channel(channel.parse().map_err(|err| invalid_input("Invalid channel", err))?);
+    }
+    let min_version = match args.value_of("MIN-VERSION") {
+        Some(version) => Some(version.to_owned()),
+        None => env::var("FF_MIN_VERSION").ok(),
+    };
+    if let Some(version) = min_version {
+        builder = builder.min_version(version.parse().map_err(|err| invalid_input("Invalid minimum version", err))?);
+    }
+    if let Some(path) = args.value_of("PREFSFILE") {
+        builder = builder.user_js(path);
+    }
+    for path in args.values_of("EXTRAPREFS").into_iter().flatten() {
+        builder = builder.extra_prefs(path);
+    }
+    if let Some(name) = args.value_of("SESSION") {
+        builder = builder.session(name);
+    }
+
+    if let Some(s) = args.value_of("WINDOW-SIZE") {
+        let (width, height) = s.split_once('x')
+            .and_then(|(w, h)| Some((u32::from_str(w).ok()?, u32::from_str(h).ok()?)))
+            .ok_or_else(|| invalid_input("Invalid window size, use WIDTHxHEIGHT", s))?;
+        builder = builder.window_size(width, height);
+    }
+    for arg in args.values_of("ARG").into_iter().flatten() {
+        builder = builder.arg(arg);
+    }
+    for var in args.values_of("ENV").into_iter().flatten() {
+        let (name, value) = var.split_once('=')
+            .ok_or_else(|| invalid_input("Invalid environment variable, use NAME=VALUE", var))?;
+        builder = builder.env(name, value);
+    }
+
+    let defaults = ff::ShutdownTimeouts::default();
+    Ok(builder
+       .headless(args.is_present("headless"))
+       .xvfb(args.is_present("xvfb"))
+       .remove_profile(args.is_present("rm-profile"))
+       .shutdown_timeouts(seconds_option(args, "QUIT-TIMEOUT")?.unwrap_or(defaults.quit),
+                          seconds_option(args, "TERM-TIMEOUT")?.unwrap_or(defaults.term)))
+}
+
+/// An option with a number of seconds
+fn seconds_option(args: &ArgMatches, name: &str) -> Result<Option<Duration>> {
+    match args.value_of(name) {
+        Some(s) => {
+            match f64::from_str(s) {
+                Ok(secs) if secs >= 0.0 && secs.is_finite() => Ok(Some(Duration::from_secs_f64(secs))),
+                _ => Err(invalid_input("Invalid number of seconds", s)),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+/// Add the last lines of the firefox log to a startup error
+fn quote_log(err: MarionetteError, log: io::Result<PathBuf>) -> MarionetteError {
+    let lines = log.and_then(|path| ff::logs::tail(path, 10));
+    match (err, lines) {
+        (MarionetteError::Io(err), Ok(ref lines)) if !lines.is_empty() => {
+            io::Error::new(err.kind(), format!("{}\nLast lines of the firefox log:\n  {}",
+                                               err, lines.join("\n  "))).into()
+        }
+        (err, _) => err,
+    }
+}
+
+/// Share the marionette session with other ff clients through a daemon
+#[cfg(unix)]
+fn start_daemon(browser: &mut ff::Browser, port: u16) -> Option<ff::daemon::Daemon> {
+    let res = browser.connect()
+        .and_then(|conn| ff::daemon::Daemon::start(port, conn));
+    match res {
+        Ok(daemon) => Some(daemon),
+        Err(err) => {
+            warn!("Unable to start ff daemon: {}", err);
+            None
+        }
+    }
+}
+
+/// The port of a browser started with port 0 in the background, from the
+/// instance file written by the child once firefox reports the port
+fn wait_for_instance(child: &mut Child) -> Result<u16> {
+    let start = Instant::now();
+    loop {
+        let found = ff::instances()?.into_iter().find(|instance| instance.ff_pid == Some(child.id()));
+        if let Some(instance) = found {
+            return Ok(instance.port);
+        }
+        if let Some(status) = child.try_wait()? {
+            return Err(io::Error::other(format!("Firefox exited during startup ({})", status)).into());
+        }
+        if start.elapsed() > ff::ACTIVE_PORT_TIMEOUT + Duration::new(5, 0) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for the firefox port").into());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Wait for a browser started in the background. If the child process runs
+/// a daemon connect through it, since the daemon holds the only session.
+/// Fails early when the child exits, e.g. because firefox did not start.
+fn wait_for_browser(port: u16, daemon: bool, child: &mut Child) -> Result<MarionetteConnection> {
+    let exited = |child: &mut Child| -> Result<()> {
+        match child.try_wait()? {
+            Some(status) => Err(io::Error::other(format!("Firefox exited during startup ({})", status)).into()),
+            None => Ok(()),
+        }
+    };
+
+    #[cfg(unix)]
+    {
+        if daemon {
+            let start = Instant::now();
+            while start.elapsed() < Duration::new(30, 0) {
+                exited(child)?;
+                match ff::daemon::wait(port, Duration::from_millis(200)) {
+                    Ok(conn) => return Ok(conn),
+                    Err(err) => debug!("Unable to connect to ff daemon: {}", err),
+                }
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = daemon;
+    exited(child)?;
+    ff::check_connection(port)
+}
+
+fn cmd_install(conn: &mut Connector, args: &ArgMatches) -> Result<()> {
+    conn.connection(args)?
         .addon_install(Path::new(args.value_of("PATH").unwrap()))
 }
 
-fn cmd_instances() -> Result<()> {
+fn cmd_instances(args: &ArgMatches, out: &mut Output) -> Result<()> {
     for instance in ff::instances()? {
-        if let Ok(mut c) = MarionetteConnection::connect(instance.port) {
-            if let Ok(title) = c.get_title() {
-                println!("FF_PORT={}:{} \"{}\"", instance.port, instance.name, title);
+        let record = Record::new()
+            .field("port", instance.port)
+            .field("name", instance.name.as_str())
+            .field("pid", instance.pid)
+            .field("profile", instance.profile.as_ref().map(|p| p.to_string_lossy().into_owned()))
+            .field("version", instance.version.clone())
+            .field("started", instance.started)
+            .field("bidi_port", instance.bidi_port)
+            .field("devtools_port", instance.devtools_port);
+
+        // Do not wait for connection timeouts on instances that are gone
+        if !instance.is_alive() {
+            if args.is_present("prune") {
+                info!("Removing instance {}:{}", instance.port, instance.name);
+                instance.remove()?;
             } else {
-                println!("{}:{} DOWN", instance.port, instance.name);
+                let text = format!("{}:{} DEAD", instance.port, instance.name);
+                out.record(Some(&text), record.field("title", JsonValue::Null).field("state", "dead"));
             }
+            continue;
+        }
+
+        let title = ff::connect(instance.port).and_then(|mut c| c.get_title());
+        if let Ok(title) = title {
+            let text = format!("FF_PORT={}:{} \"{}\"", instance.port, instance.name, title);
+            out.record(Some(&text), record.field("title", title).field("state", "up"));
+        } else {
+            let text = format!("{}:{} DOWN", instance.port, instance.name);
+            out.record(Some(&text), record.field("title", JsonValue::Null).field("state", "down"));
         }
     }
     Ok(())
 }
 
+/// Print the firefox log of an instance
+fn cmd_logs(args: &ArgMatches, out: &mut Output) -> Result<()> {
+    let port = match port_option(args)? {
+        Some(port) => port,
+        None => env_port()?,
+    };
+    let path = ff::logs::log_path(port)?;
+    let follow = args.is_present("follow");
+    let lines = match args.value_of("LINES") {
+        Some(s) => usize::from_str(s).map_err(|err| invalid_input("Invalid number of lines", err))?,
+        None if follow => 10,
+        None => usize::MAX,
+    };
+
+    for line in ff::logs::tail(&path, lines)? {
+        out.record(Some(&line), Record::new().field("line", line.as_str()));
+    }
+    if follow {
+        follow_log(&path, out)?;
+    }
+    Ok(())
+}
+
+/// Print lines appended to a log, until interrupted
+fn follow_log(path: &Path, out: &mut Output) -> Result<()> {
+    let mut file = File::open(path)?;
+    let mut pos = file.seek(SeekFrom::End(0))?;
+    let mut partial = Vec::new();
+    loop {
+        // Start over when the log was rotated
+        if fs::metadata(path).map(|meta| meta.len() < pos).unwrap_or(false) {
+            file = File::open(path)?;
+            pos = 0;
+        }
+        let mut data = Vec::new();
+        pos += file.read_to_end(&mut data)? as u64;
+        if data.is_empty() {
+            io::stdout().flush()?;
+            thread::sleep(Duration::from_millis(200));
+            continue;
+        }
+
+        partial.extend_from_slice(&data);
+        while let Some(idx) = partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = partial.drain(..=idx).collect();
+            let line = String::from_utf8_lossy(&line[..idx]).into_owned();
+            out.record(Some(&line), Record::new().field("line", line.as_str()));
+        }
+    }
+}
+
+/// Register a firefox started outside of ff
+fn cmd_attach(args: &ArgMatches, out: &mut Output) -> Result<()> {
+    let profile = match args.value_of("PROFILE") {
+        Some(path) => PathBuf::from(path),
+        None => ff::profiles::find_profile(args.value_of("PROFILE-NAME"))?,
+    };
+    let instance = ff::attach(&profile, args.value_of("SESSION"))?;
+    let text = instance.port.to_string();
+    out.record(Some(&text), Record::new()
+               .field("port", instance.port)
+               .field("name", instance.name.as_str())
+               .field("profile", profile.to_string_lossy().into_owned()));
+    Ok(())
+}
+
+fn cmd_quit(conn: &mut Connector, args: &ArgMatches) -> Result<()> {
+    conn.take(args)?.quit()?;
+    if args.is_present("wait") {
+        let timeout = seconds_option(args, "TIMEOUT")?.unwrap_or(Duration::from_secs(60));
+        let instance = ff::instances()?.into_iter().find(|instance| instance.port == conn.port);
+        match instance {
+            Some(instance) => instance.wait_for_exit(timeout)?,
+            None => warn!("Not an ff instance, unable to wait for firefox to exit"),
+        }
+    }
+    Ok(())
+}
+
+/// Remove leftovers of browsers that did not exit cleanly
+fn cmd_gc(args: &ArgMatches, out: &mut Output) -> Result<()> {
+    for path in ff::collect_garbage(args.is_present("dry-run"))? {
+        let text = path.to_string_lossy();
+        out.record(Some(&text), Record::new().field("profile", text.as_ref()));
+    }
+    Ok(())
+}
+
+/// List crash reports collected by ff and those in the profiles of
+/// instances
+fn cmd_crashes(args: &ArgMatches, out: &mut Output) -> Result<()> {
+    let mut reports = ff::crashes::reports_in(&ff::crashes::crash_dir()?)?;
+    for instance in ff::instances()? {
+        if let Some(profile) = &instance.profile {
+            reports.extend(ff::crashes::reports_in(&ff::crashes::minidumps_dir(profile))?);
+        }
+    }
+
+    for report in &reports {
+        if let Some(dir) = args.value_of("COLLECT") {
+            report.copy_to(Path::new(dir))?;
+        }
+        let text = format!("{}\t{}\t{}\t{}", report.id,
+                           report.crash_time().map(|t| t.to_string()).unwrap_or_default(),
+                           report.version().unwrap_or(""),
+                           report.reason().unwrap_or(""));
+        out.record(Some(&text), Record::new()
+                   .field("id", report.id.as_str())
+                   .field("time", report.crash_time())
+                   .field("version", report.version())
+                   .field("reason", report.reason())
+                   .field("dump", report.dump.to_string_lossy().into_owned())
+                   .field("extra", report.extra.clone()));
+    }
+    Ok(())
+}
+
+/// Print a shell command selecting an instance, for use with eval
+fn cmd_use(args: &ArgMatches, out: &mut Output) -> Result<()> {
+    let instance = ff::find_instance(args.value_of("INSTANCE").unwrap())?;
+    let text = format!("export FF_PORT={}", instance.port);
+    out.record(Some(&text), Record::new()
+               .field("port", instance.port)
+               .field("name", instance.name.as_str()));
+    Ok(())
+}
+
+/// Serve the W3C WebDriver protocol until ff is interrupted
+fn cmd_serve_webdriver(args: &ArgMatches, out: &mut Output) -> Result<()> {
+    let target = match args.value_of("CONNECT") {
+        Some(instance) => ff::webdriver::Target::Instance(ff::find_instance(instance)?.port),
+        None => ff::webdriver::Target::Launch(Box::new(browser_builder(args, Some(0), None, None)?)),
+    };
+    let port = match args.value_of("WEBDRIVER-PORT") {
+        Some(s) => u16::from_str(s).map_err(|err| invalid_input("Invalid port argument", err))?,
+        None => DEFAULT_WEBDRIVER_PORT,
+    };
+    let listener = TcpListener::bind((args.value_of("HOST").unwrap_or("127.0.0.1"), port))?;
+    let addr = listener.local_addr()?;
+    out.record(Some(&addr.to_string()), Record::new().field("port", addr.port()));
+
+    let signals = setup_signals();
+    let server = Arc::new(ff::webdriver::WebDriverServer::new(target));
+    let (done_tx, done) = mpsc::channel();
+    let serving = server.clone();
+    thread::spawn(move || {
+        let _ = done_tx.send(serving.serve(listener));
+    });
+    loop {
+        if let Ok(res) = done.recv_timeout(Duration::from_millis(200)) {
+            server.end_session();
+            return Ok(res?);
+        }
+        if signals.try_recv().is_ok() {
+            info!("Stopping WebDriver server");
+            server.end_session();
+            return Ok(());
+        }
+    }
+}
+
+/// Kill the browser for an instance that is no longer responding
+fn cmd_kill(args: &ArgMatches) -> Result<()> {
+    let instance = ff::find_instance(args.value_of("INSTANCE").unwrap())?;
+    if let Some(pid) = instance.pid {
+        if instance.is_alive() {
+            ff::kill_process(pid, Duration::new(5, 0))?;
+        }
+    }
+
+    // The ff process that started the browser removes the instance file
+    // when the browser exits, unless it is gone as well
+    thread::sleep(Duration::from_millis(500));
+    if instance.path()?.exists() {
+        instance.remove()?;
+    }
+    Ok(())
+}
+
 fn convert_url(url_in: &str) -> String {
     if let Err(url::ParseError::RelativeUrlWithoutBase) = url::Url::parse(url_in) {
         "https://".to_owned() + url_in
@@ -165,58 +591,60 @@ fn convert_url(url_in: &str) -> String {
     }
 }
 
-fn cmd_go(args: &ArgMatches) -> Result<()> {
+fn cmd_go(conn: &mut Connector, args: &ArgMatches) -> Result<()> {
     let url_arg = args.value_of("URL").unwrap();
 
-    let mut conn = connect_to_port(args);
-    conn.get(&convert_url(url_arg))
+    conn.connection(args)?.get(&convert_url(url_arg))
 }
 
-fn cmd_download(args: &ArgMatches) -> Result<()> {
+fn cmd_download(conn: &mut Connector, args: &ArgMatches) -> Result<()> {
     let url_arg = args.value_of("URL").unwrap();
     let path = args.value_of("FILE").unwrap();
 
-    let mut conn = connect_to_port(args);
-    ff::downloads::start(&mut conn, url_arg, Path::new(path))
+    ff::downloads::start(conn.connection(args)?, url_arg, Path::new(path))
 }
 
-fn cmd_windows(args: &ArgMatches) -> Result<()> {
-    let mut conn = connect_to_port(args);
+fn cmd_windows(conn: &mut Connector, args: &ArgMatches, out: &mut Output) -> Result<()> {
+    let conn = conn.connection(args)?;
     let prev = conn.get_window_handle()?;
     for win in conn.get_window_handles()? {
         conn.switch_to_window(&win)?;
         let title = conn.get_title()?;
-        println!("{} \"{}\"", win, title);
+        let text = format!("{} \"{}\"", win, title);
+        out.record(Some(&text), Record::new()
+                   .field("id", win.to_string())
+                   .field("title", title));
     }
 
     conn.switch_to_window(&prev)?;
     Ok(())
 }
 
-fn cmd_cookies(args: &ArgMatches) -> Result<()> {
-    let mut conn = connect_to_port(args);
-    let cookies = conn.get_cookies()?;
+fn cmd_cookies(conn: &mut Connector, args: &ArgMatches, out: &mut Output) -> Result<()> {
+    let cookies = conn.connection(args)?.get_cookies()?;
     for cookie in cookies {
-        print!("{}=", cookie.name);
-        print!("{} -- ", cookie.value);
+        let mut text = format!("{}={} -- ", cookie.name, cookie.value);
 
         if let Some(ref domain) = cookie.domain {
-            print!("domain={} ", domain);
+            text.push_str(&format!("domain={} ", domain));
         }
         if let Some(ref path) = cookie.path {
-            print!("path={} ", path);
+            text.push_str(&format!("path={} ", path));
         }
         if let Some(ref sec) = cookie.secure {
-            print!("secure={}", sec);
+            text.push_str(&format!("secure={}", sec));
         }
-        println!("");
+        out.record(Some(&text), Record::new()
+                   .field("name", cookie.name)
+                   .field("value", cookie.value)
+                   .field("domain", cookie.domain)
+                   .field("path", cookie.path)
+                   .field("secure", cookie.secure));
     }
     Ok(())
 }
 
-fn cmd_addcookie(args: &ArgMatches) -> Result<()> {
-    let mut conn = connect_to_port(args);
-
+fn cmd_addcookie(conn: &mut Connector, args: &ArgMatches) -> Result<()> {
     let name = args.value_of("NAME").unwrap();
     let value = args.value_of("VALUE").unwrap();
     let domain = args.value_of("DOMAIN");
@@ -231,56 +659,580 @@ fn cmd_addcookie(args: &ArgMatches) -> Result<()> {
         secure: None,
     };
 
-    conn.add_cookie(&cookie)?;
+    conn.connection(args)?.add_cookie(&cookie)?;
+    Ok(())
+}
+
+fn cmd_text(conn: &mut Connector, args: &ArgMatches, out: &mut Output) -> Result<()> {
+    let conn = conn.connection(args)?;
+    conn.switch_to_frame(None)?;
+    foreach_frame(conn, args, &mut |conn, args, path| {
+        foreach_element(conn, args, &mut |elem, idx| {
+            let text = elem.text()?;
+            let plain = if text.is_empty() { None } else { Some(text.as_str()) };
+            out.record(plain, frame_record(path)
+                       .field("index", idx)
+                       .field("text", text.as_str()));
+            Ok(())
+        })
+    })?;
+    conn.switch_to_frame(None)
+}
+
+fn cmd_attr(conn: &mut Connector, args: &ArgMatches, out: &mut Output) -> Result<()> {
+    let conn = conn.connection(args)?;
+    let attrname = args.value_of("ATTRNAME").unwrap();
+    conn.switch_to_frame(None)?;
+    foreach_frame(conn, args, &mut |conn, args, path| {
+        foreach_element(conn, args, &mut |elem, idx| {
+            let value = elem.attr(attrname)?;
+            let plain = value.as_deref().filter(|text| !text.is_empty());
+            out.record(plain, frame_record(path)
+                       .field("index", idx)
+                       .field("value", value.clone()));
+            Ok(())
+        })
+    })?;
+    conn.switch_to_frame(None)
+}
+
+fn cmd_property(conn: &mut Connector, args: &ArgMatches, out: &mut Output) -> Result<()> {
+    let conn = conn.connection(args)?;
+    let propname = args.value_of("NAME").unwrap();
+    conn.switch_to_frame(None)?;
+    foreach_frame(conn, args, &mut |conn, args, path| {
+        foreach_element(conn, args, &mut |elem, idx| {
+            let val = elem.property(propname)?;
+            let plain = plain_json_value(&val, args);
+            out.record(plain.as_deref(), frame_record(path)
+                       .field("index", idx)
+                       .field("value", val));
+            Ok(())
+        })
+    })?;
+    conn.switch_to_frame(None)
+}
+
+fn cmd_exec(conn: &mut Connector, args: &ArgMatches, out: &mut Output) -> Result<()> {
+    let bundle = load_script(args)
+        .map_err(|err| invalid_input("Unable to load script", err))?;
+    let mut script = Script::new(bundle.source());
+
+    if args.is_present("SANDBOX") {
+        script.sandbox(args.value_of("SANDBOX").unwrap());
+    }
+    script.new_sandbox(args.is_present("NEW"));
+
+    if let Some(s_timeout) = args.value_of("TIMEOUT") {
+        let ms = u64::from_str(s_timeout)
+            .map_err(|err| invalid_input("Invalid TIMEOUT value", err))?;
+        script.timeout(ms);
+    }
+
+    // With --file every positional argument is a script argument
+    let first_arg = if args.is_present("FILE") { args.value_of("SCRIPT") } else { None };
+    let script_args_iter: Vec<&str> = first_arg.into_iter()
+        .chain(args.values_of("ARG").into_iter().flatten())
+        .collect();
+    if !script_args_iter.is_empty() {
+        let mut script_args: Vec<JsonValue> = Vec::new();
+        for arg in script_args_iter {
+            let val = JsonValue::from_str(arg)
+                .map_err(|err| invalid_input("Script argument is invalid JSON", err))?;
+            script_args.push(val);
+        }
+        script.arguments(script_args)?;
+    }
+
+    let conn = conn.connection(args)?;
+    conn.switch_to_frame(None)?;
+    foreach_frame(conn, args, &mut |conn, args, path| {
+        let res = if args.is_present("ASYNC") {
+            conn.execute_async_script(&script)
+        } else {
+            conn.execute_script(&script)
+        };
+
+        match res.map_err(|err| bundle.map_error(err)) {
+            Ok(val) => {
+                let plain = plain_json_value(&val, args);
+                out.record(plain.as_deref(), frame_record(path)
+                           .field("value", val)
+                           .field("error", JsonValue::Null));
+            }
+            Err(ref err) if !err.is_fatal() => {
+                error!("Error executing script: {}", err);
+                out.record(None, frame_record(path)
+                           .field("value", JsonValue::Null)
+                           .field("error", err.to_string()));
+            }
+            Err(err) => return Err(err),
+        }
+        Ok(())
+    })?;
+    conn.switch_to_frame(None)
+}
+
+/// Evaluate and inspect the selected tab through the devtools server
+fn cmd_devtools(conn: &mut Connector, args: &ArgMatches, out: &mut Output) -> Result<()> {
+    let (name, args) = match args.subcommand() {
+        (name, Some(args)) => (name, args),
+        (name, None) => return Err(invalid_input("Unsupported command", name)),
+    };
+    let mut rdp = ff::connect_devtools(conn.port(args)?)?;
+    let target = rdp.selected_target()?;
+    match name {
+        "eval" => {
+            let depth = match args.value_of("DEPTH") {
+                Some(s) => usize::from_str(s).map_err(|err| invalid_input("Invalid depth", err))?,
+                None => 1,
+            };
+            let grip = rdp.evaluate(&target, args.value_of("EXPRESSION").unwrap())?;
+            let value = rdp.grip_value(&grip, depth)?;
+            let plain = plain_json_value(&value, args);
+            out.record(plain.as_deref(), Record::new().field("value", value));
+        }
+        "inspect" => {
+            let style = rdp.inspect(&target, args.value_of("SELECTOR").unwrap())?;
+            if args.is_present("rules") {
+                for (selector, css) in style.rules {
+                    let text = format!("{} {{ {} }}", selector, css);
+                    out.record(Some(&text), Record::new().field("selector", selector).field("css", css));
+                }
+                return Ok(());
+            }
+            let names: Vec<&str> = args.values_of("PROPERTY").into_iter().flatten().collect();
+            for (property, value) in style.computed {
+                if names.is_empty() || names.contains(&property.as_str()) {
+                    let text = format!("{}: {}", property, value);
+                    out.record(Some(&text), Record::new().field("property", property).field("value", value));
+                }
+            }
+        }
+        _ => return Err(invalid_input("Unsupported command", name)),
+    }
     Ok(())
 }
 
+fn cmd_switch(conn: &mut Connector, args: &ArgMatches) -> Result<()> {
+    let conn = conn.connection(args)?;
+    let handle = if args.is_present("index") {
+        let idx = usize::from_str(args.value_of("WINDOW").unwrap())
+            .map_err(|err| invalid_input("Invalid WINDOW index", err))?;
+        conn.get_window_handles()?
+            .into_iter()
+            .nth(idx)
+            .ok_or_else(|| invalid_input("Unable to switch window", "Index is invalid"))?
+    } else {
+        WindowHandle::from_str(args.value_of("WINDOW").unwrap())
+    };
+    conn.switch_to_window(&handle)
+}
+
+/// Run a subcommand, output is written to `out`
+///
+/// `argv` is the full command line for this command, starting with the
+/// program name.
+fn run_command(name: &str, args: &ArgMatches, argv: &[String], conn: &mut Connector, out: &mut Output) -> Result<()> {
+    if args.is_present("ALL") || args.is_present("INSTANCES") {
+        return run_on_instances(name, args, argv, out);
+    }
+    run_single(name, args, argv, conn, out)
+}
+
+/// Commands that do not talk to a browser, they can not be used with --all
+const LOCAL_COMMANDS: &[&str] = &[
+    "start", "instances", "kill", "use", "logs", "attach", "gc", "crashes", "shell", "run", "pool",
+    "serve-webdriver",
+];
+
+/// Run a command on every running instance at once, or on the instances
+/// matching --instances. The output of each instance is labeled with its
+/// session name, or its port for unnamed instances.
+fn run_on_instances(name: &str, args: &ArgMatches, argv: &[String], out: &mut Output) -> Result<()> {
+    if LOCAL_COMMANDS.contains(&name) {
+        return Err(invalid_input("Unsupported command for --all and --instances", name));
+    }
+    if args.is_present("PORT") {
+        return Err(invalid_input("Conflicting options", "--port can not be used with --all and --instances"));
+    }
+
+    let pattern = args.value_of("INSTANCES");
+    let instances = ff::running_instances(pattern)?;
+    if instances.is_empty() {
+        return Err(invalid_input("No running instances", pattern.unwrap_or("*")));
+    }
+
+    let format = out.format();
+    let results: Vec<(String, Output, Result<()>)> = thread::scope(|scope| {
+        let handles: Vec<_> = instances.iter()
+            .map(|instance| scope.spawn(move || {
+                let label = match instance.name.as_str() {
+                    "" => instance.port.to_string(),
+                    name => name.to_owned(),
+                };
+                let mut out = Output::labeled(format, &label);
+                let res = Connector::open(instance.port)
+                    .and_then(|mut conn| run_single(name, args, argv, &mut conn, &mut out));
+                (label, out, res)
+            }))
+            .collect();
+        handles.into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|err| panic::resume_unwind(err)))
+            .collect()
+    });
+
+    let mut failed = 0;
+    for (label, output, res) in results {
+        out.append(output);
+        if let Err(err) = res {
+            match error_context(name) {
+                Some(msg) => eprintln!("{}: {}: {}", label, msg, err),
+                None => eprintln!("{}: {}", label, err),
+            }
+            failed += 1;
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(invalid_input("Command failed", format!("{} of {} instances failed", n, instances.len()))),
+    }
+}
+
+/// Commands that can run against the browser UI with --chrome
+const CHROME_COMMANDS: &[&str] = &["exec", "text", "attr", "property", "source"];
+
+/// Run a command on a single browser, see `run_command()`
+fn run_single(name: &str, args: &ArgMatches, argv: &[String], conn: &mut Connector, out: &mut Output) -> Result<()> {
+    let res = match args.is_present("CHROME") {
+        true => run_in_chrome(name, args, argv, conn, out),
+        false => run_subcommand(name, args, argv, conn, out),
+    };
+    match res {
+        Err(err @ MarionetteError::ConnectionLost(_)) => {
+            conn.conn = None;
+            Err(ff::crashes::detect(conn.port, err))
+        }
+        res => res,
+    }
+}
+
+/// Run a command in the chrome context, the content context is restored
+/// afterwards
+fn run_in_chrome(name: &str, args: &ArgMatches, argv: &[String], conn: &mut Connector, out: &mut Output) -> Result<()> {
+    if !CHROME_COMMANDS.contains(&name) {
+        return Err(invalid_input("Unsupported command for --chrome", name));
+    }
+    conn.connection(args)?;
+    let port = conn.port;
+    conn.connection(args)?.with_context(Context::Chrome, |browser| {
+        run_subcommand(name, args, argv, &mut Connector::borrow(browser, port), out)
+    })
+}
+
+fn run_subcommand(name: &str, args: &ArgMatches, argv: &[String], conn: &mut Connector, out: &mut Output) -> Result<()> {
+    match name {
+        "go" => cmd_go(conn, args),
+        "back" => conn.connection(args)?.go_back(),
+        "download" => cmd_download(conn, args),
+        "forward" => conn.connection(args)?.go_forward(),
+        "source" => {
+            let source = conn.connection(args)?.get_page_source()?;
+            out.record(Some(&source), Record::new().field("source", source.as_str()));
+            Ok(())
+        }
+        "text" => cmd_text(conn, args, out),
+        "attr" => cmd_attr(conn, args, out),
+        "exec" => cmd_exec(conn, args, out),
+        "prefget" => {
+            let name = args.value_of("NAME").unwrap();
+            let value = conn.connection(args)?.get_pref(name)?;
+            let text = value.to_string();
+            out.record(Some(&text), Record::new().field("name", name).field("value", value));
+            Ok(())
+        }
+        "property" => cmd_property(conn, args, out),
+        "prefset" => {
+            let name = args.value_of("NAME").unwrap();
+            let value = JsonValue::from_str(args.value_of("VALUE").unwrap())
+                .map_err(|err| invalid_input("Invalid JSON argument", err))?;
+            conn.connection(args)?.set_pref(name, value)
+        }
+        "call" => {
+            let params = match args.value_of("JSON") {
+                Some(json) => JsonValue::from_str(json).map_err(|err| invalid_input("Invalid JSON argument", err))?,
+                None => JsonValue::Object(Default::default()),
+            };
+            let value = conn.connection(args)?.call_raw(args.value_of("COMMAND").unwrap(), params)?;
+            let text = value.to_string();
+            out.record(Some(&text), Record::new().field("value", value));
+            Ok(())
+        }
+        "title" => {
+            let title = conn.connection(args)?.get_title()?;
+            out.record(Some(&title), Record::new().field("title", title.as_str()));
+            Ok(())
+        }
+        "url" => {
+            let url = conn.connection(args)?.get_url()?;
+            out.record(Some(&url), Record::new().field("url", url.as_str()));
+            Ok(())
+        }
+        "quit" => cmd_quit(conn, args),
+        "start" => cmd_start(args, argv, out),
+        "install" => cmd_install(conn, args),
+        "instances" => cmd_instances(args, out),
+        "kill" => cmd_kill(args),
+        "use" => cmd_use(args, out),
+        "attach" => cmd_attach(args, out),
+        "gc" => cmd_gc(args, out),
+        "crashes" => cmd_crashes(args, out),
+        "logs" => cmd_logs(args, out),
+        "switch" => cmd_switch(conn, args),
+        "windows" => cmd_windows(conn, args, out),
+        "cookies" => cmd_cookies(conn, args, out),
+        "addcookie" => cmd_addcookie(conn, args),
+        "shell" => shell::run(args, out.format()),
+        "run" => batch::run(args, out.format()),
+        "pool" => batch::run_pool(args, out.format()),
+        "serve-webdriver" => cmd_serve_webdriver(args, out),
+        "devtools" => cmd_devtools(conn, args, out),
+        _ => Err(invalid_input("Unsupported command", name)),
+    }
+}
+
+/// Parse a command, without the program name, using the same definitions as
+/// the ff binary. On success the full command line is returned along with the
+/// matches, otherwise the error or help message.
+fn parse_command<'a>(words: Vec<String>) -> std::result::Result<(Vec<String>, ArgMatches<'a>), String> {
+    let mut argv = words;
+    argv.insert(0, "ff".to_owned());
+    let matches = build_app()
+        .get_matches_from_safe(&argv)
+        .map_err(|err| match err.kind {
+            clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => err.message,
+            _ => err.to_string(),
+        })?;
+    Ok((argv, matches))
+}
+
+/// The message shown when a command fails
+fn error_context(name: &str) -> Option<&'static str> {
+    match name {
+        "prefset" => Some("Unable to set preference"),
+        "call" => Some("Unable to call command"),
+        "devtools" => Some("Unable to use devtools"),
+        "start" => Some("Unable to start browser"),
+        "install" => Some("Unable to install addon"),
+        "instances" => Some("Unable to list ff instances"),
+        "kill" => Some("Unable to kill instance"),
+        "use" => Some("Unable to select instance"),
+        "attach" => Some("Unable to attach to firefox"),
+        "gc" => Some("Unable to remove unused profiles"),
+        "crashes" => Some("Unable to list crash reports"),
+        "logs" => Some("Unable to read firefox log"),
+        "serve-webdriver" => Some("Unable to serve WebDriver"),
+        _ => None,
+    }
+}
+
+/// Build an error for invalid user input
+fn invalid_input<E: fmt::Display>(msg: &str, err: E) -> MarionetteError {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", msg, err)).into()
+}
+
 
 const FRAME_SELECTOR: &'static str = "iframe, frame";
 
 /// Iterate over all frames under the current frame
-fn foreach_frame<F>(conn: &mut MarionetteConnection, args: &ArgMatches, f: &F) -> Result<()>
-        where F: Fn(&mut MarionetteConnection, &ArgMatches) -> Result<()> {
-    f(conn, args)?;
-    for frameref in conn.find_elements(CssSelector, FRAME_SELECTOR, None)? {
+///
+/// The callback also gets the frame path, the index of each frame
+/// starting from the current one.
+fn foreach_frame<F>(conn: &mut MarionetteConnection, args: &ArgMatches, f: &mut F) -> Result<()>
+        where F: FnMut(&mut MarionetteConnection, &ArgMatches, &[usize]) -> Result<()> {
+    foreach_frame_path(conn, args, f, &mut Vec::new())
+}
+
+fn foreach_frame_path<F>(conn: &mut MarionetteConnection, args: &ArgMatches, f: &mut F, path: &mut Vec<usize>) -> Result<()>
+        where F: FnMut(&mut MarionetteConnection, &ArgMatches, &[usize]) -> Result<()> {
+    f(conn, args, path)?;
+    // The frames of the browser UI hold web content and panels, which are
+    // reached through the content context
+    if args.is_present("CHROME") {
+        return Ok(());
+    }
+    for (idx, frameref) in conn.find_elements(CssSelector, FRAME_SELECTOR, None)?.into_iter().enumerate() {
         conn.switch_to_frame(Some(frameref))?;
-        foreach_frame(conn, args, f)?;
+        path.push(idx);
+        foreach_frame_path(conn, args, f, path)?;
+        path.pop();
         conn.switch_to_parent_frame()?;
     }
     Ok(())
 }
 
-/// Iterate over elements based on argument "SELECTOR"
-fn foreach_element<F, T>(conn: &mut MarionetteConnection, args: &ArgMatches, f: &F) -> Result<()>
-        where F: Fn(&mut Element) -> Result<T> {
+/// Iterate over elements based on argument "SELECTOR", the callback
+/// gets the element index inside the frame
+fn foreach_element<F, T>(conn: &mut MarionetteConnection, args: &ArgMatches, f: &mut F) -> Result<()>
+        where F: FnMut(&mut Element, usize) -> Result<T> {
     let selector =  args.value_of("SELECTOR").unwrap();
-    for elemref in conn.find_elements(CssSelector, selector, None)? {
-        f(&mut Element::new(conn, &elemref))?;
+    for (idx, elemref) in conn.find_elements(CssSelector, selector, None)?.into_iter().enumerate() {
+        f(&mut Element::new(conn, &elemref), idx)?;
     }
     Ok(())
 }
 
-fn print_json_value(val: &JsonValue, args: &ArgMatches) {
+/// Plain text representation for a JSON value, None if nothing should be printed
+fn plain_json_value(val: &JsonValue, args: &ArgMatches) -> Option<String> {
     if args.is_present("FILTER-STR") {
         if let JsonValue::String(ref val) = *val {
-            println!("{}", val);
+            return Some(val.to_owned());
         }
     } else if JsonValue::Null != *val {
-        println!("{}", val);
+        return Some(val.to_string());
     }
+    None
+}
+
+/// A record for a value computed in a frame
+fn frame_record(path: &[usize]) -> Record {
+    Record::new().field("frame", path.to_vec())
 }
 
-/// Panics unless --port of $FF_PORT is a valid port number
-fn connect_to_port(args: &ArgMatches) -> MarionetteConnection {
-    let port_arg = args.value_of("PORT")
-        .map(|val| val.to_owned())
-        .or_else(|| env::var("FF_PORT").ok())
-        .map(|ref s| u16::from_str(s)
-             .unwrap_or_exitmsg(-1, "Invalid port argument"));
+/// Build the script for the exec command. Helper libraries from the lib
+/// folder and --lib are placed before the script body.
+fn load_script(args: &ArgMatches) -> io::Result<ff::scripts::ScriptBundle> {
+    let mut bundle = ff::scripts::ScriptBundle::new();
+    if !args.is_present("NO-LIB-DIR") {
+        bundle.push_dir(ff::scripts::lib_dir()?)?;
+    }
+    if let Some(libs) = args.values_of("LIB") {
+        for lib in libs {
+            bundle.push_file(lib)?;
+        }
+    }
+
+    if let Some(path) = args.value_of("FILE") {
+        bundle.push_file(path)?;
+    } else {
+        // --new alone only creates the sandbox
+        let mut js = args.value_of("SCRIPT").unwrap_or("").to_owned();
+        if js == "-" {
+            js.clear();
+            io::stdin().read_to_string(&mut js)?;
+            bundle.push("<stdin>", &js);
+        } else {
+            bundle.push("<script>", &js);
+        }
+    }
+    Ok(bundle)
+}
 
-    let port = port_arg.unwrap_or_exitmsg(-1, "No port given, use --port or $FF_PORT");
-    MarionetteConnection::connect(port)
-        .unwrap_or_exitmsg(-1, "Unable to connect to firefox")
+/// Provides the connection used by commands. The connection is opened on
+/// first use and reused for any command that follows.
+struct Connector<'a> {
+    conn: Option<MarionetteConnection>,
+    /// A connection owned elsewhere, e.g. by a browser pool, used by every
+    /// command
+    borrowed: Option<&'a mut MarionetteConnection>,
+    port: u16,
+}
+
+impl<'a> Connector<'a> {
+    fn new() -> Self {
+        Connector { conn: None, borrowed: None, port: 0 }
+    }
+
+    /// Connect to the browser on `port` right away
+    fn open(port: u16) -> Result<Self> {
+        Ok(Connector { conn: Some(connect_to_port(port)?), borrowed: None, port })
+    }
+
+    fn borrow(conn: &'a mut MarionetteConnection, port: u16) -> Self {
+        Connector { conn: None, borrowed: Some(conn), port }
+    }
+
+    /// Fails unless --port, --session, $FF_PORT or $FF_SESSION select a
+    /// browser. An explicit --port or --session for a different browser
+    /// replaces the current connection.
+    fn connection(&mut self, args: &ArgMatches) -> Result<&mut MarionetteConnection> {
+        let port_arg = port_option(args)?;
+        let current = self.port;
+        if let Some(conn) = self.borrowed.as_deref_mut() {
+            if port_arg.is_some_and(|port| port != current) {
+                return Err(invalid_input("Unable to switch browser", "the connection is fixed"));
+            }
+            return Ok(conn);
+        }
+
+        if let Some(port) = port_arg {
+            if port != self.port {
+                self.conn = None;
+            }
+        }
+
+        if self.conn.is_none() {
+            let port = match port_arg {
+                Some(port) => port,
+                None => env_port()?,
+            };
+            self.conn = Some(connect_to_port(port)?);
+            self.port = port;
+        }
+        Ok(self.conn.as_mut().unwrap())
+    }
+
+    /// The port of the browser selected like for `connection()`, without
+    /// connecting to marionette
+    fn port(&self, args: &ArgMatches) -> Result<u16> {
+        match port_option(args)? {
+            Some(port) => Ok(port),
+            None if self.port != 0 => Ok(self.port),
+            None => env_port(),
+        }
+    }
+
+    /// Take ownership of the connection, the next command will reconnect
+    fn take(&mut self, args: &ArgMatches) -> Result<MarionetteConnection> {
+        if self.borrowed.is_some() {
+            return Err(invalid_input("Unable to take over the connection", "the connection is fixed"));
+        }
+        self.connection(args)?;
+        Ok(self.conn.take().unwrap())
+    }
+}
+
+/// The port given with --port or --session
+fn port_option(args: &ArgMatches) -> Result<Option<u16>> {
+    match (args.value_of("PORT"), args.value_of("SESSION")) {
+        (Some(s), _) => Ok(Some(u16::from_str(s).map_err(|err| invalid_input("Invalid port argument", err))?)),
+        (None, Some(name)) => Ok(Some(session_port(name)?)),
+        (None, None) => Ok(None),
+    }
+}
+
+/// The port number in $FF_PORT, or the port of the $FF_SESSION instance
+fn env_port() -> Result<u16> {
+    if let Ok(s) = env::var("FF_PORT") {
+        return u16::from_str(&s).map_err(|err| invalid_input("Invalid port argument", err));
+    }
+    match env::var("FF_SESSION") {
+        Ok(name) => session_port(&name),
+        Err(_) => Err(invalid_input("No port given", "use --port, --session, $FF_PORT or $FF_SESSION")),
+    }
+}
+
+/// Look up the port of a named instance
+fn session_port(name: &str) -> Result<u16> {
+    Ok(ff::find_instance(name)?.port)
+}
+
+fn connect_to_port(port: u16) -> Result<MarionetteConnection> {
+    ff::connect(port)
+        .map_err(|err| match err {
+            MarionetteError::Io(err) => io::Error::new(err.kind(), format!("Unable to connect to firefox: {}", err)).into(),
+            err => err,
+        })
 }
 
 fn option_port<'a, 'b>() -> Arg<'a, 'b> {
@@ -299,8 +1251,9 @@ fn option_json_filters<'a, 'b>() -> [Arg<'a, 'b>; 1] {
     ]
 }
 
-fn main() {
-    let matches = App::new("ff")
+/// The command line interface definition, shared by all frontends
+fn build_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("ff")
         .about("Firefox from your shell")
         .version(crate_version!())
         .setting(AppSettings::SubcommandRequiredElseHelp)
@@ -310,165 +1263,457 @@ fn main() {
              .short("v")
              .multiple(true)
              .long("verbose"))
-        .subcommand(SubCommand::with_name("start")
-                    .arg(option_port())
-                    .arg(Arg::with_name("no-fork")
-                         .help("Run ff in the foreground")
-                         .long("no-fork"))
-                    .arg(Arg::with_name("no-marionette")
-                         .help("Run without marionette")
-                         .long("no-marionette"))
-                    .arg(Arg::with_name("rm-profile")
-                         .help("Remove profile on exit")
-                         .long("rm-profile"))
-                    .arg(Arg::with_name("PROFILE")
-                         .takes_value(true)
-                         .help("Profile path")
-                         .long("profile")
-                         .short("P"))
-                    .arg(Arg::with_name("SESSION")
-                         .takes_value(true)
-                         .help("Give a name to this session")
-                         .long("session")
-                         .short("S"))
-                    .arg(Arg::with_name("FIREFOX-BIN")
-                         .takes_value(true)
-                         .help("Firefox binary path")
-                         .long("firefox-bin"))
-                    .arg(Arg::with_name("PREFSFILE")
-                         .takes_value(true)
-                         .help("Firefox user.js file")
-                         .long("user-js"))
-                    .arg(Arg::with_name("EXTRAPREFS")
-                         .takes_value(true)
-                         .multiple(true)
-                         .help("Additional Firefox profile files")
-                         .long("extra-prefs"))
-                    .arg(Arg::with_name("URL")
-                         .help("Open the given URL after starting"))
-                    .about("Start a new browser instance"))
-        .subcommand(SubCommand::with_name("go")
-                    .arg(option_port())
-                    .about("Navigate to URL")
-                    .arg(Arg::with_name("URL")
-                         .required(true)
-                        ))
-        .subcommand(SubCommand::with_name("download")
-                    .arg(option_port())
-                    .about("Download URL")
-                    .arg(Arg::with_name("URL")
-                         .required(true))
-                    .arg(Arg::with_name("FILE")
-                         .required(true)))
-        .subcommand(SubCommand::with_name("back")
-                    .arg(option_port())
-                    .about("Go back to the previous page in history"))
-        .subcommand(SubCommand::with_name("forward")
-                    .arg(option_port())
-                    .about("Go forward to the next page in history"))
-        .subcommand(SubCommand::with_name("source")
-                    .arg(option_port())
-                    .about("Print page source"))
-        .subcommand(SubCommand::with_name("attr")
-                    .arg(option_port())
-                    .arg(Arg::with_name("SELECTOR")
-                         .required(true))
-                    .arg(Arg::with_name("ATTRNAME")
-                         .required(true))
-                    .about("Print element attribute"))
-        .subcommand(SubCommand::with_name("exec")
-                    .arg(option_port())
-                    .arg(Arg::with_name("SCRIPT")
-                         .required(true)
-                         .help("Javascript code"))
-                    .arg(Arg::with_name("ASYNC")
-                         .takes_value(false)
-                         .long("async")
-                         .help("Run asynchronous script"))
-                    .arg(Arg::with_name("SANDBOX")
-                         .takes_value(true)
-                         .long("sandbox")
-                         .required(false)
-                         .help("Sandbox name"))
-                    .arg(Arg::with_name("TIMEOUT")
-                         .takes_value(true)
-                         .long("timeout")
-                         .required(false)
-                         .help("Timeout script execution after TIMEOUT milliseconds"))
-                    .arg(Arg::with_name("ARG")
-                         .multiple(true)
-                         .required(false)
-                         .help("Script arguments[]"))
-                    .args(&option_json_filters())
-                    .about("Executes script in all frames, print its return value"))
-        .subcommand(SubCommand::with_name("property")
-                    .arg(option_port())
-                    .arg(Arg::with_name("SELECTOR")
-                         .required(true))
-                    .arg(Arg::with_name("NAME")
-                         .required(true))
-                    .args(&option_json_filters())
-                    .about("Print element property")
-                    .alias("prop"))
-        .subcommand(SubCommand::with_name("text")
-                    .arg(option_port())
-                    .arg(Arg::with_name("SELECTOR")
-                         .required(true))
-                    .about("Print element text"))
-        .subcommand(SubCommand::with_name("install")
-                    .arg(option_port())
-                    .arg(Arg::with_name("PATH")
-                         .required(true))
-                    .about("Install XPI addon"))
-        .subcommand(SubCommand::with_name("prefget")
-                    .arg(option_port())
-                    .arg(Arg::with_name("NAME")
-                         .required(true))
-                    .about("Get firefox preference"))
-        .subcommand(SubCommand::with_name("prefset")
-                    .arg(option_port())
-                    .arg(Arg::with_name("NAME")
-                         .required(true))
-                    .arg(Arg::with_name("VALUE")
-                         .required(true))
-                    .about("Set firefox preference"))
-        .subcommand(SubCommand::with_name("instances")
-                    .about("List running ff instances"))
-        .subcommand(SubCommand::with_name("title")
-                    .arg(option_port())
-                    .about("Print page title"))
-        .subcommand(SubCommand::with_name("url")
-                    .arg(option_port())
-                    .about("Print page url"))
-        .subcommand(SubCommand::with_name("quit")
-                    .arg(option_port())
-                    .about("Close the browser"))
-        .subcommand(SubCommand::with_name("windows")
-                    .arg(option_port())
-                    .about("List browser windows"))
-        .subcommand(SubCommand::with_name("cookies")
-                    .arg(option_port())
-                    .about("List browser cookies"))
-        .subcommand(SubCommand::with_name("addcookie")
-                    .arg(option_port())
-                    .arg(Arg::with_name("NAME")
-                         .required(true))
-                    .arg(Arg::with_name("VALUE")
-                         .required(true))
-                    .arg(Arg::with_name("DOMAIN")
-                         .required(false))
-                    .arg(Arg::with_name("PATH")
-                         .required(false))
-                    .about("Set cookie"))
-        .subcommand(SubCommand::with_name("switch")
-                    .arg(option_port())
-                    .arg(Arg::with_name("index")
-                         .help("Treat WINDOW as an index instead of a window id")
-                         .long("idx"))
-                    .arg(Arg::with_name("WINDOW")
-                         .required(true))
-                    .about("Switch browser window"))
-        .get_matches();
+        .arg(Arg::with_name("FORMAT")
+             .help("Output format")
+             .long("format")
+             .takes_value(true)
+             .possible_values(output::FORMATS)
+             .global(true))
+        .arg(Arg::with_name("SESSION")
+             .help("Session name, gives a name to the browser on start")
+             .long("session")
+             .takes_value(true)
+             .global(true))
+        .arg(Arg::with_name("CHROME")
+             .help("Run exec, text, attr, property and source against the browser UI instead of the page")
+             .long("chrome")
+             .global(true))
+        .arg(Arg::with_name("ALL")
+             .help("Run the command on every running instance")
+             .long("all")
+             .global(true))
+        .arg(Arg::with_name("INSTANCES")
+             .help("Run the command on the instances with a matching session name, * and ? are wildcards")
+             .long("instances")
+             .takes_value(true)
+             .global(true))
+        .subcommands(subcommands())
+}
+
+/// All subcommands
+fn subcommands<'a, 'b>() -> Vec<App<'a, 'b>> {
+    vec![
+        SubCommand::with_name("start")
+            .arg(option_port())
+            .arg(Arg::with_name("no-fork")
+                 .help("Run ff in the foreground")
+                 .long("no-fork"))
+            .arg(Arg::with_name("no-marionette")
+                 .help("Run without marionette")
+                 .long("no-marionette"))
+            .arg(Arg::with_name("no-daemon")
+                 .help("Do not share the browser session through a daemon")
+                 .long("no-daemon"))
+            .arg(Arg::with_name("headless")
+                 .help("Run firefox without a window")
+                 .long("headless"))
+            .arg(Arg::with_name("bidi")
+                 .help("Enable WebDriver BiDi on a free port")
+                 .long("bidi"))
+            .arg(Arg::with_name("BIDI-PORT")
+                 .takes_value(true)
+                 .help("Enable WebDriver BiDi on this port")
+                 .long("bidi-port"))
+            .arg(Arg::with_name("devtools")
+                 .help("Start the devtools server on a free port, for ff devtools")
+                 .long("devtools"))
+            .arg(Arg::with_name("DEVTOOLS-PORT")
+                 .takes_value(true)
+                 .help("Start the devtools server on this port")
+                 .long("devtools-port"))
+            .arg(Arg::with_name("xvfb")
+                 .help("Run firefox on a private Xvfb display")
+                 .long("xvfb")
+                 .conflicts_with("headless"))
+            .arg(Arg::with_name("WINDOW-SIZE")
+                 .takes_value(true)
+                 .help("Window size for --headless and --xvfb, e.g. 1366x768")
+                 .long("window-size"))
+            .arg(Arg::with_name("ARG")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .allow_hyphen_values(true)
+                 .help("Additional firefox argument")
+                 .long("arg"))
+            .arg(Arg::with_name("ENV")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .help("Additional environment variable, NAME=VALUE")
+                 .long("env"))
+            .arg(Arg::with_name("QUIT-TIMEOUT")
+                 .takes_value(true)
+                 .long("quit-timeout")
+                 .help("Seconds to wait for firefox to quit on shutdown before sending SIGTERM"))
+            .arg(Arg::with_name("TERM-TIMEOUT")
+                 .takes_value(true)
+                 .long("term-timeout")
+                 .help("Seconds to wait after SIGTERM before killing firefox"))
+            .arg(Arg::with_name("rm-profile")
+                 .help("Remove profile on exit")
+                 .long("rm-profile"))
+            .arg(Arg::with_name("PROFILE")
+                 .takes_value(true)
+                 .help("Profile path")
+                 .long("profile")
+                 .short("P"))
+            .arg(Arg::with_name("FIREFOX-BIN")
+                 .takes_value(true)
+                 .help("Firefox binary path")
+                 .long("firefox-bin"))
+            .arg(Arg::with_name("CHANNEL")
+                 .takes_value(true)
+                 .possible_values(ff::channels::CHANNELS)
+                 .conflicts_with("FIREFOX-BIN")
+                 .help("Use the installed firefox of this channel, Linux only")
+                 .long("channel"))
+            .arg(Arg::with_name("MIN-VERSION")
+                 .takes_value(true)
+                 .help("Refuse older firefox versions, defaults to $FF_MIN_VERSION")
+                 .long("min-version"))
+            .arg(Arg::with_name("PREFSFILE")
+                 .takes_value(true)
+                 .help("Firefox user.js file")
+                 .long("user-js"))
+            .arg(Arg::with_name("EXTRAPREFS")
+                 .takes_value(true)
+                 .multiple(true)
+                 .help("Additional Firefox profile files")
+                 .long("extra-prefs"))
+            .arg(Arg::with_name("URL")
+                 .help("Open the given URL after starting"))
+            .about("Start a new browser instance"),
+        SubCommand::with_name("go")
+            .arg(option_port())
+            .about("Navigate to URL")
+            .arg(Arg::with_name("URL")
+                 .required(true)
+                ),
+        SubCommand::with_name("download")
+            .arg(option_port())
+            .about("Download URL")
+            .arg(Arg::with_name("URL")
+                 .required(true))
+            .arg(Arg::with_name("FILE")
+                 .required(true)),
+        SubCommand::with_name("back")
+            .arg(option_port())
+            .about("Go back to the previous page in history"),
+        SubCommand::with_name("forward")
+            .arg(option_port())
+            .about("Go forward to the next page in history"),
+        SubCommand::with_name("source")
+            .arg(option_port())
+            .about("Print page source"),
+        SubCommand::with_name("attr")
+            .arg(option_port())
+            .arg(Arg::with_name("SELECTOR")
+                 .required(true))
+            .arg(Arg::with_name("ATTRNAME")
+                 .required(true))
+            .about("Print element attribute"),
+        SubCommand::with_name("exec")
+            .arg(option_port())
+            .arg(Arg::with_name("SCRIPT")
+                 .required_unless_one(&["FILE", "NEW"])
+                 .help("Javascript code"))
+            .arg(Arg::with_name("FILE")
+                 .takes_value(true)
+                 .long("file")
+                 .short("f")
+                 .help("Read script from file"))
+            .arg(Arg::with_name("LIB")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .long("lib")
+                 .help("Helper library to load before the script"))
+            .arg(Arg::with_name("NO-LIB-DIR")
+                 .long("no-lib-dir")
+                 .help("Do not load helper libraries from $FF_LIB_DIR or ~/.ff/lib"))
+            .arg(Arg::with_name("ASYNC")
+                 .takes_value(false)
+                 .long("async")
+                 .help("Run asynchronous script"))
+            .arg(Arg::with_name("SANDBOX")
+                 .takes_value(true)
+                 .long("sandbox")
+                 .required(false)
+                 .help("Sandbox name, named sandboxes keep their globals until the page is unloaded"))
+            .arg(Arg::with_name("NEW")
+                 .long("new")
+                 .requires("SANDBOX")
+                 .help("Start from a fresh sandbox, replacing the one with this name"))
+            .arg(Arg::with_name("TIMEOUT")
+                 .takes_value(true)
+                 .long("timeout")
+                 .required(false)
+                 .help("Timeout script execution after TIMEOUT milliseconds"))
+            .arg(Arg::with_name("ARG")
+                 .multiple(true)
+                 .required(false)
+                 .help("Script arguments[]"))
+            .args(&option_json_filters())
+            .about("Executes script in all frames, print its return value"),
+        SubCommand::with_name("property")
+            .arg(option_port())
+            .arg(Arg::with_name("SELECTOR")
+                 .required(true))
+            .arg(Arg::with_name("NAME")
+                 .required(true))
+            .args(&option_json_filters())
+            .about("Print element property")
+            .alias("prop"),
+        SubCommand::with_name("text")
+            .arg(option_port())
+            .arg(Arg::with_name("SELECTOR")
+                 .required(true))
+            .about("Print element text"),
+        SubCommand::with_name("install")
+            .arg(option_port())
+            .arg(Arg::with_name("PATH")
+                 .required(true))
+            .about("Install XPI addon"),
+        SubCommand::with_name("prefget")
+            .arg(option_port())
+            .arg(Arg::with_name("NAME")
+                 .required(true))
+            .about("Get firefox preference"),
+        SubCommand::with_name("prefset")
+            .arg(option_port())
+            .arg(Arg::with_name("NAME")
+                 .required(true))
+            .arg(Arg::with_name("VALUE")
+                 .required(true))
+            .about("Set firefox preference"),
+        SubCommand::with_name("call")
+            .arg(option_port())
+            .arg(Arg::with_name("COMMAND")
+                 .required(true)
+                 .help("Marionette command name, e.g. WebDriver:GetTitle"))
+            .arg(Arg::with_name("JSON")
+                 .help("Command parameters, {} by default"))
+            .about("Send a marionette command and print the response"),
+        SubCommand::with_name("devtools")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("eval")
+                 .arg(option_port())
+                 .arg(Arg::with_name("EXPRESSION")
+                      .required(true)
+                      .help("Javascript expression, evaluated like in the web console"))
+                 .arg(Arg::with_name("DEPTH")
+                      .takes_value(true)
+                      .long("depth")
+                      .help("Expand object properties this many levels deep, defaults to 1"))
+                 .args(&option_json_filters())
+                 .about("Evaluate an expression in the web console of the selected tab"))
+            .subcommand(SubCommand::with_name("inspect")
+                 .arg(option_port())
+                 .arg(Arg::with_name("SELECTOR")
+                      .required(true)
+                      .help("CSS selector of the element"))
+                 .arg(Arg::with_name("PROPERTY")
+                      .takes_value(true)
+                      .multiple(true)
+                      .number_of_values(1)
+                      .long("property")
+                      .help("Only print this computed property"))
+                 .arg(Arg::with_name("rules")
+                      .long("rules")
+                      .help("Print the CSS rules that apply to the element instead"))
+                 .about("Print the computed style of the first matching element"))
+            .about("Use the devtools server of a browser started with --devtools"),
+        SubCommand::with_name("instances")
+            .arg(Arg::with_name("prune")
+                 .long("prune")
+                 .help("Remove instances whose browser is no longer running"))
+            .about("List running ff instances"),
+        SubCommand::with_name("kill")
+            .arg(Arg::with_name("INSTANCE")
+                 .required(true)
+                 .help("Port number or session name"))
+            .about("Kill an instance that no longer responds"),
+        SubCommand::with_name("logs")
+            .arg(option_port())
+            .arg(Arg::with_name("follow")
+                 .long("follow")
+                 .short("f")
+                 .help("Keep printing lines as they are written"))
+            .arg(Arg::with_name("LINES")
+                 .takes_value(true)
+                 .long("lines")
+                 .short("n")
+                 .help("Print only the last LINES lines"))
+            .about("Print the firefox output of an instance"),
+        SubCommand::with_name("attach")
+            .arg(Arg::with_name("PROFILE-NAME")
+                 .takes_value(true)
+                 .long("profile-name")
+                 .help("Name of the profile in profiles.ini"))
+            .arg(Arg::with_name("PROFILE")
+                 .takes_value(true)
+                 .long("profile")
+                 .short("P")
+                 .conflicts_with("PROFILE-NAME")
+                 .help("Profile path"))
+            .about("Use a firefox started with --marionette, by default the one using the default profile"),
+        SubCommand::with_name("gc")
+            .arg(Arg::with_name("dry-run")
+                 .long("dry-run")
+                 .help("Only print the profiles that would be removed"))
+            .about("Remove exited instances and unused temporary profiles"),
+        SubCommand::with_name("crashes")
+            .arg(Arg::with_name("COLLECT")
+                 .takes_value(true)
+                 .long("collect")
+                 .help("Copy the minidumps and their metadata to this folder"))
+            .about("List firefox crash reports"),
+        SubCommand::with_name("use")
+            .arg(Arg::with_name("INSTANCE")
+                 .required(true)
+                 .help("Port number or session name"))
+            .about("Print the FF_PORT setting for an instance"),
+        SubCommand::with_name("title")
+            .arg(option_port())
+            .about("Print page title"),
+        SubCommand::with_name("url")
+            .arg(option_port())
+            .about("Print page url"),
+        SubCommand::with_name("quit")
+            .arg(option_port())
+            .arg(Arg::with_name("wait")
+                 .long("wait")
+                 .help("Wait until firefox has exited and released the profile"))
+            .arg(Arg::with_name("TIMEOUT")
+                 .takes_value(true)
+                 .long("timeout")
+                 .requires("wait")
+                 .help("Seconds to wait, 60 by default"))
+            .about("Close the browser"),
+        SubCommand::with_name("windows")
+            .arg(option_port())
+            .about("List browser windows"),
+        SubCommand::with_name("cookies")
+            .arg(option_port())
+            .about("List browser cookies"),
+        SubCommand::with_name("addcookie")
+            .arg(option_port())
+            .arg(Arg::with_name("NAME")
+                 .required(true))
+            .arg(Arg::with_name("VALUE")
+                 .required(true))
+            .arg(Arg::with_name("DOMAIN")
+                 .required(false))
+            .arg(Arg::with_name("PATH")
+                 .required(false))
+            .about("Set cookie"),
+        SubCommand::with_name("switch")
+            .arg(option_port())
+            .arg(Arg::with_name("index")
+                 .help("Treat WINDOW as an index instead of a window id")
+                 .long("idx"))
+            .arg(Arg::with_name("WINDOW")
+                 .required(true))
+            .about("Switch browser window"),
+        SubCommand::with_name("shell")
+            .arg(option_port())
+            .about("Interactive shell, commands share a single connection"),
+        SubCommand::with_name("run")
+            .arg(option_port())
+            .arg(Arg::with_name("SCRIPT")
+                 .help("File with one ff command per line, defaults to stdin"))
+            .arg(Arg::with_name("CONTINUE-ON-ERROR")
+                 .long("continue-on-error")
+                 .help("Keep going when a command fails"))
+            .arg(Arg::with_name("TIMING")
+                 .long("timing")
+                 .help("Print the time taken by each command to stderr"))
+            .about("Run a batch of ff commands over a single connection"),
+        SubCommand::with_name("pool")
+            .arg(Arg::with_name("SCRIPT")
+                 .required(true)
+                 .help("File with one ff command per line, $URL is the URL being processed"))
+            .arg(Arg::with_name("URLS")
+                 .help("File with one URL per line, defaults to stdin"))
+            .arg(Arg::with_name("SIZE")
+                 .takes_value(true)
+                 .long("size")
+                 .short("n")
+                 .help("Number of browsers, defaults to 4"))
+            .arg(Arg::with_name("MAX-USES")
+                 .takes_value(true)
+                 .long("max-uses")
+                 .help("Replace a browser after it processed this many URLs"))
+            .arg(Arg::with_name("headless")
+                 .help("Run firefox without a window")
+                 .long("headless"))
+            .arg(Arg::with_name("FIREFOX-BIN")
+                 .takes_value(true)
+                 .help("Firefox binary path")
+                 .long("firefox-bin"))
+            .arg(Arg::with_name("CHANNEL")
+                 .takes_value(true)
+                 .possible_values(ff::channels::CHANNELS)
+                 .conflicts_with("FIREFOX-BIN")
+                 .help("Use the installed firefox of this channel, Linux only")
+                 .long("channel"))
+            .arg(Arg::with_name("MIN-VERSION")
+                 .takes_value(true)
+                 .help("Refuse older firefox versions, defaults to $FF_MIN_VERSION")
+                 .long("min-version"))
+            .arg(Arg::with_name("PREFSFILE")
+                 .takes_value(true)
+                 .help("Firefox user.js file")
+                 .long("user-js"))
+            .arg(Arg::with_name("CONTINUE-ON-ERROR")
+                 .long("continue-on-error")
+                 .help("Keep going when a command fails"))
+            .arg(Arg::with_name("TIMING")
+                 .long("timing")
+                 .help("Print the time taken by each command to stderr"))
+            .about("Run a batch of ff commands for every URL, on several browsers at once"),
+        SubCommand::with_name("serve-webdriver")
+            .arg(Arg::with_name("WEBDRIVER-PORT")
+                 .takes_value(true)
+                 .long("port")
+                 .short("p")
+                 .help("Port for WebDriver clients, defaults to 4444"))
+            .arg(Arg::with_name("HOST")
+                 .takes_value(true)
+                 .long("host")
+                 .help("Address to listen on, defaults to 127.0.0.1"))
+            .arg(Arg::with_name("CONNECT")
+                 .takes_value(true)
+                 .long("connect")
+                 .help("Use a running instance, by port or session name, instead of starting a browser per session"))
+            .arg(Arg::with_name("headless")
+                 .help("Run firefox without a window")
+                 .long("headless"))
+            .arg(Arg::with_name("FIREFOX-BIN")
+                 .takes_value(true)
+                 .help("Firefox binary path")
+                 .long("firefox-bin"))
+            .arg(Arg::with_name("CHANNEL")
+                 .takes_value(true)
+                 .possible_values(ff::channels::CHANNELS)
+                 .conflicts_with("FIREFOX-BIN")
+                 .help("Use the installed firefox of this channel, Linux only")
+                 .long("channel"))
+            .arg(Arg::with_name("MIN-VERSION")
+                 .takes_value(true)
+                 .help("Refuse older firefox versions, defaults to $FF_MIN_VERSION")
+                 .long("min-version"))
+            .arg(Arg::with_name("PREFSFILE")
+                 .takes_value(true)
+                 .help("Firefox user.js file")
+                 .long("user-js"))
+            .about("Serve the W3C WebDriver HTTP protocol, for Selenium and other WebDriver clients"),
+    ]
+}
+
+fn main() {
+    let matches = build_app().get_matches();
 
     let verbose = matches.occurrences_of("verbose") as usize;
     let mut log = stderrlog::new();
@@ -487,133 +1732,24 @@ it can be fixed - {}\n", ISSUES_URL);
         def_panic_hook(info);
     }));
 
-    match matches.subcommand() {
-        ("go", Some(ref args)) => cmd_go(args).unwrap_or_exit(-1),
-        ("back", Some(ref args)) => connect_to_port(args).go_back().unwrap_or_exit(-1),
-        ("download", Some(ref args)) => cmd_download(args).unwrap_or_exit(-1),
-        ("forward", Some(ref args)) => connect_to_port(args).go_forward().unwrap_or_exit(-1),
-        ("source", Some(ref args)) => println!("{}", connect_to_port(args).get_page_source().unwrap_or_exit(-1)),
-        ("text", Some(ref args)) => {
-            let mut conn = connect_to_port(args);
-            conn.switch_to_frame(None).unwrap_or_exit(-1);
-            foreach_frame(&mut conn, args, &|conn, args| {
-                foreach_element(conn, args, &|elem| {
-                    let text = elem.text()?;
-                    if !text.is_empty() {
-                        println!("{}", text);
-                    }
-                    Ok(())
-                })
-            }).unwrap_or_exit(-1);
-            conn.switch_to_frame(None).unwrap_or_exit(-1);
-        }
-        ("attr", Some(ref args)) => {
-            let mut conn = connect_to_port(args);
-            let attrname = args.value_of("ATTRNAME").unwrap();
-            conn.switch_to_frame(None).unwrap_or_exit(-1);
-            foreach_frame(&mut conn, args, &|conn, args| {
-                foreach_element(conn, args, &|elem| {
-                    let text = elem.attr(attrname)?.unwrap_or(String::new());
-                    if !text.is_empty() {
-                        println!("{}", text);
-                    }
-                    Ok(())
-                })
-            }).unwrap_or_exit(-1);
-            conn.switch_to_frame(None).unwrap_or_exit(-1);
-        }
-        ("exec", Some(ref args)) => {
-            let mut conn = connect_to_port(args);
-            let mut js = args.value_of("SCRIPT").unwrap().to_owned();
-            if js == "-" {
-                js.clear();
-                io::stdin().read_to_string(&mut js).unwrap_or_exitmsg(-1, "Error reading stdin");
-            }
-
-            let mut script = Script::new(&js);
-
-            if args.is_present("SANDBOX") {
-                script.sandbox(args.value_of("SANDBOX").unwrap());
-            }
-
-            if let Some(s_timeout) = args.value_of("TIMEOUT") {
-                let ms = u64::from_str(s_timeout)
-                    .expect("Invalid TIMEOUT value");
-                script.timeout(ms);
-            }
-
-            if let Some(iter) = args.values_of("ARG") {
-                let mut script_args: Vec<JsonValue> = Vec::new();
-                for arg in iter {
-                    let val = JsonValue::from_str(arg).unwrap_or_exitmsg(-1, "Script argument is invalid JSON");
-                    script_args.push(val);
-                }
-                script.arguments(script_args).unwrap_or_exit(-1);
-            }
+    let format = matches.value_of("FORMAT")
+        .and_then(|f| Format::from_str(f).ok())
+        .unwrap_or(Format::Plain);
+    let mut out = Output::new(format);
 
-            conn.switch_to_frame(None).unwrap_or_exit(-1);
-            foreach_frame(&mut conn, args, &|conn, args| {
-                let res = if args.is_present("ASYNC") {
-                    conn.execute_async_script(&script)
-                } else {
-                    conn.execute_script(&script)
-                };
-
-                match res {
-                    Ok(val) => print_json_value(&val, args),
-                    Err(ref err) if !err.is_fatal() => error!("Error executing script: {}", err),
-                    Err(err) => return Err(err),
-                }
-                Ok(())
-            }).unwrap_or_exit(-1);
-            conn.switch_to_frame(None).unwrap_or_exit(-1);
-        }
-        ("prefget", Some(ref args)) => println!("{}", connect_to_port(args).get_pref(args.value_of("NAME").unwrap()).unwrap()),
-        ("property", Some(ref args)) => {
-            let mut conn = connect_to_port(args);
-            let propname = args.value_of("NAME").unwrap();
-            conn.switch_to_frame(None).unwrap_or_exit(-1);
-            foreach_frame(&mut conn, args, &|conn, args| {
-                foreach_element(conn, args, &|elem| {
-                    let val = elem.property(propname)?;
-                    print_json_value(&val, args);
-                    Ok(())
-                })
-            }).unwrap_or_exit(-1);
-            conn.switch_to_frame(None).unwrap_or_exit(-1);
-        }
-        ("prefset", Some(ref args)) => {
-            let name = args.value_of("NAME").unwrap();
-            let value = JsonValue::from_str(args.value_of("VALUE").unwrap()).unwrap_or_exitmsg(-1, "Invalid JSON argument");
-            connect_to_port(args).set_pref(name, value).unwrap_or_exitmsg(-1, "Unable to set preference");
-        }
-        ("title", Some(ref args)) => println!("{}", connect_to_port(args).get_title().unwrap_or_exit(-1)),
-        ("url", Some(ref args)) => println!("{}", connect_to_port(args).get_url().unwrap_or_exit(-1)),
-        ("quit", Some(ref args)) => connect_to_port(args).quit().unwrap_or_exit(-1),
-        ("start", Some(ref args)) => cmd_start(args).unwrap_or_exitmsg(-1, "Unable to start browser"),
-        ("install", Some(ref args)) => cmd_install(args).unwrap_or_exitmsg(-1, "Unable to install addon"),
-        ("instances", _) => cmd_instances().unwrap_or_exitmsg(-1, "Unable to list ff instances"),
-        ("switch", Some(ref args)) => {
-            let mut conn = connect_to_port(args);
-            let handle = if args.is_present("index") {
-                let idx = usize::from_str(args.value_of("WINDOW").unwrap())
-                    .expect("Invalid WINDOW index");
-                let mut handles = conn.get_window_handles()
-                    .unwrap_or_exitmsg(-1, "Unable to get window list");
-                let handle = handles.drain(..)
-                    .nth(idx)
-                    .unwrap_or_exitmsg(-1, "Index is invalid");
-                handle
-            } else {
-                WindowHandle::from_str(args.value_of("WINDOW").unwrap())
-            };
-            conn.switch_to_window(&handle)
-                .unwrap_or_exitmsg(-1, "Unable to switch window");
-        }
-        ("windows", Some(ref args)) => cmd_windows(args).unwrap_or_exit(-1),
-        ("cookies", Some(ref args)) => cmd_cookies(args).unwrap_or_exit(-1),
-        ("addcookie", Some(ref args)) => cmd_addcookie(args).unwrap_or_exit(-1),
-        _ => panic!("Unsupported command"),
+    let (name, args) = matches.subcommand();
+    let mut conn = Connector::new();
+    let argv: Vec<String> = env::args().collect();
+    let res = run_command(name, args.unwrap(), &argv, &mut conn, &mut out);
+    // Records collected before an error are still printed
+    out.finish();
+    let code = match res {
+        Err(MarionetteError::BrowserCrashed(_)) => EXIT_CRASHED,
+        _ => -1,
+    };
+    match error_context(name) {
+        Some(msg) => res.unwrap_or_exitmsg(code, msg),
+        None => res.unwrap_or_exit(code),
     }
 }
 
@@ -0,0 +1,251 @@
+//! Output formats for the ff CLI
+//!
+//! Commands produce records, a list of named JSON values. In plain mode
+//! each command chooses its own text representation, the other formats
+//! are generated from the record fields.
+
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use marionette::JsonValue;
+use serde_json::Map;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    /// Human readable output, the default
+    Plain,
+    /// A single JSON array with all records
+    Json,
+    /// One JSON object per line
+    Jsonl,
+    /// Tab separated values, one record per line after a header with the
+    /// field names
+    Tsv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Format::Plain),
+            "json" => Ok(Format::Json),
+            "jsonl" => Ok(Format::Jsonl),
+            "tsv" => Ok(Format::Tsv),
+            other => Err(format!("Unknown output format: {}", other)),
+        }
+    }
+}
+
+pub const FORMATS: &[&str] = &["plain", "json", "jsonl", "tsv"];
+
+/// A single result. Plain text and TSV keep the fields in insertion order,
+/// JSON objects have their keys sorted.
+pub struct Record {
+    fields: Vec<(&'static str, JsonValue)>,
+}
+
+impl Record {
+    pub fn new() -> Self {
+        Record { fields: Vec::new() }
+    }
+
+    pub fn field<V: Into<JsonValue>>(mut self, name: &'static str, value: V) -> Self {
+        self.fields.push((name, value.into()));
+        self
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut map = Map::new();
+        for (name, value) in &self.fields {
+            map.insert(name.to_string(), value.clone());
+        }
+        JsonValue::Object(map)
+    }
+
+    fn tsv_header(&self) -> String {
+        let names: Vec<&str> = self.fields.iter().map(|&(name, _)| name).collect();
+        names.join("\t")
+    }
+
+    fn to_tsv(&self) -> String {
+        let values: Vec<String> = self.fields.iter()
+            .map(|(_, value)| match value {
+                JsonValue::String(s) => s.replace('\\', "\\\\")
+                    .replace('\t', "\\t")
+                    .replace('\n', "\\n"),
+                JsonValue::Null => String::new(),
+                other => other.to_string(),
+            })
+            .collect();
+        values.join("\t")
+    }
+}
+
+pub struct Output {
+    format: Format,
+    records: Vec<JsonValue>,
+    /// When set output is kept here instead of going to stdout
+    captured: Option<Vec<u8>>,
+    /// Prefix for lines and `instance` field for records, see `labeled()`
+    label: Option<String>,
+    /// TSV headers written so far, the header is repeated when the fields
+    /// change
+    tsv_headers: Vec<String>,
+}
+
+impl Output {
+    pub fn new(format: Format) -> Self {
+        Output {
+            format,
+            records: Vec::new(),
+            captured: None,
+            label: None,
+            tsv_headers: Vec::new(),
+        }
+    }
+
+    /// Output that is kept in memory, see `captured()`
+    pub fn capture(format: Format) -> Self {
+        Output {
+            captured: Some(Vec::new()),
+            ..Output::new(format)
+        }
+    }
+
+    /// Captured output for one of several browsers, see `append()`. Lines
+    /// are prefixed with the label and records get an `instance` field.
+    pub fn labeled(format: Format, label: &str) -> Self {
+        Output {
+            label: Some(label.to_owned()),
+            ..Output::capture(format)
+        }
+    }
+
+    /// Add the output of an unfinished `labeled()` output
+    pub fn append(&mut self, other: Output) {
+        for line in other.captured().unwrap_or_default().lines() {
+            if other.tsv_headers.iter().any(|header| header == line) {
+                self.write_tsv_header(line);
+            } else {
+                self.write_line(line);
+            }
+        }
+        self.records.extend(other.records);
+    }
+
+    /// Write a TSV header unless it is the one of the previous records
+    fn write_tsv_header(&mut self, header: &str) {
+        if self.tsv_headers.last().map(String::as_str) != Some(header) {
+            self.write_line(header);
+            self.tsv_headers.push(header.to_owned());
+        }
+    }
+
+    /// The text written so far, for captured output
+    pub fn captured(&self) -> Option<String> {
+        self.captured.as_ref().map(|buf| String::from_utf8_lossy(buf).into_owned())
+    }
+
+    fn write_line(&mut self, line: &str) {
+        match self.captured {
+            Some(ref mut buf) => {
+                buf.extend_from_slice(line.as_bytes());
+                buf.push(b'\n');
+            }
+            None => {
+                let stdout = io::stdout();
+                let _ = writeln!(stdout.lock(), "{}", line);
+            }
+        }
+    }
+
+    pub fn format(&self) -> Format { self.format }
+
+    /// Emit a record, `plain` is the text used in plain mode. A `None` value
+    /// means nothing is printed in plain mode.
+    pub fn record(&mut self, plain: Option<&str>, record: Record) {
+        let record = match self.label {
+            Some(ref label) => {
+                let mut fields = vec![("instance", JsonValue::from(label.as_str()))];
+                fields.extend(record.fields);
+                Record { fields }
+            }
+            None => record,
+        };
+        match self.format {
+            Format::Plain => if let Some(text) = plain {
+                match self.label.clone() {
+                    Some(label) => for line in text.lines() {
+                        self.write_line(&format!("{}: {}", label, line));
+                    },
+                    None => self.write_line(text),
+                }
+            },
+            Format::Json => self.records.push(record.to_json()),
+            Format::Jsonl => self.write_line(&record.to_json().to_string()),
+            Format::Tsv => {
+                self.write_tsv_header(&record.tsv_header());
+                self.write_line(&record.to_tsv());
+            }
+        }
+    }
+
+    /// Flush buffered output, in JSON mode this prints the array of records
+    pub fn finish(&mut self) {
+        if self.format == Format::Json {
+            let records = JsonValue::Array(self.records.drain(..).collect());
+            self.write_line(&records.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(format: Format) -> String {
+        let mut out = Output::capture(format);
+        out.record(Some("first"), Record::new().field("name", "a\tb").field("value", 1));
+        out.record(None, Record::new().field("name", "c").field("value", JsonValue::Null));
+        out.finish();
+        out.captured().unwrap()
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(output(Format::Plain), "first\n");
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(output(Format::Json), "[{\"name\":\"a\\tb\",\"value\":1},{\"name\":\"c\",\"value\":null}]\n");
+        assert_eq!(output(Format::Jsonl), "{\"name\":\"a\\tb\",\"value\":1}\n{\"name\":\"c\",\"value\":null}\n");
+    }
+
+    #[test]
+    fn tsv() {
+        assert_eq!(output(Format::Tsv), "name\tvalue\na\\tb\t1\nc\t\n");
+
+        let mut out = Output::capture(Format::Tsv);
+        out.record(None, Record::new().field("port", 1));
+        out.record(None, Record::new().field("url", "x"));
+        assert_eq!(out.captured().unwrap(), "port\n1\nurl\nx\n");
+    }
+
+    #[test]
+    fn labeled() {
+        let mut out = Output::capture(Format::Tsv);
+        for label in &["a", "b"] {
+            let mut instance = Output::labeled(Format::Tsv, label);
+            instance.record(None, Record::new().field("title", "t"));
+            out.append(instance);
+        }
+        assert_eq!(out.captured().unwrap(), "instance\ttitle\na\tt\nb\tt\n");
+
+        let mut out = Output::capture(Format::Plain);
+        let mut instance = Output::labeled(Format::Plain, "a");
+        instance.record(Some("one\ntwo"), Record::new());
+        out.append(instance);
+        assert_eq!(out.captured().unwrap(), "a: one\na: two\n");
+    }
+}
@@ -0,0 +1,235 @@
+//! A pool of browsers for parallel work
+//!
+//! Each browser of the pool runs on its own port with its own temporary
+//! profile. Worker threads check out a connection with `Pool::get()`, the
+//! connection goes back to the pool when it is dropped.
+//!
+//! ```no_run
+//! let pool = ff::Pool::new(ff::BrowserBuilder::new().headless(true), 4)
+//!     .unwrap()
+//!     .max_uses(50);
+//! std::thread::scope(|scope| {
+//!     for url in &["https://www.mozilla.org", "https://www.rust-lang.org"] {
+//!         let pool = &pool;
+//!         scope.spawn(move || {
+//!             let mut conn = pool.get().unwrap();
+//!             conn.get(url).unwrap();
+//!             println!("{}", conn.get_title().unwrap());
+//!         });
+//!     }
+//! });
+//! ```
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread;
+
+use marionette::{MarionetteConnection, Result};
+
+use super::{Browser, BrowserBuilder};
+
+/// A browser of the pool and the session used to talk to it
+struct Member {
+    browser: Browser,
+    conn: MarionetteConnection,
+    uses: usize,
+}
+
+impl Member {
+    fn launch(builder: &BrowserBuilder) -> Result<Self> {
+        let (browser, conn) = builder.pool_member().launch()?;
+        Ok(Member { browser, conn, uses: 0 })
+    }
+
+    fn has_exited(&mut self) -> bool {
+        !matches!(self.browser.runner.process.try_wait(), Ok(None))
+    }
+
+    /// False once the browser exited or stopped answering
+    fn is_healthy(&mut self) -> bool {
+        !self.has_exited() && self.conn.get_title().is_ok()
+    }
+
+    fn shutdown(self) {
+        let Member { mut browser, conn, .. } = self;
+        let timeouts = browser.runner.options().shutdown;
+        if let Err(err) = browser.runner.shutdown(Some(conn), &timeouts) {
+            warn!("Unable to stop pool browser: {}", err);
+        }
+    }
+}
+
+struct State {
+    idle: Vec<Member>,
+    /// Browsers that are idle, checked out or starting
+    running: usize,
+}
+
+/// A fixed number of browsers shared by several threads, see the module
+/// documentation
+pub struct Pool {
+    builder: BrowserBuilder,
+    size: usize,
+    max_uses: Option<usize>,
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+impl Pool {
+    /// Start `size` browsers configured by `builder`. The port, profile and
+    /// session name of the builder are ignored.
+    pub fn new(builder: BrowserBuilder, size: usize) -> Result<Self> {
+        let launched: Vec<Result<Member>> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..size)
+                .map(|_| scope.spawn(|| Member::launch(&builder)))
+                .collect();
+            handles.into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|err| std::panic::resume_unwind(err)))
+                .collect()
+        });
+        // Members that did start are stopped when dropped
+        let idle = launched.into_iter().collect::<Result<Vec<Member>>>()?;
+
+        Ok(Pool {
+            builder,
+            size,
+            max_uses: None,
+            state: Mutex::new(State { idle, running: size }),
+            available: Condvar::new(),
+        })
+    }
+
+    /// Replace a browser after it was checked out `uses` times
+    pub fn max_uses(mut self, uses: usize) -> Self {
+        self.max_uses = Some(uses);
+        self
+    }
+
+    /// The number of browsers in the pool
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Check out a connection, blocks until a browser is available. Browsers
+    /// that crashed or stopped answering are replaced.
+    pub fn get(&self) -> Result<PooledConnection<'_>> {
+        loop {
+            let mut state = self.lock();
+            let member = loop {
+                if let Some(member) = state.idle.pop() {
+                    break Some(member);
+                }
+                if state.running < self.size {
+                    state.running += 1;
+                    break None;
+                }
+                state = self.available.wait(state).unwrap_or_else(|err| err.into_inner());
+            };
+            drop(state);
+
+            let member = match member {
+                Some(mut member) => {
+                    if !member.is_healthy() {
+                        warn!("Replacing unresponsive browser on port {:?}", member.browser.runner.port());
+                        self.retire(member);
+                        continue;
+                    }
+                    member
+                }
+                None => match Member::launch(&self.builder) {
+                    Ok(member) => member,
+                    Err(err) => {
+                        self.release();
+                        return Err(err);
+                    }
+                },
+            };
+            return Ok(PooledConnection { pool: self, member: Some(member), discard: false });
+        }
+    }
+
+    /// Stop a browser, a new one is started by the next `get()`
+    fn retire(&self, member: Member) {
+        member.shutdown();
+        self.release();
+    }
+
+    fn release(&self) {
+        self.lock().running -= 1;
+        self.available.notify_one();
+    }
+
+    fn put_back(&self, mut member: Member, discard: bool) {
+        member.uses += 1;
+        let worn_out = self.max_uses.is_some_and(|max| member.uses >= max);
+        if discard || worn_out || member.has_exited() {
+            debug!("Retiring browser on port {:?} after {} uses", member.browser.runner.port(), member.uses);
+            self.retire(member);
+        } else {
+            self.lock().idle.push(member);
+            self.available.notify_one();
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        let idle: Vec<Member> = self.lock().idle.drain(..).collect();
+        thread::scope(|scope| {
+            for member in idle {
+                scope.spawn(move || member.shutdown());
+            }
+        });
+    }
+}
+
+/// A connection checked out of a `Pool`, it goes back to the pool when
+/// dropped
+pub struct PooledConnection<'a> {
+    pool: &'a Pool,
+    member: Option<Member>,
+    discard: bool,
+}
+
+impl<'a> PooledConnection<'a> {
+    /// The browser this connection talks to
+    pub fn browser(&self) -> &Browser {
+        &self.member.as_ref().unwrap().browser
+    }
+
+    pub fn port(&self) -> u16 {
+        self.browser().runner.port().unwrap_or(0)
+    }
+
+    /// Replace the browser instead of returning it to the pool, e.g. when
+    /// it was left in a bad state
+    pub fn discard(mut self) {
+        self.discard = true;
+    }
+}
+
+impl<'a> Deref for PooledConnection<'a> {
+    type Target = MarionetteConnection;
+
+    fn deref(&self) -> &MarionetteConnection {
+        &self.member.as_ref().unwrap().conn
+    }
+}
+
+impl<'a> DerefMut for PooledConnection<'a> {
+    fn deref_mut(&mut self) -> &mut MarionetteConnection {
+        &mut self.member.as_mut().unwrap().conn
+    }
+}
+
+impl<'a> Drop for PooledConnection<'a> {
+    fn drop(&mut self) {
+        if let Some(member) = self.member.take() {
+            self.pool.put_back(member, self.discard);
+        }
+    }
+}
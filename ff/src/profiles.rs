@@ -0,0 +1,173 @@
+//! Firefox profiles of the user, from `profiles.ini`
+//!
+//! Used to attach to a browser that was not started by ff. Firefox started
+//! with `--marionette` writes the port it listens on to the
+//! `MarionetteActivePort` file in the profile.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use super::process_exists;
+
+/// Folder for the temporary profiles created by ff
+pub fn tmp_profile_root() -> PathBuf {
+    env::temp_dir().join("ff-profiles")
+}
+
+/// A profile listed in profiles.ini
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileEntry {
+    pub name: String,
+    pub path: PathBuf,
+    /// The default profile, the default of an installation takes
+    /// precedence over the legacy `Default=1` flag
+    pub default: bool,
+}
+
+/// The firefox folder with profiles.ini, `$FF_PROFILES_DIR` overrides the
+/// platform default
+pub fn firefox_dir() -> io::Result<PathBuf> {
+    if let Some(dir) = env::var_os("FF_PROFILES_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+
+    let home = dirs::home_dir()
+        .ok_or(io::Error::other("Could not determine your HOME folder"))?;
+    Ok(if cfg!(target_os = "macos") {
+        home.join("Library/Application Support/Firefox")
+    } else if cfg!(windows) {
+        match env::var_os("APPDATA") {
+            Some(appdata) => PathBuf::from(appdata).join("Mozilla").join("Firefox"),
+            None => home.join("AppData/Roaming/Mozilla/Firefox"),
+        }
+    } else {
+        home.join(".mozilla/firefox")
+    })
+}
+
+/// Parse the profiles in profiles.ini, relative paths are resolved against
+/// `dir`
+pub fn parse_profiles_ini(text: &str, dir: &Path) -> Vec<ProfileEntry> {
+    let mut sections: Vec<(String, Vec<(String, String)>)> = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') && line.ends_with(']') {
+            sections.push((line[1..line.len() - 1].to_owned(), Vec::new()));
+        } else if let Some((key, value)) = line.split_once('=') {
+            if let Some(section) = sections.last_mut() {
+                section.1.push((key.trim().to_owned(), value.trim().to_owned()));
+            }
+        }
+    }
+
+    // Newer versions keep the default per installation
+    let install_default: Vec<&str> = sections.iter()
+        .filter(|(name, _)| name.starts_with("Install"))
+        .filter_map(|(_, values)| values.iter().find(|(k, _)| k == "Default"))
+        .map(|(_, v)| v.as_str())
+        .collect();
+
+    let mut profiles = Vec::new();
+    for (name, values) in &sections {
+        if !name.starts_with("Profile") {
+            continue;
+        }
+        let get = |key: &str| values.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        let path = match get("Path") {
+            Some(path) => path,
+            None => continue,
+        };
+        profiles.push(ProfileEntry {
+            name: get("Name").unwrap_or("").to_owned(),
+            path: match get("IsRelative") {
+                Some("1") => dir.join(path),
+                _ => PathBuf::from(path),
+            },
+            default: if install_default.is_empty() {
+                get("Default") == Some("1")
+            } else {
+                install_default.contains(&path)
+            },
+        });
+    }
+    profiles
+}
+
+/// The profiles of the user
+pub fn profiles() -> io::Result<Vec<ProfileEntry>> {
+    let dir = firefox_dir()?;
+    let text = fs::read_to_string(dir.join("profiles.ini"))
+        .map_err(|err| io::Error::new(err.kind(), format!("Unable to read profiles.ini in {:?}: {}", dir, err)))?;
+    Ok(parse_profiles_ini(&text, &dir))
+}
+
+/// Find a profile by name, or the default profile
+pub fn find_profile(name: Option<&str>) -> io::Result<PathBuf> {
+    let profiles = profiles()?;
+    let found = match name {
+        Some(name) => profiles.into_iter().find(|p| p.name == name),
+        None => profiles.into_iter().find(|p| p.default),
+    };
+    found.map(|p| p.path).ok_or_else(|| match name {
+        Some(name) => io::Error::new(io::ErrorKind::NotFound, format!("No firefox profile named {}", name)),
+        None => io::Error::new(io::ErrorKind::NotFound, "No default firefox profile"),
+    })
+}
+
+/// The marionette port of the browser running with a profile
+pub fn active_port(profile: &Path) -> io::Result<u16> {
+    let text = fs::read_to_string(profile.join("MarionetteActivePort"))
+        .map_err(|err| io::Error::new(err.kind(),
+                                      format!("No marionette port in {:?}, is firefox running with --marionette? ({})",
+                                              profile, err)))?;
+    text.trim().parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid MarionetteActivePort: {}", err)))
+}
+
+/// The pid of the browser holding the profile lock, on unix the lock is a
+/// symlink to `HOST:+PID`
+#[cfg(unix)]
+pub fn lock_pid(profile: &Path) -> Option<u32> {
+    let target = fs::read_link(profile.join("lock")).ok()?;
+    target.to_str()?.rsplit(":+").next()?.parse().ok()
+}
+
+#[cfg(not(unix))]
+pub fn lock_pid(_profile: &Path) -> Option<u32> {
+    None
+}
+
+/// True while a browser holds the profile lock. A crashed browser leaves
+/// the lock behind, so the process it names must still be running.
+#[cfg(unix)]
+pub fn is_locked(profile: &Path) -> bool {
+    // The symlink is dangling, do not follow it
+    if fs::symlink_metadata(profile.join("lock")).is_err() {
+        return false;
+    }
+    match lock_pid(profile) {
+        Some(pid) => process_exists(pid),
+        // Not a lock we know how to read, assume it is held
+        None => true,
+    }
+}
+
+/// True while a browser holds the profile lock
+#[cfg(windows)]
+pub fn is_locked(profile: &Path) -> bool {
+    // The file stays around, but can not be removed while firefox runs
+    let path = profile.join("parent.lock");
+    path.exists() && fs::OpenOptions::new().write(true).open(&path).is_err()
+}
+
+/// The firefox version that last used the profile, from compatibility.ini
+pub fn last_version(profile: &Path) -> Option<String> {
+    let text = fs::read_to_string(profile.join("compatibility.ini")).ok()?;
+    // LastVersion=115.0.2_20230726201356/20230726201356
+    text.lines()
+        .find_map(|line| line.trim().strip_prefix("LastVersion="))
+        .and_then(|version| version.split('_').next())
+        .map(str::to_owned)
+}
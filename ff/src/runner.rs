@@ -1,21 +1,151 @@
 use std::convert::From;
-use std::io::ErrorKind;
+use std::io::{BufRead, BufReader, ErrorKind};
 use std::io::Error as IoError;
 use std::io::Result as IoResult;
 use std::path::{PathBuf, Path};
 use std::process;
-use std::process::{Command, Stdio, Child};
+use std::process::{Command, Stdio, Child, ExitStatus};
 use std::fs;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 extern crate marionette;
+use marionette::MarionetteConnection;
 use mktemp::Temp;
 
+use channels::{self, Channel, Version};
+use logs::{self, RotatingLog};
+use profiles;
+
+type SharedLog = Arc<Mutex<RotatingLog>>;
+
+/// How long to wait for firefox to report the port it picked, see
+/// `FirefoxRunner::tmp()`
+pub const ACTIVE_PORT_TIMEOUT: Duration = Duration::from_secs(30);
+
 use mozrunner::firefox_default_path;
 use mozprofile::profile::Profile;
 use mozprofile::preferences::Pref;
 
+/// Timeouts for the stages of `FirefoxRunner::shutdown()`
+#[derive(Clone, Copy, Debug)]
+pub struct ShutdownTimeouts {
+    /// Wait after asking firefox to quit through marionette
+    pub quit: Duration,
+    /// Wait after SIGTERM, before the process is killed
+    pub term: Duration,
+}
+
+impl Default for ShutdownTimeouts {
+    fn default() -> Self {
+        ShutdownTimeouts {
+            quit: Duration::from_secs(10),
+            term: Duration::from_secs(5),
+        }
+    }
+}
+
+/// How the firefox process is launched
+#[derive(Clone, Debug, Default)]
+pub struct LaunchOptions {
+    /// Run without a window, sets `MOZ_HEADLESS`
+    pub headless: bool,
+    /// Window size as `(width, height)`, used for headless and Xvfb
+    /// displays
+    pub window_size: Option<(u32, u32)>,
+    /// Run on a private Xvfb display, see `Xvfb`
+    pub xvfb: bool,
+    /// The X display, e.g. `:99`
+    pub display: Option<String>,
+    /// Additional firefox command line arguments
+    pub args: Vec<String>,
+    /// Additional environment variables
+    pub env: Vec<(String, String)>,
+    /// Write firefox output to this file instead of discarding it, see
+    /// `logs::RotatingLog`
+    pub log_file: Option<PathBuf>,
+    /// Used when the runner is dropped
+    pub shutdown: ShutdownTimeouts,
+    /// Remove the profile when the runner is dropped, temporary profiles
+    /// are always removed
+    pub remove_profile: bool,
+    /// Preferences added to the user.js file of the profile
+    pub prefs: Vec<(String, Pref)>,
+    /// Look up the executable of this channel when no path is given
+    pub channel: Option<Channel>,
+    /// Refuse to start older firefox versions
+    pub min_version: Option<Version>,
+    /// Enable the remote agent for WebDriver BiDi on this port
+    pub bidi_port: Option<u16>,
+    /// Start the devtools server on this port, see `marionette::rdp`
+    pub devtools_port: Option<u16>,
+}
+
+impl LaunchOptions {
+    /// The firefox executable: the given path, the executable of the
+    /// channel, or the default installation. Fails if it is older than
+    /// `min_version`, the version is returned.
+    fn firefox_bin<P: AsRef<Path>>(&self, path: Option<P>) -> IoResult<(PathBuf, Option<String>)> {
+        let bin = match (path, self.channel) {
+            (Some(path), _) => path.as_ref().to_owned(),
+            (None, Some(channel)) => channels::find_firefox(channel)?,
+            (None, None) => firefox_default_path().unwrap_or(PathBuf::from("firefox")),
+        };
+        let version = firefox_version(&bin);
+        if let Some(min) = &self.min_version {
+            channels::check_version(&bin, version.as_deref(), min)?;
+        }
+        Ok((bin, version))
+    }
+
+    /// Configure and spawn the firefox command, also returns the log the
+    /// output is captured in
+    fn spawn(&self, mut cmd: Command) -> IoResult<(Child, Option<SharedLog>)> {
+        if self.headless {
+            cmd.env("MOZ_HEADLESS", "1");
+            if let Some((width, height)) = self.window_size {
+                cmd.env("MOZ_HEADLESS_WIDTH", width.to_string())
+                    .env("MOZ_HEADLESS_HEIGHT", height.to_string());
+            }
+        }
+        if let Some(display) = &self.display {
+            cmd.env("DISPLAY", display);
+        }
+        if let Some(port) = self.bidi_port {
+            cmd.arg("--remote-debugging-port").arg(port.to_string());
+        }
+        if let Some(port) = self.devtools_port {
+            cmd.arg("--start-debugger-server").arg(port.to_string());
+        }
+        cmd.args(&self.args);
+        for (name, value) in &self.env {
+            cmd.env(name, value);
+        }
+
+        let log = match &self.log_file {
+            Some(path) => Some(RotatingLog::create(path, logs::MAX_LOG_SIZE, logs::KEEP_LOGS)?),
+            None => None,
+        };
+        match log {
+            Some(log) => {
+                let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+                let log = Arc::new(Mutex::new(log));
+                if let Some(stdout) = child.stdout.take() {
+                    logs::capture(stdout, log.clone());
+                }
+                if let Some(stderr) = child.stderr.take() {
+                    logs::capture(stderr, log.clone());
+                }
+                Ok((child, Some(log)))
+            }
+            None => Ok((cmd.stdout(Stdio::null()).stderr(Stdio::null()).spawn()?, None)),
+        }
+    }
+}
+
 #[cfg(unix)]
-fn spawn_firefox(firefox_bin: &Path, profile: &Path, marionette: bool) -> IoResult<Child> {
+fn spawn_firefox(firefox_bin: &Path, profile: &Path, marionette: bool, options: &LaunchOptions) -> IoResult<(Child, Option<SharedLog>)> {
     let mut cmd = Command::new(firefox_bin);
     if marionette {
         cmd.arg("--marionette");
@@ -23,13 +153,12 @@ fn spawn_firefox(firefox_bin: &Path, profile: &Path, marionette: bool) -> IoResu
 
     cmd.arg("--profile")
         .arg(profile)
-        .stdout(Stdio::null()).stderr(Stdio::null())
-        .env("MOZ_NO_REMOTE", "1").env("NO_EM_RESTART", "1")
-        .spawn()
+        .env("MOZ_NO_REMOTE", "1").env("NO_EM_RESTART", "1");
+    options.spawn(cmd)
 }
 
 #[cfg(windows)]
-fn spawn_firefox(firefox_bin: &Path, profile: &Path, marionette: bool) -> IoResult<Child> {
+fn spawn_firefox(firefox_bin: &Path, profile: &Path, marionette: bool, options: &LaunchOptions) -> IoResult<(Child, Option<SharedLog>)> {
     let mut cmd = Command::new(firefox_bin);
     if marionette {
         cmd.arg("-marionette");
@@ -37,15 +166,97 @@ fn spawn_firefox(firefox_bin: &Path, profile: &Path, marionette: bool) -> IoResu
 
     cmd.arg("-profile")
         .arg(profile)
-        .stdout(Stdio::null()).stderr(Stdio::null())
-        .env("MOZ_NO_REMOTE", "1").env("NO_EM_RESTART", "1")
-        .spawn()
+        .env("MOZ_NO_REMOTE", "1").env("NO_EM_RESTART", "1");
+    options.spawn(cmd)
+}
+
+/// A private X server for a browser, it is stopped on drop
+#[cfg(unix)]
+pub struct Xvfb {
+    process: process::Child,
+    display: String,
+}
+
+#[cfg(unix)]
+impl Xvfb {
+    /// Start Xvfb on a free display picked by the server
+    pub fn start(window_size: Option<(u32, u32)>) -> IoResult<Xvfb> {
+        let (width, height) = window_size.unwrap_or((1366, 768));
+
+        let mut process = Command::new("Xvfb")
+            .arg("-displayfd").arg("1")
+            .arg("-screen").arg("0").arg(format!("{}x{}x24", width, height))
+            .arg("-nolisten").arg("tcp")
+            .stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::null())
+            .spawn()
+            .map_err(|err| IoError::new(err.kind(), format!("Unable to run Xvfb: {}", err)))?;
+
+        // The display number is written once the server accepts clients
+        let stdout = process.stdout.take().ok_or(IoError::other("No Xvfb output"))?;
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut line = String::new();
+            let _ = tx.send(BufReader::new(stdout).read_line(&mut line).map(|_| line));
+        });
+        let num = match rx.recv_timeout(Duration::new(10, 0)) {
+            Ok(Ok(line)) if !line.trim().is_empty() => line.trim().to_owned(),
+            Ok(_) => {
+                let _ = process.kill();
+                return Err(IoError::other(format!("Xvfb exited with {}", process.wait()?)));
+            }
+            Err(_) => {
+                let _ = process.kill();
+                let _ = process.wait();
+                return Err(IoError::new(ErrorKind::TimedOut, "Timed out waiting for Xvfb"));
+            }
+        };
+        let display = format!(":{}", num);
+
+        info!("Started Xvfb on {}", display);
+        Ok(Xvfb { process, display })
+    }
+
+    /// The display name, e.g. `:99`
+    pub fn display(&self) -> &str { &self.display }
+}
+
+#[cfg(unix)]
+impl Drop for Xvfb {
+    fn drop(&mut self) {
+        debug!("Stopping Xvfb on {}", self.display);
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Add the marionette port and the preferences from `options` to user.js
+fn write_prefs(profile: &mut Profile, port: Option<u16>, options: &LaunchOptions) -> IoResult<()> {
+    let prefs = profile.user_prefs()
+        .map_err(|err| IoError::other(format!("{}", err)))?;
+    if let Some(port) = port {
+        prefs.insert("marionette.port", Pref::new(port as i64));
+        prefs.insert("marionette.defaultPrefs.port", Pref::new(port as i64));
+    }
+    if options.devtools_port.is_some() {
+        // Remote devtools clients are accepted without a prompt
+        prefs.insert("devtools.debugger.remote-enabled", Pref::new(true));
+        prefs.insert("devtools.chrome.enabled", Pref::new(true));
+        prefs.insert("devtools.debugger.prompt-connection", Pref::new(false));
+    }
+    for (name, pref) in &options.prefs {
+        prefs.insert(name, pref.clone());
+    }
+    prefs.write()
 }
 
 pub struct FirefoxRunner {
     pub process: process::Child,
     pub profile: Profile,
+    bin: PathBuf,
+    version: Option<String>,
     port: Option<u16>,
+    options: LaunchOptions,
+    log: Option<SharedLog>,
     profile_tmpdir: Option<Temp>,
     drop_browser: bool,
 }
@@ -54,15 +265,24 @@ impl FirefoxRunner {
     /// Run a new browser instance, listening on the given port.
     /// Creates a temporary profile for this instance.
     ///
+    /// With port 0 firefox picks a free port and writes it to the
+    /// `MarionetteActivePort` file in the profile, this waits for the file
+    /// and `port()` returns the port that was picked.
+    ///
     /// firefox_path: is an optional path to the firefox executable
     /// user_prefs: is an optional path to a user.js file to be copied into
     ///             the new profile
+    /// options: headless mode, display, extra arguments and environment
     pub fn tmp<P: AsRef<Path>>(port: Option<u16>,
                                firefox_path: Option<P>,
                                user_prefs: Option<P>,
-                               extraprefs: Option<Vec<P>>) -> IoResult<FirefoxRunner> {
+                               extraprefs: Option<Vec<P>>,
+                               options: LaunchOptions) -> IoResult<FirefoxRunner> {
+        let (bin, version) = options.firefox_bin(firefox_path)?;
 
-        let profile_tmpdir = Temp::new_dir()?;
+        let root = profiles::tmp_profile_root();
+        fs::create_dir_all(&root)?;
+        let profile_tmpdir = Temp::new_dir_in(&root)?;
 
         if let Some(src) = user_prefs {
             fs::copy(src, profile_tmpdir.as_ref().join("user.js"))?;
@@ -78,33 +298,25 @@ impl FirefoxRunner {
 
 
         let mut profile = Profile::new_from_path(profile_tmpdir.as_ref())?;
+        write_prefs(&mut profile, port, &options)?;
 
-        {
-            let prefs = profile.user_prefs()
-                .map_err(|err| IoError::new(ErrorKind::Other, format!("{}", err)))?;
-            if let Some(port) = port {
-                prefs.insert("marionette.port", Pref::new(port as i64));
-                prefs.insert("marionette.defaultPrefs.port", Pref::new(port as i64));
-            }
-
-            prefs.write()?;
-        }
-
-        let bin = firefox_path
-            .map(|p| p.as_ref().to_owned())
-            .or(firefox_default_path())
-            .unwrap_or(PathBuf::from("firefox"));
-        let child = spawn_firefox(&bin, &profile.path, port.is_some())?;
+        let (child, log) = spawn_firefox(&bin, &profile.path, port.is_some(), &options)?;
 
         info!("Started firefox: {:?}", port);
 
-        Ok(FirefoxRunner {
+        let mut runner = FirefoxRunner {
             process: child,
             profile: profile,
+            bin,
+            version,
             port: port,
+            options,
+            log,
             profile_tmpdir: Some(profile_tmpdir),
             drop_browser: true,
-        })
+        };
+        runner.resolve_port()?;
+        Ok(runner)
     }
 
     /// Starts a new firefox instance using the profile at the given path, if the
@@ -113,7 +325,9 @@ impl FirefoxRunner {
                                      port: Option<u16>,
                                      firefox_path: Option<P>,
                                      user_prefs: Option<P>,
-                                     extraprefs: Option<Vec<P>>) -> IoResult<FirefoxRunner> {
+                                     extraprefs: Option<Vec<P>>,
+                                     options: LaunchOptions) -> IoResult<FirefoxRunner> {
+        let (bin, version) = options.firefox_bin(firefox_path)?;
         fs::create_dir_all(&profile_path)?;
 
         if let Some(src) = user_prefs {
@@ -129,40 +343,174 @@ impl FirefoxRunner {
         }
 
         let mut profile = Profile::new_from_path(profile_path.as_ref())?;
-
-        if let Some(port) = port {
-            let prefs = profile.user_prefs()
-                .map_err(|err| IoError::new(ErrorKind::Other, format!("{}", err)))?;
-            prefs.insert("marionette.port", Pref::new(port as i64));
-            prefs.insert("marionette.defaultPrefs.port", Pref::new(port as i64));
-            prefs.write()?;
+        if port.is_some() || options.devtools_port.is_some() || !options.prefs.is_empty() {
+            write_prefs(&mut profile, port, &options)?;
         }
 
-        let bin = firefox_path
-            .map(|p| p.as_ref().to_owned())
-            .or(firefox_default_path())
-            .unwrap_or(PathBuf::from("firefox"));
-        let child = spawn_firefox(&bin, &profile.path, port.is_some())?;
+        // Left behind if firefox did not exit cleanly
+        let _ = fs::remove_file(profile.path.join(ACTIVE_PORT_FILE));
+        let (child, log) = spawn_firefox(&bin, &profile.path, port.is_some(), &options)?;
 
         info!("Started firefox: {:?}", port);
 
-        Ok(FirefoxRunner {
+        let mut runner = FirefoxRunner {
             process: child,
             profile: profile,
+            bin,
+            version,
             port: port,
+            options,
+            log,
             profile_tmpdir: None,
             drop_browser: true,
-        })
+        };
+        runner.resolve_port()?;
+        Ok(runner)
     }
 
     /// The marionette port the browser is listening on
     pub fn port(&self) -> Option<u16> { self.port }
 
+    /// The firefox executable used to start the browser
+    pub fn firefox_bin(&self) -> &Path { &self.bin }
+
+    /// The version reported by `firefox --version`
+    pub fn version(&self) -> Option<&str> { self.version.as_deref() }
+
+    /// The process id of the browser
+    pub fn pid(&self) -> u32 { self.process.id() }
+
+    /// The options the browser was launched with
+    pub fn options(&self) -> &LaunchOptions { &self.options }
+
+    /// True if the profile is removed when the runner is dropped
+    pub fn owns_profile(&self) -> bool {
+        self.profile_tmpdir.is_some() || self.options.remove_profile
+    }
+
+    /// The file firefox output is written to
+    pub fn log_file(&self) -> Option<PathBuf> {
+        self.log.as_ref().map(|log| log.lock().unwrap_or_else(|err| err.into_inner()).path().to_owned())
+    }
+
+    /// Move the output log to a new path, e.g. once the port is known
+    pub fn move_log<P: AsRef<Path>>(&self, path: P) -> IoResult<()> {
+        match &self.log {
+            Some(log) => log.lock().unwrap_or_else(|err| err.into_inner()).move_to(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Wait up to `timeout` for the browser to exit
+    pub fn wait_timeout(&mut self, timeout: Duration) -> IoResult<Option<ExitStatus>> {
+        let start = Instant::now();
+        loop {
+            if let Some(status) = self.process.try_wait()? {
+                return Ok(Some(status));
+            }
+            if start.elapsed() > timeout {
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Stop the browser without risking the profile data. Firefox is asked
+    /// to quit through `conn`, then sent SIGTERM and finally killed. Each step
+    /// waits for the process to exit up to its timeout.
+    pub fn shutdown(&mut self, conn: Option<MarionetteConnection>, timeouts: &ShutdownTimeouts) -> IoResult<ExitStatus> {
+        if let Some(status) = self.process.try_wait()? {
+            return Ok(status);
+        }
+
+        if let Some(conn) = conn {
+            match conn.quit() {
+                Ok(()) => if let Some(status) = self.wait_timeout(timeouts.quit)? {
+                    return Ok(status);
+                },
+                Err(err) => debug!("Unable to quit firefox through marionette: {}", err),
+            }
+        }
+
+        if self.terminate()? {
+            if let Some(status) = self.wait_timeout(timeouts.term)? {
+                return Ok(status);
+            }
+        }
+
+        info!("Killing firefox");
+        self.process.kill()?;
+        self.process.wait()
+    }
+
+    /// Send SIGTERM, false if not supported on this platform
+    #[cfg(unix)]
+    fn terminate(&mut self) -> IoResult<bool> {
+        debug!("Sending SIGTERM to firefox");
+        if unsafe { libc::kill(self.process.id() as libc::pid_t, libc::SIGTERM) } != 0 {
+            return Err(IoError::last_os_error());
+        }
+        Ok(true)
+    }
+
+    #[cfg(not(unix))]
+    fn terminate(&mut self) -> IoResult<bool> {
+        Ok(false)
+    }
+
+    /// For port 0 wait for firefox to report the port it is listening on
+    fn resolve_port(&mut self) -> IoResult<()> {
+        if self.port != Some(0) {
+            return Ok(());
+        }
+
+        let path = self.profile.path.join(ACTIVE_PORT_FILE);
+        let start = Instant::now();
+        loop {
+            // The file may be read while firefox is still writing it
+            if let Some(port) = fs::read_to_string(&path).ok().and_then(|s| s.trim().parse().ok()) {
+                info!("Firefox is listening on port {}", port);
+                self.port = Some(port);
+                return Ok(());
+            }
+            if let Some(status) = self.process.try_wait()? {
+                return Err(IoError::other(format!("Firefox exited ({})", status)));
+            }
+            if start.elapsed() > ACTIVE_PORT_TIMEOUT {
+                return Err(IoError::new(ErrorKind::TimedOut,
+                                        format!("Timed out waiting for {:?}, firefox may be too old for port 0", path)));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
     /// If true (the default) the browser process will be killed
     /// on Drop.
     pub fn kill_on_drop(&mut self, drop: bool) {
         self.drop_browser = drop;
     }
+
+    pub fn kills_on_drop(&self) -> bool {
+        self.drop_browser
+    }
+}
+
+/// The file firefox writes the marionette port to
+const ACTIVE_PORT_FILE: &str = "MarionetteActivePort";
+
+/// Get the version string of a firefox executable, e.g. `115.0.2`
+pub fn firefox_version(firefox_bin: &Path) -> Option<String> {
+    let output = Command::new(firefox_bin)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    // The output looks like "Mozilla Firefox 115.0.2esr"
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .last()
+        .map(str::to_owned)
 }
 
 impl Drop for FirefoxRunner {
@@ -171,6 +519,16 @@ impl Drop for FirefoxRunner {
             return;
         }
 
-        let _ = self.process.kill();
+        let timeouts = self.options.shutdown;
+        if let Err(err) = self.shutdown(None, &timeouts) {
+            warn!("Failed to stop firefox: {}", err);
+        }
+
+        // Temporary profiles are removed by `Temp`
+        if self.options.remove_profile && self.profile_tmpdir.is_none() {
+            if let Err(err) = fs::remove_dir_all(&self.profile.path) {
+                warn!("Failed to remove profile dir: {}", err);
+            }
+        }
     }
 }
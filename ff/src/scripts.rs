@@ -0,0 +1,207 @@
+//! Assemble scripts from several sources
+//!
+//! Helper libraries are concatenated in front of the main script before it is
+//! sent to the browser. A `ScriptBundle` remembers where each piece starts, so
+//! line numbers reported by the browser can be mapped back to the original
+//! file.
+
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use marionette::{JsonValue, MarionetteConnection, MarionetteError, Result, Script};
+
+/// A piece of the bundle, `first_line` is 1 based
+#[derive(Debug)]
+struct Part {
+    name: String,
+    first_line: usize,
+    lines: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct ScriptBundle {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl ScriptBundle {
+    pub fn new() -> Self {
+        ScriptBundle::default()
+    }
+
+    /// Append source code, `name` is used when reporting errors
+    pub fn push(&mut self, name: &str, src: &str) {
+        let first_line = self.source.lines().count() + 1;
+        let lines = src.lines().count().max(1);
+        self.source.push_str(src);
+        if !src.ends_with('\n') {
+            self.source.push('\n');
+        }
+        self.parts.push(Part {
+            name: name.to_owned(),
+            first_line,
+            lines,
+        });
+    }
+
+    /// Append the contents of a file
+    pub fn push_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let src = fs::read_to_string(&path)?;
+        self.push(&path.as_ref().to_string_lossy(), &src);
+        Ok(())
+    }
+
+    /// Append all `.js` files in a folder, sorted by file name. A missing
+    /// folder is not an error.
+    pub fn push_dir<P: AsRef<Path>>(&mut self, dir: P) -> io::Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        let mut files = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_file() && path.extension() == Some(OsStr::new("js")) {
+                files.push(path);
+            }
+        }
+        files.sort();
+
+        for file in files {
+            self.push_file(file)?;
+        }
+        Ok(())
+    }
+
+    /// The concatenated source code
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Map a line in the bundle to the name and line of the original source
+    pub fn locate(&self, line: usize) -> Option<(&str, usize)> {
+        self.parts.iter()
+            .find(|part| part.first_line <= line && line < part.first_line + part.lines)
+            .map(|part| (part.name.as_str(), line - part.first_line + 1))
+    }
+
+    /// Rewrite script errors to point at the original source files
+    ///
+    /// The first stack frame of the evaluated script is used, frames of
+    /// page scripts and of marionette itself are skipped.
+    pub fn map_error(&self, err: MarionetteError) -> MarionetteError {
+        self.map_frames(err, |file| file == SCRIPT_FILE)
+    }
+
+    /// Rewrite script errors using the first frame in a file accepted by
+    /// `is_bundle`
+    fn map_frames<F: Fn(&str) -> bool>(&self, err: MarionetteError, is_bundle: F) -> MarionetteError {
+        match err {
+            MarionetteError::Call(mut obj) => {
+                let location = obj.stacktrace.lines()
+                    .filter_map(frame_location)
+                    .filter(|&(file, _)| is_bundle(file))
+                    .filter_map(|(_, line)| self.locate(line))
+                    .next();
+                if let Some((name, line)) = location {
+                    obj.message = format!("{} ({}:{})", obj.message, name, line);
+                }
+                MarionetteError::Call(obj)
+            }
+            err => err,
+        }
+    }
+}
+
+/// The file name marionette gives to the scripts it evaluates
+const SCRIPT_FILE: &str = "dummy file";
+
+/// Extract the file and line number from a `function@FILE:LINE:COL` stack
+/// frame
+fn frame_location(frame: &str) -> Option<(&str, usize)> {
+    let (_, location) = frame.trim().split_once('@')?;
+    let mut fields = location.rsplitn(3, ':');
+    let _col = fields.next()?;
+    let line = fields.next()?.parse().ok()?;
+    Some((fields.next()?, line))
+}
+
+/// A helper library loaded into a named sandbox once per page load.
+/// Sandboxes are discarded with the page, so the library is loaded again
+/// after a navigation. The library is run by an indirect eval, so only its
+/// top level `function` and `var` declarations become globals of the
+/// sandbox, `let`, `const` and `class` declarations do not.
+pub struct SandboxLibrary {
+    sandbox: String,
+    bundle: ScriptBundle,
+    id: String,
+}
+
+/// Evaluates the library at the top level of the sandbox, unless this
+/// version is already loaded
+const LOAD_LIBRARY: &str = "
+let [source, id] = arguments;
+if (globalThis.__ffLibrary === id) {
+  return false;
+}
+(0, eval)(source);
+globalThis.__ffLibrary = id;
+return true;
+";
+
+impl SandboxLibrary {
+    pub fn new(sandbox: &str, bundle: ScriptBundle) -> Self {
+        let mut hasher = DefaultHasher::new();
+        bundle.source().hash(&mut hasher);
+        SandboxLibrary {
+            sandbox: sandbox.to_owned(),
+            bundle,
+            id: format!("{:016x}", hasher.finish()),
+        }
+    }
+
+    pub fn sandbox(&self) -> &str {
+        &self.sandbox
+    }
+
+    /// Load the library unless the current page already has it, returns
+    /// true if it was loaded
+    pub fn load(&self, conn: &mut MarionetteConnection) -> Result<bool> {
+        let mut script = Script::new(LOAD_LIBRARY);
+        script.arguments((self.bundle.source(), &self.id))?;
+        script.sandbox(&self.sandbox);
+        // The library is evaluated by eval(), in frames such as
+        // `@dummy file line 6 > eval:3:1`
+        let loaded = conn.execute_script(&script)
+            .map_err(|err| self.bundle.map_frames(err, |file| file.starts_with(SCRIPT_FILE) && file.ends_with("> eval")))?;
+        Ok(loaded == JsonValue::Bool(true))
+    }
+
+    /// Run a script in the sandbox of the library, after loading it. The
+    /// script can not ask for a new sandbox, that would drop the library.
+    pub fn execute_script(&self, conn: &mut MarionetteConnection, script: &mut Script) -> Result<JsonValue> {
+        self.load(conn)?;
+        script.sandbox(&self.sandbox);
+        script.new_sandbox(false);
+        conn.execute_script(script)
+    }
+}
+
+/// Folder with helper libraries loaded into every script, `$FF_LIB_DIR` or
+/// `~/.ff/lib`
+pub fn lib_dir() -> io::Result<PathBuf> {
+    if let Some(dir) = env::var_os("FF_LIB_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+
+    let mut path = super::ff_dir()?;
+    path.push("lib");
+    Ok(path)
+}
@@ -0,0 +1,239 @@
+//! Interactive shell, all commands run over a single connection
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use clap::ArgMatches;
+use marionette::{JsonValue, Result, Script};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use serde_json;
+use shell_words;
+
+use output::{Format, Output};
+use super::{invalid_input, parse_command, run_command, subcommands, Connector};
+
+/// Commands handled by the shell itself
+const SHELL_COMMANDS: &[&str] = &[":js", ":ff", ":help", ":exit"];
+
+const HELP: &str = "\
+Any ff command can be used, e.g. `title` or `go example.com`
+    :js [CODE]  evaluate CODE, or switch to javascript mode
+    :ff         switch back to ff command mode
+    :help       show this message
+    :exit       leave the shell (or Ctrl-D)";
+
+/// Completes subcommand names in the first word
+struct ShellHelper {
+    commands: Vec<String>,
+}
+
+impl ShellHelper {
+    fn new() -> ShellHelper {
+        let mut commands: Vec<String> = subcommands().iter()
+            .map(|cmd| cmd.get_name().to_owned())
+            .filter(|name| name != "shell")
+            .collect();
+        commands.extend(SHELL_COMMANDS.iter().map(|cmd| cmd.to_string()));
+        ShellHelper { commands }
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context) -> rustyline::Result<(usize, Vec<String>)> {
+        let prefix = &line[..pos];
+        if prefix.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let candidates = self.commands.iter()
+            .filter(|cmd| cmd.starts_with(prefix))
+            .cloned()
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+impl Highlighter for ShellHelper {}
+impl Validator for ShellHelper {}
+impl Helper for ShellHelper {}
+
+fn history_path() -> io::Result<PathBuf> {
+    let mut path = ::ff::ff_dir()?;
+    fs::create_dir_all(&path)?;
+    path.push("history");
+    Ok(path)
+}
+
+/// Split a line into words and parse it as an ff command
+fn parse_line<'a>(line: &str) -> std::result::Result<Option<(Vec<String>, ArgMatches<'a>)>, String> {
+    let words = shell_words::split(line).map_err(|err| err.to_string())?;
+    if words.is_empty() {
+        return Ok(None);
+    }
+    parse_command(words).map(Some)
+}
+
+/// Evaluate a javascript expression in the current frame
+fn eval_js(conn: &mut Connector, args: &ArgMatches, code: &str) -> Result<()> {
+    let mut script = Script::new("return eval(arguments[0]);");
+    script.arguments((code,))?;
+    let value = conn.connection(args)?.execute_script(&script)?;
+    if value != JsonValue::Null {
+        println!("{}", serde_json::to_string_pretty(&value)?);
+    }
+    Ok(())
+}
+
+/// Run one line of ff commands
+fn run_line(line: &str, conn: &mut Connector, format: Format) -> Result<()> {
+    let (argv, matches) = match parse_line(line) {
+        Ok(Some(res)) => res,
+        Ok(None) => return Ok(()),
+        Err(msg) => {
+            println!("{}", msg);
+            return Ok(());
+        }
+    };
+
+    let (name, args) = matches.subcommand();
+    if name == "shell" || name == "run" {
+        return Err(invalid_input("Unsupported command", name));
+    }
+
+    // --format on the command line takes precedence over the shell format
+    let format = matches.value_of("FORMAT")
+        .and_then(|f| f.parse().ok())
+        .unwrap_or(format);
+    let mut out = Output::new(format);
+    let res = run_command(name, args.unwrap(), &argv, conn, &mut out);
+    out.finish();
+    res
+}
+
+/// Run the interactive shell
+pub fn run(args: &ArgMatches, format: Format) -> Result<()> {
+    let mut conn = Connector::new();
+    conn.connection(args)?;
+
+    let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new()
+        .map_err(|err| invalid_input("Unable to start shell", err))?;
+    editor.set_helper(Some(ShellHelper::new()));
+    let history = history_path().ok();
+    if let Some(ref path) = history {
+        let _ = editor.load_history(path);
+    }
+
+    let mut js_mode = false;
+    loop {
+        let prompt = if js_mode { "js> " } else { "ff> " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(invalid_input("Unable to read input", err)),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+
+        let res = match line.split_whitespace().next() {
+            Some(":exit") => break,
+            Some(":help") => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            Some(":ff") => {
+                js_mode = false;
+                Ok(())
+            }
+            Some(":js") => {
+                let code = line[3..].trim();
+                if code.is_empty() {
+                    js_mode = true;
+                    Ok(())
+                } else {
+                    eval_js(&mut conn, args, code)
+                }
+            }
+            _ if js_mode => eval_js(&mut conn, args, line),
+            _ => run_line(line, &mut conn, format),
+        };
+
+        if let Err(err) = res {
+            eprintln!("{}", err);
+        }
+    }
+
+    if let Some(ref path) = history {
+        let _ = editor.save_history(path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The words of a line, without the program name
+    fn words(line: &str) -> Vec<String> {
+        parse_line(line).unwrap().unwrap().0.split_off(1)
+    }
+
+    #[test]
+    fn parse_words() {
+        assert_eq!(words("go example.com"), vec!["go", "example.com"]);
+        assert_eq!(words("  exec  'return 1 + 1;'  "), vec!["exec", "return 1 + 1;"]);
+        assert_eq!(words(r#"exec "say \"hi\"" a\ b"#), vec!["exec", "say \"hi\"", "a b"]);
+        assert_eq!(words("exec 'it'\\''s'"), vec!["exec", "it's"]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse_line("").unwrap().is_none());
+        assert!(parse_line("   ").unwrap().is_none());
+        assert!(parse_line("exec 'unterminated").is_err());
+        assert!(parse_line("no-such-command").is_err());
+    }
+
+    #[test]
+    fn parse_matches() {
+        let (_, matches) = parse_line("go example.com").unwrap().unwrap();
+        let (name, args) = matches.subcommand();
+        assert_eq!(name, "go");
+        assert_eq!(args.unwrap().value_of("URL"), Some("example.com"));
+    }
+
+    fn complete(line: &str) -> (usize, Vec<String>) {
+        let history = DefaultHistory::new();
+        ShellHelper::new().complete(line, line.len(), &Context::new(&history)).unwrap()
+    }
+
+    #[test]
+    fn completion() {
+        let (start, candidates) = complete("ti");
+        assert_eq!(start, 0);
+        assert_eq!(candidates, vec!["title"]);
+
+        let (_, candidates) = complete(":e");
+        assert_eq!(candidates, vec![":exit"]);
+        let (_, candidates) = complete("");
+        assert!(candidates.contains(&"go".to_owned()));
+        assert!(!candidates.contains(&"shell".to_owned()));
+
+        // Only the command name is completed
+        assert_eq!(complete("go ti"), (5, Vec::new()));
+    }
+}
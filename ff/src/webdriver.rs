@@ -0,0 +1,663 @@
+//! A W3C WebDriver HTTP frontend, for clients such as the Selenium
+//! bindings
+//!
+//! Requests are translated to the `WebDriver:` marionette commands, which
+//! take the same parameters as the HTTP endpoints. Like geckodriver there is
+//! at most one session at a time. Each session either launches a browser,
+//! configured by a `BrowserBuilder` and the `moz:firefoxOptions`
+//! capability, or uses a running ff instance.
+//!
+//! The requested capabilities are matched as in the specification, the
+//! first `firstMatch` entry that can be met is used. `browserVersion`
+//! takes an optional comparison, e.g. `>=115`, and is checked once the
+//! browser runs.
+//!
+//! ```no_run
+//! use std::net::TcpListener;
+//!
+//! let server = ff::webdriver::WebDriverServer::new(ff::webdriver::Target::Launch(Box::new(ff::BrowserBuilder::new())));
+//! server.serve(TcpListener::bind("127.0.0.1:4444").unwrap()).unwrap();
+//! ```
+
+use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use marionette::{Context, JsonValue, MarionetteConnection, MarionetteError, Script};
+use mozprofile::preferences::PrefValue;
+use serde_json::{self, Map};
+
+use super::{connect, Browser, BrowserBuilder};
+use channels::Version;
+
+/// Where sessions get their browser from
+pub enum Target {
+    /// Launch a browser for each session, it is stopped when the session
+    /// ends
+    Launch(Box<BrowserBuilder>),
+    /// Use the ff instance on this marionette port, it keeps running when
+    /// the session ends
+    Instance(u16),
+}
+
+/// A WebDriver error, sent as `{"value": {"error": ..}}`
+#[derive(Debug)]
+struct WebDriverError {
+    error: String,
+    message: String,
+    stacktrace: String,
+}
+
+impl WebDriverError {
+    fn new(error: &str, message: &str) -> Self {
+        WebDriverError { error: error.to_owned(), message: message.to_owned(), stacktrace: String::new() }
+    }
+
+    /// The HTTP status of the error code, see the WebDriver specification
+    fn status(&self) -> u16 {
+        match self.error.as_str() {
+            "element click intercepted" | "element not interactable" | "insecure certificate" | "invalid argument"
+                | "invalid cookie domain" | "invalid element state" | "invalid selector" => 400,
+            "invalid session id" | "no such alert" | "no such cookie" | "no such element" | "no such frame"
+                | "no such window" | "no such shadow root" | "stale element reference" | "detached shadow root"
+                | "unknown command" => 404,
+            "unknown method" => 405,
+            _ => 500,
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        serde_json::json!({"error": self.error, "message": self.message, "stacktrace": self.stacktrace})
+    }
+}
+
+impl From<MarionetteError> for WebDriverError {
+    fn from(err: MarionetteError) -> Self {
+        match err {
+            MarionetteError::Call(err) => WebDriverError { error: err.error, message: err.message, stacktrace: err.stacktrace },
+            err => WebDriverError::new("unknown error", &err.to_string()),
+        }
+    }
+}
+
+type WebDriverResult = Result<JsonValue, WebDriverError>;
+
+type Capabilities = Map<String, JsonValue>;
+
+struct Session {
+    id: String,
+    conn: MarionetteConnection,
+    /// The browser launched for the session
+    browser: Option<Browser>,
+    /// Certificate errors are ignored until the session ends
+    insecure_certs: bool,
+}
+
+impl Session {
+    fn end(self) {
+        let Session { id, mut conn, browser, insecure_certs } = self;
+        info!("Ending session {}", id);
+        if insecure_certs && browser.is_none() {
+            if let Err(err) = accept_insecure_certs(&mut conn, false) {
+                warn!("Unable to restore certificate checks: {}", err);
+            }
+        }
+        stop_browser(conn, browser);
+    }
+}
+
+fn stop_browser(conn: MarionetteConnection, browser: Option<Browser>) {
+    if let Some(mut browser) = browser {
+        let timeouts = browser.runner.options().shutdown;
+        if let Err(err) = browser.runner.shutdown(Some(conn), &timeouts) {
+            warn!("Unable to stop browser: {}", err);
+        }
+    }
+}
+
+/// Serves the WebDriver endpoints, see the module documentation
+pub struct WebDriverServer {
+    target: Target,
+    session: Mutex<Option<Session>>,
+}
+
+impl WebDriverServer {
+    pub fn new(target: Target) -> Self {
+        WebDriverServer { target, session: Mutex::new(None) }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<Session>> {
+        self.session.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Handle connections until the listener fails, each connection is
+    /// served by its own thread
+    pub fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        thread::scope(|scope| {
+            for stream in listener.incoming() {
+                let stream = stream?;
+                scope.spawn(move || {
+                    if let Err(err) = self.serve_connection(stream) {
+                        debug!("WebDriver connection failed: {}", err);
+                    }
+                });
+            }
+            Ok(())
+        })
+    }
+
+    /// End the current session, if any
+    pub fn end_session(&self) {
+        if let Some(session) = self.lock().take() {
+            session.end();
+        }
+    }
+
+    fn serve_connection(&self, stream: TcpStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        loop {
+            let request = match read_request(&mut reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                // The rest of the stream can not be parsed, answer and close
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                    let err = WebDriverError::new("invalid argument", &err.to_string());
+                    write_response(&mut writer, err.status(), &serde_json::json!({ "value": err.to_json() }))?;
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
+            debug!("{} {} {}", request.method, request.path, request.body);
+            let (status, body) = match self.handle(&request.method, &request.path, &request.body) {
+                Ok(value) => (200, serde_json::json!({ "value": value })),
+                Err(err) => (err.status(), serde_json::json!({ "value": err.to_json() })),
+            };
+            write_response(&mut writer, status, &body)?;
+            if request.close {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&self, method: &str, path: &str, body: &str) -> WebDriverResult {
+        let params = match body.trim() {
+            "" => JsonValue::Object(Default::default()),
+            body => serde_json::from_str(body)
+                .map_err(|err| WebDriverError::new("invalid argument", &format!("Invalid JSON body: {}", err)))?,
+        };
+        if !params.is_object() {
+            return Err(WebDriverError::new("invalid argument", "The body must be a JSON object"));
+        }
+
+        let path = path.split('?').next().unwrap_or("");
+        let path = path.strip_prefix("/wd/hub").unwrap_or(path);
+        let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+        match (method, segments.as_slice()) {
+            ("GET", ["status"]) => {
+                let ready = self.lock().is_none();
+                let message = if ready { "ready" } else { "a session is already running" };
+                Ok(serde_json::json!({"ready": ready, "message": message}))
+            }
+            ("POST", ["session"]) => self.new_session(&params),
+            ("DELETE", ["session", id]) => {
+                let mut session = self.lock();
+                match session.take() {
+                    Some(current) if current.id == *id => {
+                        current.end();
+                        Ok(JsonValue::Null)
+                    }
+                    other => {
+                        *session = other;
+                        Err(invalid_session(id))
+                    }
+                }
+            }
+            (method, ["session", id, rest @ ..]) => self.session_command(id, method, rest, params),
+            _ => Err(WebDriverError::new("unknown command", &format!("{} {}", method, path))),
+        }
+    }
+
+    fn new_session(&self, params: &JsonValue) -> WebDriverResult {
+        let mut session = self.lock();
+        if session.is_some() {
+            return Err(WebDriverError::new("session not created", "Maximum number of active sessions"));
+        }
+
+        let candidates = process_capabilities(params)?;
+        let matching: Vec<&Capabilities> = candidates.iter()
+            .filter(|caps| unmet_capability(caps, None).is_none())
+            .collect();
+        let first = match matching.first() {
+            Some(caps) => *caps,
+            None => {
+                let reason = candidates.iter().find_map(|caps| unmet_capability(caps, None)).unwrap_or_default();
+                return Err(WebDriverError::new("session not created", &format!("No matching capabilities: {}", reason)));
+            }
+        };
+
+        let not_created = |err: MarionetteError| WebDriverError::new("session not created", &err.to_string());
+        let (mut conn, browser, port) = match self.target {
+            Target::Instance(port) => (connect(port).map_err(not_created)?, None, port),
+            Target::Launch(ref builder) => {
+                // Each session gets its own port and temporary profile
+                let builder = firefox_options(builder.pool_member(), first)?;
+                let (browser, conn) = builder.launch().map_err(not_created)?;
+                let port = browser.runner.port().unwrap_or(0);
+                (conn, Some(browser), port)
+            }
+        };
+
+        // The browser version is only known now
+        let version = conn.browser_version().unwrap_or("").to_owned();
+        let caps = match matching.iter().find(|caps| unmet_capability(caps, Some(&version)).is_none()) {
+            Some(caps) => *caps,
+            None => {
+                let reason = unmet_capability(first, Some(&version)).unwrap_or_default();
+                stop_browser(conn, browser);
+                return Err(WebDriverError::new("session not created", &format!("No matching capabilities: {}", reason)));
+            }
+        };
+        let insecure_certs = caps.get("acceptInsecureCerts") == Some(&JsonValue::Bool(true));
+        let setup = |conn: &mut MarionetteConnection| {
+            if let Some(timeouts) = caps.get("timeouts") {
+                conn.call_raw("WebDriver:SetTimeouts", timeouts.clone())?;
+            }
+            if insecure_certs {
+                accept_insecure_certs(conn, true)?;
+            }
+            Ok(())
+        };
+        if let Err(err) = setup(&mut conn) {
+            stop_browser(conn, browser);
+            return Err(not_created(err));
+        }
+
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos());
+        let id = format!("{:x}-{:x}", std::process::id(), nanos);
+        let capabilities = serde_json::json!({
+            "browserName": "firefox",
+            "browserVersion": version,
+            "platformName": platform_name(),
+            "acceptInsecureCerts": insecure_certs,
+            "pageLoadStrategy": "normal",
+            "setWindowRect": true,
+            "strictFileInteractability": false,
+            "unhandledPromptBehavior": "dismiss and notify",
+            "ff:port": port,
+        });
+        info!("Started session {} on port {}", id, port);
+        *session = Some(Session { id: id.clone(), conn, browser, insecure_certs });
+        Ok(serde_json::json!({"sessionId": id, "capabilities": capabilities}))
+    }
+
+    fn session_command(&self, id: &str, method: &str, path: &[&str], mut params: JsonValue) -> WebDriverResult {
+        let mut session = self.lock();
+        let current = match session.as_mut() {
+            Some(current) if current.id == id => current,
+            _ => return Err(invalid_session(id)),
+        };
+
+        let (name, url_params) = route(method, path)
+            .ok_or_else(|| WebDriverError::new("unknown command", &format!("{} {}", method, path.join("/"))))?;
+        for (key, value) in url_params {
+            params[key] = JsonValue::from(value);
+        }
+
+        let result = current.conn.call_raw(name, params);
+        match result {
+            Ok(value) => match (method, path) {
+                ("GET", ["cookie", name]) => find_cookie(unwrap_value(value), name),
+                _ => Ok(unwrap_value(value)),
+            },
+            Err(err) => {
+                if err.is_fatal() {
+                    warn!("Ending session {}: {}", id, err);
+                    if let Some(session) = session.take() {
+                        session.end();
+                    }
+                }
+                Err(err.into())
+            }
+        }
+    }
+}
+
+fn invalid_session(id: &str) -> WebDriverError {
+    WebDriverError::new("invalid session id", &format!("No active session with id {}", id))
+}
+
+/// The marionette command for a path below `/session/{id}`, and the
+/// parameters taken from the path
+fn route<'a>(method: &str, path: &[&'a str]) -> Option<(&'static str, Vec<(&'static str, &'a str)>)> {
+    let command = match (method, path) {
+        ("GET", ["timeouts"]) => "WebDriver:GetTimeouts",
+        ("POST", ["timeouts"]) => "WebDriver:SetTimeouts",
+        ("POST", ["url"]) => "WebDriver:Navigate",
+        ("GET", ["url"]) => "WebDriver:GetCurrentURL",
+        ("POST", ["back"]) => "WebDriver:Back",
+        ("POST", ["forward"]) => "WebDriver:Forward",
+        ("POST", ["refresh"]) => "WebDriver:Refresh",
+        ("GET", ["title"]) => "WebDriver:GetTitle",
+        ("GET", ["window"]) => "WebDriver:GetWindowHandle",
+        ("DELETE", ["window"]) => "WebDriver:CloseWindow",
+        ("POST", ["window"]) => "WebDriver:SwitchToWindow",
+        ("GET", ["window", "handles"]) => "WebDriver:GetWindowHandles",
+        ("POST", ["window", "new"]) => "WebDriver:NewWindow",
+        ("GET", ["window", "rect"]) => "WebDriver:GetWindowRect",
+        ("POST", ["window", "rect"]) => "WebDriver:SetWindowRect",
+        ("POST", ["window", "maximize"]) => "WebDriver:MaximizeWindow",
+        ("POST", ["window", "minimize"]) => "WebDriver:MinimizeWindow",
+        ("POST", ["window", "fullscreen"]) => "WebDriver:FullscreenWindow",
+        ("POST", ["frame"]) => "WebDriver:SwitchToFrame",
+        ("POST", ["frame", "parent"]) => "WebDriver:SwitchToParentFrame",
+        ("GET", ["element", "active"]) => "WebDriver:GetActiveElement",
+        ("POST", ["element"]) => "WebDriver:FindElement",
+        ("POST", ["elements"]) => "WebDriver:FindElements",
+        ("POST", ["element", element, "element"]) => return Some(("WebDriver:FindElement", vec![("element", element)])),
+        ("POST", ["element", element, "elements"]) => return Some(("WebDriver:FindElements", vec![("element", element)])),
+        ("GET", ["element", id, "attribute", name]) => {
+            return Some(("WebDriver:GetElementAttribute", vec![("id", id), ("name", name)]))
+        }
+        ("GET", ["element", id, "property", name]) => {
+            return Some(("WebDriver:GetElementProperty", vec![("id", id), ("name", name)]))
+        }
+        ("GET", ["element", id, "css", name]) => {
+            return Some(("WebDriver:GetElementCSSValue", vec![("id", id), ("propertyName", name)]))
+        }
+        ("GET", ["element", id, item]) => {
+            let command = match *item {
+                "selected" => "WebDriver:IsElementSelected",
+                "enabled" => "WebDriver:IsElementEnabled",
+                "displayed" => "WebDriver:IsElementDisplayed",
+                "text" => "WebDriver:GetElementText",
+                "name" => "WebDriver:GetElementTagName",
+                "rect" => "WebDriver:GetElementRect",
+                "screenshot" => "WebDriver:TakeScreenshot",
+                _ => return None,
+            };
+            return Some((command, vec![("id", id)]));
+        }
+        ("POST", ["element", id, action]) => {
+            let command = match *action {
+                "click" => "WebDriver:ElementClick",
+                "clear" => "WebDriver:ElementClear",
+                "value" => "WebDriver:ElementSendKeys",
+                _ => return None,
+            };
+            return Some((command, vec![("id", id)]));
+        }
+        ("GET", ["source"]) => "WebDriver:GetPageSource",
+        ("POST", ["execute", "sync"]) => "WebDriver:ExecuteScript",
+        ("POST", ["execute", "async"]) => "WebDriver:ExecuteAsyncScript",
+        ("GET", ["cookie"]) | ("GET", ["cookie", _]) => "WebDriver:GetCookies",
+        ("POST", ["cookie"]) => "WebDriver:AddCookie",
+        ("DELETE", ["cookie"]) => "WebDriver:DeleteAllCookies",
+        ("DELETE", ["cookie", name]) => return Some(("WebDriver:DeleteCookie", vec![("name", name)])),
+        ("POST", ["actions"]) => "WebDriver:PerformActions",
+        ("DELETE", ["actions"]) => "WebDriver:ReleaseActions",
+        ("POST", ["alert", "dismiss"]) => "WebDriver:DismissAlert",
+        ("POST", ["alert", "accept"]) => "WebDriver:AcceptAlert",
+        ("GET", ["alert", "text"]) => "WebDriver:GetAlertText",
+        ("POST", ["alert", "text"]) => "WebDriver:SendAlertText",
+        ("GET", ["screenshot"]) => "WebDriver:TakeScreenshot",
+        ("POST", ["print"]) => "WebDriver:Print",
+        _ => return None,
+    };
+    Some((command, Vec::new()))
+}
+
+/// Marionette wraps some results in `{"value": ..}` and returns `{}` for
+/// commands without a result, WebDriver does neither
+fn unwrap_value(mut value: JsonValue) -> JsonValue {
+    match value.as_object_mut() {
+        Some(map) if map.len() == 1 && map.contains_key("value") => map.remove("value").unwrap_or(JsonValue::Null),
+        Some(map) if map.is_empty() => JsonValue::Null,
+        _ => value,
+    }
+}
+
+fn find_cookie(cookies: JsonValue, name: &str) -> WebDriverResult {
+    cookies.as_array()
+        .and_then(|cookies| cookies.iter().find(|cookie| cookie["name"] == name))
+        .cloned()
+        .ok_or_else(|| WebDriverError::new("no such cookie", &format!("No cookie named {}", name)))
+}
+
+/// Merge `alwaysMatch` with each `firstMatch` entry. The legacy
+/// `desiredCapabilities` are used when `capabilities` is missing.
+fn process_capabilities(params: &JsonValue) -> Result<Vec<Capabilities>, WebDriverError> {
+    let invalid = |msg: &str| WebDriverError::new("invalid argument", msg);
+    let object = |value: &JsonValue, name: &str| match *value {
+        JsonValue::Null => Ok(Capabilities::new()),
+        JsonValue::Object(ref map) => Ok(map.clone()),
+        _ => Err(invalid(&format!("{} must be an object", name))),
+    };
+
+    let (always, first) = match params.get("capabilities") {
+        Some(capabilities) => {
+            object(capabilities, "capabilities")?;
+            let first = match capabilities["firstMatch"] {
+                JsonValue::Null => vec![Capabilities::new()],
+                JsonValue::Array(ref entries) if !entries.is_empty() => entries.iter()
+                    .map(|entry| object(entry, "firstMatch entries"))
+                    .collect::<Result<_, _>>()?,
+                _ => return Err(invalid("firstMatch must be a non empty array")),
+            };
+            (object(&capabilities["alwaysMatch"], "alwaysMatch")?, first)
+        }
+        None => (object(&params["desiredCapabilities"], "desiredCapabilities")?, vec![Capabilities::new()]),
+    };
+    validate_capabilities(&always)?;
+
+    let mut merged = Vec::new();
+    for entry in first {
+        validate_capabilities(&entry)?;
+        let mut caps = always.clone();
+        for (name, value) in entry {
+            if caps.contains_key(&name) {
+                return Err(invalid(&format!("{} is in both alwaysMatch and firstMatch", name)));
+            }
+            caps.insert(name, value);
+        }
+        merged.push(caps);
+    }
+    Ok(merged)
+}
+
+/// Check the types of the standard capabilities, unknown ones are ignored
+fn validate_capabilities(caps: &Capabilities) -> Result<(), WebDriverError> {
+    for (name, value) in caps {
+        let valid = match name.as_str() {
+            "browserName" | "browserVersion" | "platformName" | "pageLoadStrategy" | "unhandledPromptBehavior" => {
+                value.is_string()
+            }
+            "acceptInsecureCerts" | "setWindowRect" | "strictFileInteractability" | "webSocketUrl" => value.is_boolean(),
+            "proxy" | "timeouts" | "moz:firefoxOptions" => value.is_object(),
+            _ => true,
+        };
+        if !valid && !value.is_null() {
+            return Err(WebDriverError::new("invalid argument", &format!("Invalid value for {}: {}", name, value)));
+        }
+    }
+    Ok(())
+}
+
+/// Why the capabilities can not be met, `version` is the browser version
+/// once it is known
+fn unmet_capability(caps: &Capabilities, version: Option<&str>) -> Option<String> {
+    for (name, value) in caps {
+        let met = match (name.as_str(), value) {
+            (_, &JsonValue::Null) => true,
+            ("browserName", value) => value == "firefox",
+            ("platformName", value) => value == platform_name(),
+            ("browserVersion", value) => match version {
+                Some(version) => value.as_str().is_some_and(|wanted| version_matches(wanted, version)),
+                None => true,
+            },
+            ("pageLoadStrategy", value) => value == "normal",
+            ("unhandledPromptBehavior", value) => value == "dismiss and notify",
+            ("setWindowRect", value) => value == true,
+            ("strictFileInteractability", value) | ("webSocketUrl", value) => value == false,
+            ("proxy", value) => value.as_object().is_some_and(|proxy| {
+                proxy.is_empty() || (proxy.len() == 1 && proxy.get("proxyType").is_some_and(|kind| kind == "system"))
+            }),
+            _ => true,
+        };
+        if !met {
+            return Some(match (name.as_str(), version) {
+                ("browserVersion", Some(version)) => format!("browserVersion {} is not met by {}", value, version),
+                _ => format!("{} {} is not supported", name, value),
+            });
+        }
+    }
+    None
+}
+
+/// Compare with an optional `>=`, `<=`, `>`, `<` or `=`, no comparison
+/// means equal
+fn version_matches(wanted: &str, version: &str) -> bool {
+    let wanted = wanted.trim();
+    let (op, wanted) = [">=", "<=", ">", "<", "="].iter()
+        .find_map(|op| Some((*op, wanted.strip_prefix(op)?)))
+        .unwrap_or(("=", wanted));
+    match (wanted.trim().parse::<Version>(), version.parse::<Version>()) {
+        (Ok(wanted), Ok(version)) => match op {
+            ">=" => version >= wanted,
+            "<=" => version <= wanted,
+            ">" => version > wanted,
+            "<" => version < wanted,
+            _ => version == wanted,
+        },
+        _ => false,
+    }
+}
+
+/// The `platformName` of this system, as named by the specification
+fn platform_name() -> &'static str {
+    match env::consts::OS {
+        "macos" => "mac",
+        os => os,
+    }
+}
+
+/// Let the browser load pages with invalid certificates, like marionette
+/// does for `acceptInsecureCerts`
+fn accept_insecure_certs(conn: &mut MarionetteConnection, accept: bool) -> marionette::Result<()> {
+    let mut script = Script::new(r#"
+    let [accept] = arguments;
+    Components.classes["@mozilla.org/security/certoverride;1"]
+        .getService(Components.interfaces.nsICertOverrideService)
+        .setDisableAllSecurityChecksAndLetAttackersInterceptMyData(accept);
+    "#);
+    script.arguments((accept,))?;
+    script.sandbox("system");
+    conn.with_context(Context::Chrome, |conn| {
+        conn.execute_script(&script)?;
+        Ok(())
+    })
+}
+
+/// Apply the `moz:firefoxOptions` capability: `binary`, `args`, `prefs` and
+/// `env` are supported
+fn firefox_options(mut builder: BrowserBuilder, caps: &Capabilities) -> Result<BrowserBuilder, WebDriverError> {
+    let options = caps.get("moz:firefoxOptions").cloned().unwrap_or(JsonValue::Null);
+    let invalid = |msg: &str| WebDriverError::new("invalid argument", msg);
+
+    if let Some(binary) = options.get("binary") {
+        builder = builder.firefox_bin(binary.as_str().ok_or_else(|| invalid("binary must be a string"))?);
+    }
+    for arg in options["args"].as_array().into_iter().flatten() {
+        builder = builder.arg(arg.as_str().ok_or_else(|| invalid("args must be strings"))?);
+    }
+    for (name, value) in options["prefs"].as_object().into_iter().flatten() {
+        let value = match *value {
+            JsonValue::Bool(value) => PrefValue::from(value),
+            JsonValue::String(ref value) => PrefValue::from(value.clone()),
+            JsonValue::Number(ref value) => PrefValue::from(value.as_i64().ok_or_else(|| invalid("Invalid integer pref"))?),
+            _ => return Err(invalid(&format!("Invalid value for pref {}", name))),
+        };
+        builder = builder.pref(name, value);
+    }
+    for (name, value) in options["env"].as_object().into_iter().flatten() {
+        builder = builder.env(name, value.as_str().ok_or_else(|| invalid("env values must be strings"))?);
+    }
+    if options.get("profile").is_some() {
+        warn!("The profile option of moz:firefoxOptions is not supported");
+    }
+    Ok(builder)
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: String,
+    /// The client closes the connection after the response
+    close: bool,
+}
+
+/// Largest request body accepted, addons are sent base64 encoded
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Read an HTTP request, `None` when the client closed the connection
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (method, path, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) => (method.to_owned(), path.to_owned(), version),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid HTTP request: {}", line.trim()))),
+    };
+
+    let mut length = 0;
+    let mut close = version == "HTTP/1.0";
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => {
+                    length = value.parse()
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid Content-Length"))?;
+                }
+                "connection" => close = value.eq_ignore_ascii_case("close"),
+                _ => (),
+            }
+        }
+    }
+
+    if length > MAX_BODY_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("Request body of {} bytes is larger than {} bytes", length, MAX_BODY_SIZE)));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(Request { method, path, body: String::from_utf8_lossy(&body).into_owned(), close }))
+}
+
+fn write_response<W: Write>(writer: &mut W, status: u16, body: &JsonValue) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    };
+    let body = body.to_string();
+    write!(writer, "HTTP/1.1 {} {}\r\nContent-Type: application/json; charset=utf-8\r\nCache-Control: no-cache\r\n\
+                    Content-Length: {}\r\n\r\n{}", status, reason, body.len(), body)?;
+    writer.flush()
+}
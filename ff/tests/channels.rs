@@ -0,0 +1,78 @@
+extern crate ff;
+extern crate mktemp;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use ff::channels::{check_version, Channel, Version};
+
+fn version(s: &str) -> Version {
+    s.parse().unwrap()
+}
+
+#[test]
+fn versions() {
+    let esr = version("115.0.2esr");
+    assert_eq!((esr.major, esr.minor, esr.patch, esr.pre.as_str()), (115, 0, 2, "esr"));
+    assert_eq!(esr.to_string(), "115.0.2esr");
+    assert_eq!(version("130.0a1").pre, "a1");
+    assert!("firefox".parse::<Version>().is_err());
+
+    assert!(version("115.0.2esr") > version("115.0"));
+    assert!(version("99.0") < version("115.0"));
+    assert!(version("128.0b3") == version("128.0"));
+
+    assert!(Channel::Release.matches(&version("128.0")));
+    assert!(Channel::Esr.matches(&version("128.3.1esr")));
+    assert!(Channel::Beta.matches(&version("129.0b9")));
+    assert!(Channel::DevEdition.matches(&version("129.0b9")));
+    assert!(Channel::Nightly.matches(&version("130.0a1")));
+    assert!(!Channel::Release.matches(&version("128.3.1esr")));
+    assert_eq!("devedition".parse::<Channel>(), Ok(Channel::DevEdition));
+
+    let bin = Path::new("/usr/bin/firefox");
+    assert!(check_version(bin, Some("52.9.0esr"), &version("60.0")).is_err());
+    assert!(check_version(bin, Some("60.0"), &version("60.0")).is_ok());
+    // Unknown versions are accepted
+    assert!(check_version(bin, None, &version("60.0")).is_ok());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn find_firefox() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = mktemp::Temp::new_dir().unwrap();
+    let fake = |name: &str, version: &str| {
+        let path = dir.as_ref().join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, format!("#!/bin/sh\necho Mozilla Firefox {}\n", version)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    };
+    fake("firefox", "128.3.1esr");
+    fake("firefox-nightly", "130.0a1");
+    env::set_var("PATH", dir.as_ref());
+
+    assert_eq!(ff::channels::find_firefox(Channel::Esr).unwrap(), dir.as_ref().join("firefox"));
+    assert_eq!(ff::channels::find_firefox(Channel::Nightly).unwrap(), dir.as_ref().join("firefox-nightly"));
+    let err = ff::channels::find_firefox(Channel::Beta).unwrap_err();
+    assert!(err.to_string().contains("128.3.1esr"), "{}", err);
+
+    // Beta and developer edition report the same versions
+    fake("firefox-developer-edition", "129.0b9");
+    assert_eq!(ff::channels::find_firefox(Channel::DevEdition).unwrap(), dir.as_ref().join("firefox-developer-edition"));
+    for (install, channel) in [("dev", "aurora"), ("beta", "beta")] {
+        fake(&format!("{}/firefox", install), "129.0b9");
+        let prefs = dir.as_ref().join(install).join("defaults/pref");
+        fs::create_dir_all(&prefs).unwrap();
+        fs::write(prefs.join("channel-prefs.js"), format!("pref(\"app.update.channel\", \"{}\");\n", channel)).unwrap();
+    }
+    env::set_var("PATH", dir.as_ref().join("dev"));
+    assert!(ff::channels::find_firefox(Channel::Beta).is_err());
+    assert_eq!(ff::channels::find_firefox(Channel::DevEdition).unwrap(), dir.as_ref().join("dev/firefox"));
+    env::set_var("PATH", dir.as_ref().join("beta"));
+    assert_eq!(ff::channels::find_firefox(Channel::Beta).unwrap(), dir.as_ref().join("beta/firefox"));
+    assert!(ff::channels::find_firefox(Channel::DevEdition).is_err());
+    assert!(!ff::channels::is_devedition(&dir.as_ref().join("firefox-nightly")));
+}
@@ -0,0 +1,121 @@
+extern crate ff;
+extern crate marionette;
+extern crate mktemp;
+extern crate serde_json;
+
+#[path = "../../marionette/tests/common/mod.rs"]
+mod common;
+
+use std::env;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use marionette::{MarionetteConnection, MarionetteError};
+use serde_json::Value;
+
+use common::fake_marionette;
+
+#[test]
+fn crash_reports() {
+    let dir = mktemp::Temp::new_dir().unwrap();
+    let dir = dir.as_ref();
+    fs::write(dir.join("b.dmp"), "MDMP").unwrap();
+    fs::write(dir.join("b.extra"), r#"{"Version":"115.0","CrashTime":"1700000200"}"#).unwrap();
+    // Older versions use key=value lines
+    fs::write(dir.join("a.dmp"), "MDMP").unwrap();
+    fs::write(dir.join("a.extra"), "Version=52.0\nCrashTime=1700000100\nMozCrashReason=MOZ_CRASH()\n").unwrap();
+    fs::write(dir.join("c.dmp"), "MDMP").unwrap();
+
+    let reports = ff::crashes::reports_in(dir).unwrap();
+    let ids: Vec<&str> = reports.iter().map(|r| r.id.as_str()).collect();
+    assert_eq!(ids, vec!["c", "a", "b"]);
+    assert_eq!(reports[0].version(), None);
+    assert_eq!(reports[1].version(), Some("52.0"));
+    assert_eq!(reports[1].reason(), Some("MOZ_CRASH()"));
+    assert_eq!(reports[2].crash_time(), Some(1700000200));
+
+    assert!(ff::crashes::reports_in(&dir.join("missing")).unwrap().is_empty());
+}
+
+#[test]
+fn connection_lost() {
+    let mut titles = 0;
+    let port = fake_marionette(move |name, _| match name {
+        "WebDriver:NewSession" => serde_json::json!({"sessionId": "fake", "capabilities": {}}),
+        // The first title is requested by connect()
+        "WebDriver:GetTitle" if titles > 0 => Value::Null,
+        "WebDriver:GetTitle" => {
+            titles += 1;
+            serde_json::json!({"value": ""})
+        }
+        _ => serde_json::json!({}),
+    });
+
+    let mut conn = MarionetteConnection::connect(port).unwrap();
+    match conn.get_title() {
+        Err(MarionetteError::ConnectionLost(_)) => (),
+        res => panic!("Unexpected result {:?}", res),
+    }
+}
+
+fn lost() -> MarionetteError {
+    MarionetteError::ConnectionLost(std::io::Error::other("closed"))
+}
+
+#[test]
+fn detect_crashes() {
+    let home = mktemp::Temp::new_dir().unwrap();
+    env::set_var("HOME", home.as_ref());
+    let profile = mktemp::Temp::new_dir().unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+    // Without an instance there is nothing to tell a crash from a closed
+    // connection
+    match ff::crashes::detect(4301, lost()) {
+        MarionetteError::ConnectionLost(_) => (),
+        err => panic!("Unexpected error {:?}", err),
+    }
+
+    let dir = ff::ff_dir().unwrap().join("instances");
+    fs::create_dir_all(&dir).unwrap();
+    let instance = serde_json::json!({
+        "port": 4301, "name": "", "pid": u32::MAX / 2, "started": now, "profile": profile.as_ref(),
+    });
+    fs::write(dir.join("4301"), instance.to_string()).unwrap();
+    // An old report and the firefox process is gone
+    let minidumps = ff::crashes::minidumps_dir(profile.as_ref());
+    fs::create_dir_all(&minidumps).unwrap();
+    fs::write(minidumps.join("old.dmp"), "MDMP").unwrap();
+    fs::write(minidumps.join("old.extra"), format!(r#"{{"CrashTime":"{}"}}"#, now - 100)).unwrap();
+    match ff::crashes::detect(4301, lost()) {
+        MarionetteError::ConnectionLost(_) => (),
+        err => panic!("Unexpected error {:?}", err),
+    }
+
+    fs::write(minidumps.join("new.dmp"), "MDMP").unwrap();
+    match ff::crashes::detect(4301, lost()) {
+        MarionetteError::BrowserCrashed(msg) => assert!(msg.ends_with("1 crash reports, see ff crashes"), "{}", msg),
+        err => panic!("Unexpected error {:?}", err),
+    }
+    fs::remove_file(minidumps.join("new.dmp")).unwrap();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::ExitStatus;
+
+        ff::crashes::record_exit(4301, &ExitStatus::from_raw(11)).unwrap();
+        match ff::crashes::detect(4301, lost()) {
+            MarionetteError::BrowserCrashed(msg) => assert!(msg.contains("SIGSEGV"), "{}", msg),
+            err => panic!("Unexpected error {:?}", err),
+        }
+        // The instance list is not confused by the status file
+        assert_eq!(ff::instances().unwrap().len(), 1);
+
+        ff::crashes::record_exit(4301, &ExitStatus::from_raw(0)).unwrap();
+        match ff::crashes::detect(4301, lost()) {
+            MarionetteError::ConnectionLost(_) => (),
+            err => panic!("Unexpected error {:?}", err),
+        }
+    }
+}
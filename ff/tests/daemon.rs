@@ -0,0 +1,115 @@
+#![cfg(unix)]
+
+extern crate ff;
+extern crate marionette;
+extern crate mktemp;
+extern crate serde_json;
+
+#[path = "../../marionette/tests/common/mod.rs"]
+mod common;
+
+use std::env;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+
+use marionette::{Context, MarionetteConnection};
+use marionette::messages::{ElementRef, WindowHandle};
+use serde_json::json;
+
+/// A marionette server that counts the sessions created. The title shows
+/// the current window, context and frame depth.
+fn fake_firefox() -> u16 {
+    let mut sessions = 0;
+    let (mut window, mut context, mut frames) = ("main".to_owned(), "content".to_owned(), 0);
+    common::fake_marionette(move |name, params| {
+        match name {
+            "WebDriver:NewSession" => {
+                sessions += 1;
+                return json!({"sessionId": "fake", "capabilities": {}});
+            }
+            "WebDriver:GetTitle" => {
+                return json!({"value": format!("sessions {} {} {} {}", sessions, window, context, frames)});
+            }
+            "WebDriver:GetWindowHandle" => return json!({"value": window}),
+            "Marionette:GetContext" => return json!({"value": context}),
+            "WebDriver:SwitchToWindow" => {
+                window = params["handle"].as_str().unwrap().to_owned();
+                frames = 0;
+            }
+            "Marionette:SetContext" => context = params["value"].as_str().unwrap().to_owned(),
+            "WebDriver:SwitchToFrame" if params["element"].is_null() => frames = 0,
+            "WebDriver:SwitchToFrame" => frames += 1,
+            _ => {}
+        }
+        json!({})
+    })
+}
+
+/// The tests change $HOME, so they run one at a time
+static HOME_LOCK: Mutex<()> = Mutex::new(());
+
+struct TestDaemon {
+    daemon: ff::daemon::Daemon,
+    port: u16,
+    _home: mktemp::Temp,
+    _lock: MutexGuard<'static, ()>,
+}
+
+fn start_daemon() -> TestDaemon {
+    let lock = HOME_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let home = mktemp::Temp::new_dir().unwrap();
+    env::set_var("HOME", home.as_ref());
+
+    let port = fake_firefox();
+    let conn = MarionetteConnection::connect(port).unwrap();
+    let daemon = ff::daemon::Daemon::start(port, conn).unwrap();
+    TestDaemon { daemon, port, _home: home, _lock: lock }
+}
+
+#[test]
+fn clients_share_session() {
+    let test = start_daemon();
+    let port = test.port;
+    assert!(test.daemon.path().exists());
+
+    let mut first = ff::connect(port).unwrap();
+    let mut second = ff::connect(port).unwrap();
+    assert_eq!(first.session_id(), "fake");
+    assert_eq!(first.get_title().unwrap(), "sessions 1 main content 0");
+    assert_eq!(second.get_title().unwrap(), "sessions 1 main content 0");
+
+    let path = test.daemon.path().to_owned();
+    drop(test.daemon);
+    assert!(!path.exists());
+}
+
+#[test]
+fn clients_keep_their_window() {
+    let test = start_daemon();
+    let port = test.port;
+
+    let clients: Vec<_> = (0..2).map(|i| {
+        thread::spawn(move || {
+            let mut conn = ff::connect(port).unwrap();
+            let window = format!("window{}", i);
+            conn.switch_to_window(&WindowHandle::from_str(&window)).unwrap();
+            let context = if i == 0 { Context::Chrome } else { Context::Content };
+            conn.set_context(context).unwrap();
+            let frame = ElementRef { reference: "frame".to_owned() };
+            for _ in 0..i {
+                conn.switch_to_frame(Some(frame.clone())).unwrap();
+            }
+            let expected = format!("sessions 1 {} {} {}", window, if i == 0 { "chrome" } else { "content" }, i);
+            for _ in 0..50 {
+                assert_eq!(conn.get_title().unwrap(), expected);
+            }
+        })
+    }).collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+
+    // A new client starts in the initial window
+    let mut conn = ff::connect(port).unwrap();
+    assert_eq!(conn.get_title().unwrap(), "sessions 1 main content 0");
+}
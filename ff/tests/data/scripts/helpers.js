@@ -0,0 +1,3 @@
+function pageTitle() {
+	return document.title;
+}
@@ -0,0 +1,109 @@
+extern crate ff;
+extern crate mktemp;
+extern crate serde_json;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The tests change $HOME, so they run one at a time
+static HOME_LOCK: Mutex<()> = Mutex::new(());
+
+fn temp_home() -> (MutexGuard<'static, ()>, mktemp::Temp) {
+    let lock = HOME_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let home = mktemp::Temp::new_dir().unwrap();
+    env::set_var("HOME", home.as_ref());
+    (lock, home)
+}
+
+fn instance_path(port: u16) -> PathBuf {
+    ff::ff_dir().unwrap().join("instances").join(port.to_string())
+}
+
+/// Write an instance file like the one `ff start` creates
+fn add_instance(port: u16, name: &str, pid: u32) {
+    add_started_instance(port, name, pid, None);
+}
+
+fn add_started_instance(port: u16, name: &str, pid: u32, started: Option<u64>) {
+    let path = instance_path(port);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let instance = serde_json::json!({"port": port, "name": name, "pid": pid, "started": started});
+    fs::write(path, instance.to_string()).unwrap();
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn ff(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_ff")).args(args).output().unwrap();
+    assert!(output.status.success(), "ff {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn ports(pattern: Option<&str>) -> Vec<u16> {
+    ff::running_instances(pattern).unwrap().iter().map(|instance| instance.port).collect()
+}
+
+#[test]
+fn running_instances() {
+    let _home = temp_home();
+
+    let pid = std::process::id();
+    add_instance(4003, "web-2", pid);
+    add_instance(4001, "web-1", pid);
+    add_instance(4002, "api", pid);
+    add_instance(4004, "", pid);
+    // No such process
+    add_instance(4005, "web-3", u32::MAX / 2);
+
+    assert_eq!(ports(None), vec![4001, 4002, 4003, 4004]);
+    assert_eq!(ports(Some("web-*")), vec![4001, 4003]);
+    assert_eq!(ports(Some("*-?")), vec![4001, 4003]);
+    assert_eq!(ports(Some("a*i")), vec![4002]);
+    assert_eq!(ports(Some("*")), vec![4001, 4002, 4003, 4004]);
+    assert!(ports(Some("web")).is_empty());
+}
+
+#[test]
+fn prune() {
+    let _home = temp_home();
+
+    let pid = std::process::id();
+    add_started_instance(4101, "alive", pid, Some(now()));
+    add_instance(4102, "dead", u32::MAX / 2);
+    // The pid now belongs to a process started after the instance
+    add_started_instance(4103, "reused", pid, Some(1));
+
+    let out = ff(&["instances"]);
+    assert!(out.contains("4102:dead DEAD"), "{}", out);
+    assert!(out.contains("4103:reused DEAD"), "{}", out);
+    assert!(out.contains("4101:alive DOWN"), "{}", out);
+
+    ff(&["instances", "--prune"]);
+    assert!(instance_path(4101).exists());
+    assert!(!instance_path(4102).exists());
+    assert!(!instance_path(4103).exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn kill() {
+    let _home = temp_home();
+
+    let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+    // A reused pid is not killed
+    add_started_instance(4201, "reused", child.id(), Some(1));
+    ff(&["kill", "reused"]);
+    assert!(!instance_path(4201).exists());
+    assert!(child.try_wait().unwrap().is_none());
+
+    add_started_instance(4202, "hung", child.id(), Some(now()));
+    ff(&["kill", "4202"]);
+    assert!(!instance_path(4202).exists());
+    assert!(child.wait().unwrap().code().is_none());
+}
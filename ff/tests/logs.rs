@@ -0,0 +1,82 @@
+extern crate ff;
+extern crate marionette;
+extern crate mktemp;
+extern crate serde_json;
+
+#[path = "../../marionette/tests/common/mod.rs"]
+mod common;
+
+use std::env;
+use std::fs;
+use std::io::Write;
+
+use ff::logs::{self, RotatingLog};
+use serde_json::{json, Value};
+
+#[test]
+fn log_rotation() {
+    let dir = mktemp::Temp::new_dir().unwrap();
+    let path = dir.as_ref().join("4242.log");
+
+    let mut log = RotatingLog::create(&path, 10, 2).unwrap();
+    log.write_all(b"first\n").unwrap();
+    log.write_all(b"second\n").unwrap();
+    log.write_all(b"third\n").unwrap();
+    assert_eq!(logs::tail(&path, 5).unwrap(), vec!["third"]);
+    assert_eq!(logs::tail(dir.as_ref().join("4242.log.1"), 5).unwrap(), vec!["second"]);
+    assert_eq!(logs::tail(dir.as_ref().join("4242.log.2"), 5).unwrap(), vec!["first"]);
+
+    // A new log rotates the previous one, the oldest is dropped
+    drop(log);
+    let mut log = RotatingLog::create(&path, 100, 2).unwrap();
+    log.write_all(b"a\nb\nc\n").unwrap();
+    assert_eq!(logs::tail(&path, 2).unwrap(), vec!["b", "c"]);
+    assert_eq!(logs::tail(dir.as_ref().join("4242.log.1"), 5).unwrap(), vec!["third"]);
+    assert!(!dir.as_ref().join("4242.log.3").exists());
+}
+
+/// Browsers started in parallel with port 0 each get their own startup log
+#[cfg(unix)]
+#[test]
+fn pool_startup_logs() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let home = mktemp::Temp::new_dir().unwrap();
+    env::set_var("HOME", home.as_ref());
+    let ports: Vec<String> = (0..2)
+        .map(|_| common::fake_marionette(|name, _| match name {
+            "WebDriver:NewSession" => json!({"sessionId": "fake", "capabilities": {}}),
+            "WebDriver:GetTitle" => json!({"value": ""}),
+            "Marionette:Quit" => Value::Null,
+            _ => json!({}),
+        }).to_string())
+        .collect();
+
+    // Each fake firefox claims one of the marionette servers
+    let firefox = home.as_ref().join("firefox");
+    fs::write(&firefox, format!(r#"#!/bin/sh
+[ "$1" = --version ] && echo Mozilla Firefox 115.0 && exit
+while [ "$1" != --profile ]; do shift; done
+for port in {ports}; do
+    if mkdir "{home}/claimed-$port" 2>/dev/null; then
+        echo "fake firefox on $port"
+        printf $port > "$2/MarionetteActivePort"
+        exec sleep 60
+    fi
+done
+exit 1
+"#, ports = ports.join(" "), home = home.as_ref().display())).unwrap();
+    fs::set_permissions(&firefox, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let pool = ff::Pool::new(ff::BrowserBuilder::new().firefox_bin(&firefox).capture_log(true), 2).unwrap();
+    let instances = ff::ff_dir().unwrap().join("instances");
+    for port in &ports {
+        let log = instances.join(format!("{}.log", port));
+        assert_eq!(logs::tail(&log, 1).unwrap(), vec![format!("fake firefox on {}", port)]);
+    }
+    drop(pool);
+    let names: Vec<String> = fs::read_dir(&instances).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    assert!(names.iter().all(|name| !name.starts_with("starting-")), "{:?}", names);
+}
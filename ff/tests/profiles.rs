@@ -0,0 +1,60 @@
+extern crate ff;
+extern crate mktemp;
+
+use std::path::{Path, PathBuf};
+
+use ff::profiles::{is_locked, parse_profiles_ini};
+
+const PROFILES_INI: &str = "\
+[Profile1]
+Name=default
+IsRelative=1
+Path=Profiles/old.default
+Default=1
+
+[Profile0]
+Name=work
+IsRelative=0
+Path=/home/me/work-profile
+
+[Install4F96D1932A9F858E]
+Default=/home/me/work-profile
+Locked=1
+
+[General]
+StartWithLastProfile=1
+";
+
+#[test]
+fn profiles_ini() {
+    let profiles = parse_profiles_ini(PROFILES_INI, Path::new("/home/me/.mozilla/firefox"));
+    assert_eq!(profiles.len(), 2);
+    assert_eq!(profiles[0].name, "default");
+    assert_eq!(profiles[0].path, PathBuf::from("/home/me/.mozilla/firefox/Profiles/old.default"));
+    assert!(!profiles[0].default);
+    assert_eq!(profiles[1].name, "work");
+    assert_eq!(profiles[1].path, PathBuf::from("/home/me/work-profile"));
+    assert!(profiles[1].default);
+}
+
+#[cfg(unix)]
+#[test]
+fn profile_lock() {
+    use std::os::unix::fs::symlink;
+
+    let profile = mktemp::Temp::new_dir().unwrap();
+    let lock = profile.as_ref().join("lock");
+    assert!(!is_locked(profile.as_ref()));
+
+    symlink(format!("127.0.0.1:+{}", std::process::id()), &lock).unwrap();
+    assert!(is_locked(profile.as_ref()));
+
+    // Left behind by a browser that crashed
+    std::fs::remove_file(&lock).unwrap();
+    symlink(format!("127.0.0.1:+{}", u32::MAX / 2), &lock).unwrap();
+    assert!(!is_locked(profile.as_ref()));
+
+    std::fs::remove_file(&lock).unwrap();
+    symlink("unknown", &lock).unwrap();
+    assert!(is_locked(profile.as_ref()));
+}
@@ -0,0 +1,131 @@
+extern crate ff;
+extern crate marionette;
+extern crate serde_json;
+
+#[path = "../../marionette/tests/common/mod.rs"]
+mod common;
+
+use std::collections::HashMap;
+
+use ff::scripts::{SandboxLibrary, ScriptBundle};
+use marionette::{MarionetteConnection, MarionetteError, Script};
+use marionette::messages::ErrorObject;
+use serde_json::{json, Value};
+
+/// A marionette server that keeps the library loaded in each sandbox until
+/// the next navigation. Scripts return the number of times a library was
+/// loaded into their sandbox.
+fn fake_firefox() -> u16 {
+    let mut sandboxes: HashMap<String, Value> = HashMap::new();
+    let mut loads = 0;
+    common::fake_marionette(move |name, params| match name {
+        "WebDriver:NewSession" => json!({"sessionId": "fake", "capabilities": {}}),
+        "WebDriver:GetTitle" => json!({"value": "Fake title"}),
+        "WebDriver:Navigate" => {
+            sandboxes.clear();
+            json!({})
+        }
+        "WebDriver:ExecuteScript" => {
+            let sandbox = params["sandbox"].as_str().unwrap().to_owned();
+            if params["newSandbox"] == true {
+                sandboxes.remove(&sandbox);
+            }
+            let id = &params["args"][1];
+            if params["script"].as_str().unwrap().contains("__ffLibrary") {
+                let loaded = sandboxes.get(&sandbox) != Some(id);
+                if loaded {
+                    sandboxes.insert(sandbox, id.clone());
+                    loads += 1;
+                }
+                json!({"value": loaded})
+            } else {
+                json!({"value": {"loads": loads, "loaded": sandboxes.contains_key(&sandbox)}})
+            }
+        }
+        _ => json!({"value": null}),
+    })
+}
+
+#[test]
+fn bundle_locate() {
+    let mut bundle = ScriptBundle::new();
+    bundle.push("lib.js", "function a() {}\nfunction b() {}\n");
+    bundle.push("main.js", "a();\nb();\nreturn 1;");
+
+    assert_eq!(bundle.source(), "function a() {}\nfunction b() {}\na();\nb();\nreturn 1;\n");
+    assert_eq!(bundle.locate(1), Some(("lib.js", 1)));
+    assert_eq!(bundle.locate(2), Some(("lib.js", 2)));
+    assert_eq!(bundle.locate(3), Some(("main.js", 1)));
+    assert_eq!(bundle.locate(5), Some(("main.js", 3)));
+    assert_eq!(bundle.locate(6), None);
+}
+
+fn script_error(message: &str, stacktrace: &str) -> MarionetteError {
+    MarionetteError::Call(ErrorObject {
+        error: "javascript error".to_owned(),
+        message: message.to_owned(),
+        stacktrace: stacktrace.to_owned(),
+    })
+}
+
+fn error_message(err: MarionetteError) -> String {
+    match err {
+        MarionetteError::Call(obj) => obj.message,
+        err => panic!("Unexpected error {}", err),
+    }
+}
+
+/// The end of a stack trace reported by firefox, below the frames of the
+/// script
+const MARIONETTE_FRAMES: &str = "\
+evaluate.sandbox/evaluationPromise<@chrome://remote/content/marionette/evaluate.sys.mjs:230:28
+evaluate.sandbox@chrome://remote/content/marionette/evaluate.sys.mjs:188:28
+receiveMessage@chrome://remote/content/marionette/actors/MarionetteCommandsChild.sys.mjs:101:31
+";
+
+#[test]
+fn bundle_map_error() {
+    let mut bundle = ScriptBundle::new();
+    bundle.push("lib.js", "function a() {}\n");
+    bundle.push("main.js", "a();\nfoo();\n");
+
+    let err = script_error("ReferenceError: foo is not defined", &format!("@dummy file:3:1\n{}", MARIONETTE_FRAMES));
+    assert_eq!(error_message(bundle.map_error(err)), "ReferenceError: foo is not defined (main.js:2)");
+
+    // A page script called by the script is not part of the bundle
+    let stacktrace = format!("boom@https://example.com/app.js:3:9\n@dummy file:2:1\n{}", MARIONETTE_FRAMES);
+    let err = script_error("Error: boom", &stacktrace);
+    assert_eq!(error_message(bundle.map_error(err)), "Error: boom (main.js:1)");
+
+    let stacktrace = format!("boom@https://example.com/app.js:2:9\n{}", MARIONETTE_FRAMES);
+    let err = script_error("Error: boom", &stacktrace);
+    assert_eq!(error_message(bundle.map_error(err)), "Error: boom");
+}
+
+#[test]
+fn library_loaded_once_per_page() {
+    let mut conn = MarionetteConnection::connect(fake_firefox()).unwrap();
+    let mut bundle = ScriptBundle::new();
+    bundle.push("helpers.js", "function helper() { return 42; }");
+    let lib = SandboxLibrary::new("helpers", bundle);
+
+    assert!(lib.load(&mut conn).unwrap());
+    assert!(!lib.load(&mut conn).unwrap());
+    let mut script = Script::new("return helper();");
+    assert_eq!(lib.execute_script(&mut conn, &mut script).unwrap(), json!({"loads": 1, "loaded": true}));
+
+    // Sandboxes are gone after a navigation
+    conn.get("https://example.com").unwrap();
+    assert_eq!(lib.execute_script(&mut conn, &mut script).unwrap(), json!({"loads": 2, "loaded": true}));
+
+    let mut script = Script::new("return 1;");
+    script.sandbox("helpers");
+    script.new_sandbox(true);
+    assert_eq!(conn.execute_script(&script).unwrap(), json!({"loads": 2, "loaded": false}));
+    assert!(lib.load(&mut conn).unwrap());
+
+    // Scripts run through the library keep its sandbox
+    let mut script = Script::new("return helper();");
+    script.new_sandbox(true);
+    assert_eq!(lib.execute_script(&mut conn, &mut script).unwrap(), json!({"loads": 3, "loaded": true}));
+}
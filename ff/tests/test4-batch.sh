@@ -0,0 +1,18 @@
+#!/bin/bash
+
+. common.sh
+
+OUT=$(ff run <<EOT
+go file://$(pwd)/data/test2/frames.html
+# save the title and pass it to a script
+title = title
+exec -S "return arguments[0];" '"\$title"'
+EOT
+)
+test "$(echo "$OUT" | head -1)" = "$(ff title)"
+
+# a failing command stops the batch, unless --continue-on-error is used
+OUT=$(printf 'switch no-such-window\ntitle\n' | ff run) && exit 1
+test -z "$OUT"
+OUT=$(printf 'switch no-such-window\ntitle\n' | ff run --continue-on-error) && exit 1
+test "$OUT" = "$(ff title)"
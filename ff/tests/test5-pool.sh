@@ -0,0 +1,15 @@
+#!/bin/bash
+set -ex
+
+trap 'echo "Test failed"' ERR
+
+PAGE=file://$(pwd)/data/test2/frames.html
+URLS=$(for n in 1 2 3 4 5; do echo "$PAGE?page=$n"; done)
+
+# every URL is visited once, browsers are replaced after two pages
+OUT=$(echo "$URLS" | ff pool --headless --size 2 --max-uses 2 <(printf 'go $URL\nurl\n'))
+test "$(echo "$OUT" | sort)" = "$URLS"
+
+# the pool fails when the script fails for any URL
+OUT=$(printf '%s\n' "$PAGE" "$PAGE" | ff pool --headless --size 2 <(printf 'go $URL\nswitch no-such-window\n')) && exit 1
+test -z "$OUT"
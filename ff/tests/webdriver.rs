@@ -0,0 +1,137 @@
+extern crate ff;
+extern crate marionette;
+extern crate serde_json;
+
+#[path = "../../marionette/tests/common/mod.rs"]
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use ff::webdriver::{Target, WebDriverServer};
+use serde_json::{json, Value};
+
+/// A marionette server that answers with the parameters it received,
+/// alerts are never open
+fn fake_firefox() -> u16 {
+    common::fake_marionette(|name, params| match name {
+        "WebDriver:NewSession" => json!({"sessionId": "fake", "capabilities": {"browserVersion": "115.0"}}),
+        "WebDriver:GetTitle" => json!({"value": "Fake title"}),
+        "WebDriver:Navigate" => json!({}),
+        "WebDriver:GetCookies" => json!([{"name": "a", "value": "1"}]),
+        "WebDriver:GetAlertText" => json!({"error": "no such alert", "message": "No dialog", "stacktrace": ""}),
+        "Marionette:GetContext" => json!({"value": "content"}),
+        name => json!({"value": {"command": name, "params": params}}),
+    })
+}
+
+/// Send a request, returns the HTTP status and the JSON body
+fn request(port: u16, method: &str, path: &str, body: Option<Value>) -> (u16, Value) {
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    raw_request(port, &format!("{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                               method, path, body.len(), body))
+}
+
+fn raw_request(port: u16, request: &str) -> (u16, Value) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[test]
+fn webdriver_session() {
+    let server = Arc::new(WebDriverServer::new(Target::Instance(fake_firefox())));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let serving = server.clone();
+    thread::spawn(move || serving.serve(listener));
+
+    assert_eq!(request(port, "GET", "/status", None).1["value"]["ready"], true);
+    let (status, resp) = request(port, "POST", "/session", Some(json!({"capabilities": {}})));
+    assert_eq!(status, 200);
+    let id = resp["value"]["sessionId"].as_str().unwrap().to_owned();
+    assert_eq!(resp["value"]["capabilities"]["browserVersion"], "115.0");
+    assert_eq!(request(port, "GET", "/status", None).1["value"]["ready"], false);
+
+    let (status, resp) = request(port, "POST", "/session", Some(json!({"capabilities": {}})));
+    assert_eq!((status, &resp["value"]["error"]), (500, &json!("session not created")));
+
+    let session = format!("/session/{}", id);
+    let resp = request(port, "POST", &format!("{}/url", session), Some(json!({"url": "https://example.com"})));
+    assert_eq!(resp, (200, json!({"value": null})));
+    assert_eq!(request(port, "GET", &format!("{}/title", session), None), (200, json!({"value": "Fake title"})));
+
+    // Path parameters are passed to marionette
+    let (_, resp) = request(port, "GET", &format!("{}/element/e1/attribute/href", session), None);
+    assert_eq!(resp["value"], json!({"command": "WebDriver:GetElementAttribute",
+                                      "params": {"id": "e1", "name": "href"}}));
+    let (_, resp) = request(port, "POST", &format!("{}/element/e1/element", session),
+                            Some(json!({"using": "css selector", "value": "p"})));
+    assert_eq!(resp["value"]["params"], json!({"element": "e1", "using": "css selector", "value": "p"}));
+
+    assert_eq!(request(port, "GET", &format!("{}/cookie/a", session), None).1["value"]["value"], "1");
+    let (status, resp) = request(port, "GET", &format!("{}/cookie/b", session), None);
+    assert_eq!((status, &resp["value"]["error"]), (404, &json!("no such cookie")));
+    let (status, resp) = request(port, "GET", &format!("{}/alert/text", session), None);
+    assert_eq!((status, &resp["value"]["error"]), (404, &json!("no such alert")));
+    let (status, resp) = request(port, "GET", "/session/other/title", None);
+    assert_eq!((status, &resp["value"]["error"]), (404, &json!("invalid session id")));
+    let (status, resp) = request(port, "GET", &format!("{}/no/such/command", session), None);
+    assert_eq!((status, &resp["value"]["error"]), (404, &json!("unknown command")));
+
+    // The body is not read
+    let (status, resp) = raw_request(port, "POST /session HTTP/1.1\r\nContent-Length: 1000000000\r\n\r\n");
+    assert_eq!((status, &resp["value"]["error"]), (400, &json!("invalid argument")));
+
+    assert_eq!(request(port, "DELETE", &session, None), (200, json!({"value": null})));
+    assert_eq!(request(port, "GET", "/status", None).1["value"]["ready"], true);
+}
+
+#[test]
+fn webdriver_capabilities() {
+    let server = Arc::new(WebDriverServer::new(Target::Instance(fake_firefox())));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let serving = server.clone();
+    thread::spawn(move || serving.serve(listener));
+    let new_session = |capabilities: Value| {
+        let (status, resp) = request(port, "POST", "/session", Some(json!({"capabilities": capabilities})));
+        if status == 200 {
+            let session = format!("/session/{}", resp["value"]["sessionId"].as_str().unwrap());
+            assert_eq!(request(port, "DELETE", &session, None).0, 200);
+        }
+        (status, resp["value"].clone())
+    };
+
+    // The first firstMatch entry that can be met is used
+    let (status, resp) = new_session(json!({
+        "alwaysMatch": {"acceptInsecureCerts": true},
+        "firstMatch": [{"browserName": "chrome"}, {"browserName": "firefox", "browserVersion": ">=100"}],
+    }));
+    assert_eq!(status, 200, "{}", resp);
+    assert_eq!(resp["capabilities"]["acceptInsecureCerts"], true);
+    assert_eq!(resp["capabilities"]["browserName"], "firefox");
+
+    for capabilities in [json!({"alwaysMatch": {"browserName": "chrome"}}),
+                         json!({"alwaysMatch": {"pageLoadStrategy": "eager"}}),
+                         json!({"firstMatch": [{"platformName": "plan9"}, {"webSocketUrl": true}]}),
+                         json!({"alwaysMatch": {"browserVersion": "<100"}})] {
+        let (status, resp) = new_session(capabilities.clone());
+        assert_eq!((status, &resp["error"]), (500, &json!("session not created")), "{}", capabilities);
+    }
+    assert_eq!(new_session(json!({"alwaysMatch": {"browserVersion": "115.0"}})).0, 200);
+
+    for capabilities in [json!({"alwaysMatch": {"browserName": "firefox"}, "firstMatch": [{"browserName": "firefox"}]}),
+                         json!({"firstMatch": []}),
+                         json!({"alwaysMatch": {"acceptInsecureCerts": "yes"}})] {
+        let (status, resp) = new_session(capabilities.clone());
+        assert_eq!((status, &resp["error"]), (400, &json!("invalid argument")), "{}", capabilities);
+    }
+    assert_eq!(request(port, "GET", "/status", None).1["value"]["ready"], true);
+}
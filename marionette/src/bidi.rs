@@ -0,0 +1,203 @@
+//! WebDriver BiDi client, enabled with the `bidi` feature
+//!
+//! Firefox started with `--remote-debugging-port` accepts WebDriver BiDi
+//! sessions over a WebSocket. Unlike marionette, BiDi sends events for the
+//! modules a client subscribed to, e.g. console messages, network requests
+//! or navigation. Events are delivered over a channel, commands wait for
+//! their own response in the meantime.
+//!
+//! ```no_run
+//! use marionette::bidi::BidiConnection;
+//!
+//! let mut bidi = BidiConnection::connect(9222).unwrap();
+//! bidi.subscribe(&["log.entryAdded"], None).unwrap();
+//! for event in bidi.events().iter() {
+//!     println!("{}: {}", event.method, event.params["text"]);
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread;
+
+use serde_json;
+use tungstenite::{self, Message, WebSocket};
+use tungstenite::protocol::Role;
+
+use super::{JsonValue, MarionetteError, Result};
+use messages::ErrorObject;
+
+/// An event of a subscribed module
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// e.g. `log.entryAdded`
+    pub method: String,
+    pub params: JsonValue,
+}
+
+/// Commands waiting for their response by id, `None` once the connection
+/// is closed
+type Pending = Option<HashMap<u64, mpsc::Sender<Result<JsonValue>>>>;
+
+fn lock(pending: &Mutex<Pending>) -> MutexGuard<'_, Pending> {
+    pending.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn connection_closed() -> MarionetteError {
+    MarionetteError::ConnectionLost(io::Error::new(io::ErrorKind::ConnectionAborted, "BiDi connection closed"))
+}
+
+pub struct BidiConnection {
+    socket: WebSocket<TcpStream>,
+    pending: Arc<Mutex<Pending>>,
+    events: mpsc::Receiver<Event>,
+    next_id: u64,
+    session_id: Option<String>,
+}
+
+impl BidiConnection {
+    /// Connect to the remote agent of firefox on `port` and start a new
+    /// session
+    pub fn connect(port: u16) -> Result<Self> {
+        let mut conn = BidiConnection::attach(&format!("ws://127.0.0.1:{}/session", port))?;
+        let resp = conn.send("session.new", serde_json::json!({"capabilities": {}}))?;
+        let session_id = resp["sessionId"].as_str().ok_or(MarionetteError::UnexpectedType)?;
+        conn.session_id = Some(session_id.to_owned());
+        Ok(conn)
+    }
+
+    /// Connect to the WebSocket URL of an existing session, e.g. the
+    /// `webSocketUrl` capability returned by a WebDriver session
+    pub fn attach(url: &str) -> Result<Self> {
+        let addr = url.strip_prefix("ws://")
+            .and_then(|rest| rest.split('/').next())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid BiDi URL: {}", url)))?;
+        let stream = TcpStream::connect(addr)?;
+        let (socket, _) = tungstenite::client(url, stream.try_clone()?)
+            .map_err(|err| io::Error::other(format!("WebSocket handshake failed: {}", err)))?;
+
+        // Responses and events are read on another thread, through a clone
+        // of the stream
+        let reader = WebSocket::from_raw_socket(stream, Role::Client, None);
+        let pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let (events_tx, events) = mpsc::channel();
+        let reader_pending = pending.clone();
+        thread::spawn(move || read_messages(reader, &reader_pending, &events_tx));
+
+        Ok(BidiConnection { socket, pending, events, next_id: 0, session_id: None })
+    }
+
+    /// The session started by `connect()`
+    pub fn session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
+
+    /// Send a command and wait for its result
+    pub fn send(&mut self, method: &str, params: JsonValue) -> Result<JsonValue> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let (tx, rx) = mpsc::channel();
+        match lock(&self.pending).as_mut() {
+            Some(pending) => pending.insert(id, tx),
+            None => return Err(connection_closed()),
+        };
+
+        let msg = serde_json::json!({"id": id, "method": method, "params": params}).to_string();
+        debug!("-> {}", msg);
+        if let Err(err) = self.socket.send(Message::Text(msg)) {
+            return Err(MarionetteError::ConnectionLost(io::Error::other(err.to_string())));
+        }
+        rx.recv().unwrap_or_else(|_| Err(connection_closed()))
+    }
+
+    /// Receive events for all browsing contexts or only the given ones.
+    /// Events are named as `log.entryAdded`, a module name such as
+    /// `network` selects all the events of the module.
+    pub fn subscribe(&mut self, events: &[&str], contexts: Option<&[&str]>) -> Result<()> {
+        let mut params = serde_json::json!({"events": events});
+        if let Some(contexts) = contexts {
+            params["contexts"] = serde_json::json!(contexts);
+        }
+        self.send("session.subscribe", params)?;
+        Ok(())
+    }
+
+    pub fn unsubscribe(&mut self, events: &[&str]) -> Result<()> {
+        self.send("session.unsubscribe", serde_json::json!({"events": events}))?;
+        Ok(())
+    }
+
+    /// Events of the subscribed modules, in the order they were received.
+    /// Events are kept until they are read, the channel is closed when the
+    /// connection is lost.
+    pub fn events(&self) -> &mpsc::Receiver<Event> {
+        &self.events
+    }
+
+    /// End the session started by `connect()` and close the connection
+    pub fn close(mut self) -> Result<()> {
+        if self.session_id.is_some() {
+            self.send("session.end", serde_json::json!({}))?;
+        }
+        let _ = self.socket.close(None);
+        let _ = self.socket.flush();
+        Ok(())
+    }
+}
+
+impl Drop for BidiConnection {
+    fn drop(&mut self) {
+        // Stops the reader thread
+        let _ = self.socket.get_mut().shutdown(Shutdown::Both);
+    }
+}
+
+/// Pass responses to the waiting commands and events to the channel until
+/// the connection is closed
+fn read_messages(mut socket: WebSocket<TcpStream>, pending: &Mutex<Pending>, events: &mpsc::Sender<Event>) {
+    loop {
+        let text = match socket.read() {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(_)) | Err(_) => break,
+            Ok(_) => continue,
+        };
+        debug!("<- {}", text);
+        let mut msg: JsonValue = match serde_json::from_str(&text) {
+            Ok(msg) => msg,
+            Err(err) => {
+                warn!("Invalid BiDi message {}: {}", text, err);
+                continue;
+            }
+        };
+
+        if msg["type"] == "event" {
+            let method = msg["method"].as_str().unwrap_or("").to_owned();
+            // Events are dropped when nobody listens
+            let _ = events.send(Event { method, params: msg["params"].take() });
+            continue;
+        }
+        let waiting = msg["id"].as_u64().and_then(|id| lock(pending).as_mut()?.remove(&id));
+        match waiting {
+            Some(tx) => {
+                let _ = tx.send(response(msg));
+            }
+            None => warn!("Unexpected BiDi message: {}", text),
+        }
+    }
+    // Waiting commands fail when their sender is dropped
+    lock(pending).take();
+}
+
+fn response(mut msg: JsonValue) -> Result<JsonValue> {
+    if msg["type"] == "success" {
+        return Ok(msg["result"].take());
+    }
+    let field = |name: &str| msg[name].as_str().unwrap_or("").to_owned();
+    Err(MarionetteError::Call(ErrorObject {
+        error: field("error"),
+        message: field("message"),
+        stacktrace: field("stacktrace"),
+    }))
+}
@@ -0,0 +1,112 @@
+//! The commands used by `MarionetteConnection`, with their parameter and
+//! response types and their names in each protocol dialect
+//!
+//! Firefox 56 introduced the `WebDriver:` and `Marionette:` prefixed names,
+//! older versions only know the plain names. Commands that a dialect lacks
+//! fail with `MarionetteError::Unsupported` instead of being sent.
+//!
+//! Commands are sent with `MarionetteConnection::send()`
+//!
+//! ```no_run
+//! use marionette::{commands, MarionetteConnection};
+//! use marionette::messages::Empty;
+//!
+//! let mut conn = MarionetteConnection::connect(2828).unwrap();
+//! let title = conn.send::<commands::GetTitle>(&Empty {}).unwrap();
+//! println!("{}", title.value);
+//! ```
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use super::{Compatibility, JsonValue};
+use messages::*;
+
+/// The names of a command, `None` where a dialect does not support it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommandInfo {
+    pub marionette: Option<&'static str>,
+    pub webdriver: Option<&'static str>,
+}
+
+impl CommandInfo {
+    /// The name to send with the given dialect
+    pub fn name(&self, compatibility: Compatibility) -> Option<&'static str> {
+        match compatibility {
+            Compatibility::Marionette => self.marionette,
+            Compatibility::Webdriver => self.webdriver,
+        }
+    }
+
+    /// A name for messages, the prefixed one if there is one
+    pub fn display_name(&self) -> &'static str {
+        self.webdriver.or(self.marionette).unwrap_or("")
+    }
+}
+
+/// A marionette command with typed parameters and response, the parameters
+/// may borrow their data
+pub trait Command {
+    type Params<'a>: Serialize;
+    type Response: DeserializeOwned;
+    const INFO: CommandInfo;
+}
+
+macro_rules! command {
+    ($(#[$attr:meta])* $name:ident($params:ty) -> $response:ty, $marionette:expr, $webdriver:expr) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy)]
+        pub struct $name;
+
+        impl Command for $name {
+            type Params<'a> = $params;
+            type Response = $response;
+            const INFO: CommandInfo = CommandInfo { marionette: $marionette, webdriver: $webdriver };
+        }
+    };
+}
+
+command!(NewSession(NewSessionRequest) -> NewSessionResponse, Some("newSession"), Some("WebDriver:NewSession"));
+command!(Refresh(Empty) -> Empty, Some("refresh"), Some("WebDriver:Refresh"));
+command!(Back(Empty) -> Empty, Some("goBack"), Some("WebDriver:Back"));
+command!(Forward(Empty) -> Empty, Some("goForward"), Some("WebDriver:Forward"));
+command!(GetTitle(Empty) -> ResponseValue<String>, Some("getTitle"), Some("WebDriver:GetTitle"));
+command!(Navigate(GetCommand) -> Empty, Some("get"), Some("WebDriver:Navigate"));
+command!(GetCurrentUrl(Empty) -> ResponseValue<String>, Some("getCurrentUrl"), Some("WebDriver:GetCurrentURL"));
+command!(GetWindowHandle(Empty) -> ResponseValue<WindowHandle>,
+         Some("getWindowHandle"), Some("WebDriver:GetWindowHandle"));
+command!(GetWindowHandles(Empty) -> Vec<WindowHandle>, Some("getWindowHandles"), Some("WebDriver:GetWindowHandles"));
+command!(SwitchToWindow(WindowHandle) -> Empty, Some("switchToWindow"), Some("WebDriver:SwitchToWindow"));
+command!(GetContext(Empty) -> ContextValue, Some("getContext"), Some("Marionette:GetContext"));
+command!(SetContext(ContextValue) -> Empty, Some("setContext"), Some("Marionette:SetContext"));
+command!(ExecuteScript(Script) -> ResponseValue<JsonValue>, Some("executeScript"), Some("WebDriver:ExecuteScript"));
+command!(ExecuteAsyncScript(Script) -> ResponseValue<JsonValue>,
+         Some("executeAsyncScript"), Some("WebDriver:ExecuteAsyncScript"));
+command!(SetTimeouts(Timeouts) -> Empty, Some("timeouts"), Some("WebDriver:SetTimeouts"));
+command!(GetPageSource(Empty) -> ResponseValue<String>, Some("getPageSource"), Some("WebDriver:GetPageSource"));
+command!(FindElements(FindElementQuery) -> Vec<ElementRef>, Some("findElements"), Some("WebDriver:FindElements"));
+command!(GetElementAttribute(ElementOp) -> ResponseValue<Option<String>>,
+         Some("getElementAttribute"), Some("WebDriver:GetElementAttribute"));
+command!(GetElementProperty(ElementOp) -> ResponseValue<JsonValue>,
+         Some("getElementProperty"), Some("WebDriver:GetElementProperty"));
+command!(GetElementText(ElementOp) -> ResponseValue<String>, Some("getElementText"), Some("WebDriver:GetElementText"));
+command!(GetActiveFrame(Empty) -> ResponseValue<Option<ElementRef>>,
+         Some("getActiveFrame"), Some("WebDriver:GetActiveFrame"));
+command!(SwitchToFrame(FrameSwitch) -> Empty, Some("switchToFrame"), Some("WebDriver:SwitchToFrame"));
+command!(SwitchToParentFrame(Empty) -> Empty, Some("switchToParentFrame"), Some("WebDriver:SwitchToParentFrame"));
+command!(Quit(Empty) -> Empty, Some("quitApplication"), Some("Marionette:Quit"));
+command!(InstallAddon(AddonInstall<'a>) -> Empty, Some("addon:install"), Some("Addon:Install"));
+command!(
+    /// The cookie format of the old protocol is not supported
+    AddCookieCommand(AddCookie<'a>) -> Empty, None, Some("WebDriver:AddCookie"));
+command!(GetCookies(Empty) -> Vec<Cookie>, None, Some("WebDriver:GetCookies"));
+
+/// Every command in this module
+pub const ALL: &[CommandInfo] = &[
+    NewSession::INFO, Refresh::INFO, Back::INFO, Forward::INFO, GetTitle::INFO, Navigate::INFO,
+    GetCurrentUrl::INFO, GetWindowHandle::INFO, GetWindowHandles::INFO, SwitchToWindow::INFO,
+    GetContext::INFO, SetContext::INFO, ExecuteScript::INFO, ExecuteAsyncScript::INFO,
+    SetTimeouts::INFO, GetPageSource::INFO, FindElements::INFO, GetElementAttribute::INFO,
+    GetElementProperty::INFO, GetElementText::INFO, GetActiveFrame::INFO, SwitchToFrame::INFO,
+    SwitchToParentFrame::INFO, Quit::INFO, InstallAddon::INFO, AddCookieCommand::INFO, GetCookies::INFO,
+];
@@ -3,8 +3,10 @@
 //! This is a very simple synchronous implementation of the protocol.
 
 use std::io;
-use std::io::{BufRead, Write, Error, ErrorKind, BufReader};
+use std::io::{BufRead, Read, Write, Error, ErrorKind, BufReader};
 use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 use std::str;
 use std::convert::From;
 use std::str::FromStr;
@@ -20,6 +22,8 @@ extern crate serde_json;
 use serde_json::{Value, from_str, from_value, to_value};
 use serde_json::Error as JsonError;
 extern crate serde;
+#[cfg(feature = "bidi")]
+extern crate tungstenite;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 
@@ -35,6 +39,13 @@ pub enum MarionetteError {
     InvalidResponseArray,
     UnsupportedProtocolVersion,
     UnsupportedContext(String),
+    /// The browser closed the connection or it was reset during a call
+    ConnectionLost(io::Error),
+    /// The browser process died, the string describes the crash
+    BrowserCrashed(String),
+    /// The command is not available with the protocol dialect of the
+    /// browser, see `commands`
+    Unsupported(String),
 }
 
 impl MarionetteError {
@@ -42,6 +53,7 @@ impl MarionetteError {
         match *self {
             MarionetteError::Call(_) => false,
             MarionetteError::UnsupportedContext(_) => false,
+            MarionetteError::Unsupported(_) => false,
             // Other errors are either Io errors or messages that do not follow the
             // protocol
             _ => true,
@@ -70,6 +82,9 @@ impl fmt::Display for MarionetteError {
             MarionetteError::InvalidResponseArray => write!(f, "Invalid response array in marionette message"),
             MarionetteError::UnsupportedProtocolVersion => write!(f, "Browser uses unsupported protocol version"),
             MarionetteError::UnsupportedContext(ref c) => write!(f, "Unsupported context: {}", c),
+            MarionetteError::ConnectionLost(ref err) => write!(f, "Lost connection to the browser: {}", err),
+            MarionetteError::BrowserCrashed(ref msg) => write!(f, "The browser crashed: {}", msg),
+            MarionetteError::Unsupported(ref msg) => write!(f, "Unsupported command: {}", msg),
         }
     }
 }
@@ -85,14 +100,22 @@ impl std::error::Error for MarionetteError {
             MarionetteError::InvalidResponseArray => "Invalid response array in marionette message",
             MarionetteError::UnsupportedProtocolVersion => "Browser uses unsupported protocol version",
             MarionetteError::UnsupportedContext(_) => "Unsupported context",
+            MarionetteError::ConnectionLost(_) => "Lost connection to the browser",
+            MarionetteError::BrowserCrashed(_) => "The browser crashed",
+            MarionetteError::Unsupported(_) => "Unsupported command",
         }
     }
 }
 
 pub type Result<T> = std::result::Result<T, MarionetteError>;
 
+pub mod commands;
+use commands::{Command, CommandInfo};
 pub mod messages;
 use messages::*;
+#[cfg(feature = "bidi")]
+pub mod bidi;
+pub mod rdp;
 pub use messages::{LogMsg, QueryMethod, WindowHandle, Script, Timeouts, Cookie};
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -104,60 +127,129 @@ pub enum Compatibility {
     Webdriver,
 }
 
+/// The first firefox version with the prefixed command names
+pub const WEBDRIVER_VERSION: u64 = 56;
+
+impl Compatibility {
+    /// The dialect of a firefox version such as `115.0.2esr`, `None` if the
+    /// version can not be parsed
+    pub fn from_version(version: &str) -> Option<Compatibility> {
+        let major: u64 = version.split('.').next()?.parse().ok()?;
+        Some(match major >= WEBDRIVER_VERSION {
+            true => Compatibility::Webdriver,
+            false => Compatibility::Marionette,
+        })
+    }
+}
+
 pub struct MarionetteConnection {
-    reader: BufReader<TcpStream>,
-    writer: TcpStream,
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: Box<dyn Write + Send>,
     msgid: u64,
     timeouts: Option<Timeouts>,
     compatibility: Compatibility,
+    session_id: String,
+    browser_version: Option<String>,
 }
 
 impl MarionetteConnection {
     pub fn compatibility(&self) -> Compatibility { self.compatibility }
 
+    /// The id for the session created when connecting
+    pub fn session_id(&self) -> &str { &self.session_id }
+
+    /// The firefox version from the session capabilities, if the browser
+    /// reported it
+    pub fn browser_version(&self) -> Option<&str> { self.browser_version.as_deref() }
+
+    /// Connect to marionette on a local port. The protocol dialect is taken
+    /// from the browser version, see `connect_as()` when it is known up front.
     pub fn connect(port: u16) -> Result<Self> {
+        Self::connect_tcp(port, None)
+    }
+
+    /// Connect using a known dialect, e.g. `Compatibility::from_version()`
+    /// for the version reported by `firefox --version`
+    pub fn connect_as(port: u16, compatibility: Compatibility) -> Result<Self> {
+        Self::connect_tcp(port, Some(compatibility))
+    }
+
+    fn connect_tcp(port: u16, compatibility: Option<Compatibility>) -> Result<Self> {
         let stream = TcpStream::connect(("127.0.0.1", port))?;
-        let mut reader = BufReader::new(stream.try_clone()?);
+        let reader = stream.try_clone()?;
+        Self::from_stream(Box::new(reader), Box::new(stream), &port.to_string(), compatibility)
+    }
+
+    /// Connect over a unix socket, e.g. to an ff instance daemon
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let stream = UnixStream::connect(&path)?;
+        let reader = stream.try_clone()?;
+        Self::from_stream(Box::new(reader), Box::new(stream), &path.as_ref().to_string_lossy(), None)
+    }
+
+    /// Start a new session over an already open stream, `name` is only
+    /// used for logging.
+    ///
+    /// Without a known dialect the prefixed new session command is tried
+    /// first, the old one is only used if the browser does not know the
+    /// command. Other errors are returned as is. The browser version in the
+    /// new session response decides the dialect used from then on.
+    fn from_stream(input: Box<dyn Read + Send>,
+                   output: Box<dyn Write + Send>,
+                   name: &str,
+                   compatibility: Option<Compatibility>) -> Result<Self> {
+        let mut reader = BufReader::new(input);
         let frame = readframe(&mut reader)?;
         debug!("ServerInfo frame: {}", frame);
         let info: ServerInfo = from_str(&frame)?;
-        if info.marionetteProtocol == 3 {
-            let mut conn = MarionetteConnection {
-                reader: reader,
-                writer: stream,
-                msgid: 0,
-                timeouts: None,
-                compatibility: Compatibility::Webdriver,
-            };
-            // TODO store the whole capabilities object instead
-            let options = NewSessionRequest::new();
-            let (resp, compat) = match conn.new_session_webdriver(&options) {
-                Ok(resp) => (resp, conn.compatibility),
+        if info.marionetteProtocol != 3 {
+            return Err(MarionetteError::UnsupportedProtocolVersion);
+        }
+
+        let mut conn = MarionetteConnection {
+            reader,
+            writer: output,
+            msgid: 0,
+            timeouts: None,
+            compatibility: compatibility.unwrap_or(Compatibility::Webdriver),
+            session_id: String::new(),
+            browser_version: None,
+        };
+        // TODO store the whole capabilities object instead
+        let options = NewSessionRequest::new();
+        let resp = match conn.new_session(&options) {
+            Err(MarionetteError::Call(ref err)) if compatibility.is_none() && err.error == "unknown command" => {
+                debug!("Browser does not know {}, using the old protocol", commands::NewSession::INFO.display_name());
+                conn.compatibility = Compatibility::Marionette;
+                conn.new_session(&options)?
+            }
+            resp => resp?,
+        };
+
+        let version = resp.capabilities.browserVersion;
+        if let Some(compat) = version.as_deref().and_then(Compatibility::from_version) {
+            if compat != conn.compatibility {
+                debug!("Firefox {:?} uses the {:?} protocol", version, compat);
+                conn.compatibility = compat;
+            }
+        }
+        conn.browser_version = version;
+        conn.timeouts = resp.capabilities.timeouts;
+        conn.session_id = resp.sessionId;
+
+        // Try to make sure the browser is live before returning
+        for retry in 0..4 {
+            match conn.get_title() {
+                Ok(_) => break,
                 Err(err) => {
-                    debug!("Failed to establish new session, will retry with old protocol: {}", err);
-                    // Retry with the new old protocol
-                    (conn.new_session(&options)?, Compatibility::Marionette)
-                }
-            };
-
-            conn.compatibility = compat;
-            conn.timeouts = resp.capabilities.timeouts;
-
-            // Try to make sure the browser is live before returning
-            for retry in 0..4 {
-                match conn.get_title() {
-                    Ok(_) => break,
-                    Err(err) => {
-                        debug!("#{} Failed to connect to firefox({}): {}", retry, port, err);
-                        std::thread::sleep(std::time::Duration::new(retry*2, 0));
-                    }
+                    debug!("#{} Failed to connect to firefox({}): {}", retry, name, err);
+                    std::thread::sleep(std::time::Duration::new(retry*2, 0));
                 }
             }
-
-            Ok(conn)
-        } else {
-            Err(MarionetteError::UnsupportedProtocolVersion)
         }
+
+        Ok(conn)
     }
 
     fn next_msgid(&mut self) -> u64 {
@@ -176,10 +268,10 @@ impl MarionetteConnection {
         cmdarr.push(to_value(args)?);
         let msg = Value::Array(cmdarr);
 
-        sendframe(&mut self.writer, &msg.to_string())?;
+        sendframe(&mut self.writer, &msg.to_string()).map_err(connection_error)?;
 
         loop {
-            let resp = readframe(&mut self.reader)?;
+            let resp = readframe(&mut self.reader).map_err(connection_error)?;
             debug!("<- {}", resp);
             if let Value::Array(mut arr) = Value::from_str(&resp)? {
 
@@ -222,58 +314,59 @@ impl MarionetteConnection {
         }
     }
 
-    // AFAIK the semantics for newSession is that it should be called for each connection
-    fn new_session(&mut self, options: &NewSessionRequest) -> Result<NewSessionResponse> {
-        self.call("newSession", options)
+    /// Send a command with the given parameters and return the response
+    /// value as is. This can be used for commands without a wrapper.
+    pub fn call_raw(&mut self, name: &str, args: JsonValue) -> Result<JsonValue> {
+        self.call(name, args)
+    }
+
+    /// Send a typed command from the `commands` module, using its name in
+    /// the dialect of this connection
+    pub fn send<C: Command>(&mut self, params: &C::Params<'_>) -> Result<C::Response> {
+        match C::INFO.name(self.compatibility) {
+            Some(name) => self.call(name, params),
+            None => Err(MarionetteError::Unsupported(format!("{} is not available with the {:?} protocol",
+                                                             C::INFO.display_name(), self.compatibility))),
+        }
+    }
+
+    /// True if the command can be used with the dialect of this connection
+    pub fn supports(&self, cmd: &CommandInfo) -> bool {
+        cmd.name(self.compatibility).is_some()
     }
 
-    fn new_session_webdriver(&mut self, options: &NewSessionRequest) -> Result<NewSessionResponse> {
-        self.call("WebDriver:NewSession", options)
+    // AFAIK the semantics for newSession is that it should be called for each connection
+    fn new_session(&mut self, options: &NewSessionRequest) -> Result<NewSessionResponse> {
+        self.send::<commands::NewSession>(options)
     }
 
     /// Refresh the current page
     pub fn refresh(&mut self) -> Result<()> {
-        let _: Empty = match self.compatibility {
-            Compatibility::Marionette => self.call("refresh", Empty {})?,
-            Compatibility::Webdriver => self.call("WebDriver:Refresh", Empty {})?,
-        };
+        self.send::<commands::Refresh>(&Empty {})?;
         Ok(())
     }
 
     /// Go back to the previous page
     pub fn go_back(&mut self) -> Result<()> {
-        let _: Empty = match self.compatibility {
-            Compatibility::Marionette => self.call("goBack", Empty {})?,
-            Compatibility::Webdriver => self.call("WebDriver:Back", Empty {})?,
-        };
+        self.send::<commands::Back>(&Empty {})?;
         Ok(())
     }
 
     /// Go forward to the next page in history
     pub fn go_forward(&mut self) -> Result<()> {
-        let _: Empty = match self.compatibility {
-            Compatibility::Marionette => self.call("goForward", Empty {})?,
-            Compatibility::Webdriver => self.call("WebDriver:Forward", Empty {})?,
-        };
+        self.send::<commands::Forward>(&Empty {})?;
         Ok(())
     }
 
     /// Get the window title
     pub fn get_title(&mut self) -> Result<String> {
-        let resp: ResponseValue<_> = match self.compatibility {
-            Compatibility::Marionette => self.call("getTitle", Empty {})?,
-            Compatibility::Webdriver => self.call("WebDriver:GetTitle", Empty {})?,
-        };
+        let resp = self.send::<commands::GetTitle>(&Empty {})?;
         Ok(resp.value)
     }
 
     /// Navigate to an URL
     pub fn get(&mut self, url: &str) -> Result<()> {
-        let url_arg = to_value(GetCommand::from(url))?;
-        let _: Empty = match self.compatibility {
-            Compatibility::Marionette => self.call("get", url_arg)?,
-            Compatibility::Webdriver => self.call("WebDriver:Navigate", url_arg)?,
-        };
+        self.send::<commands::Navigate>(&GetCommand::from(url))?;
         Ok(())
     }
     pub fn navigate(&mut self, url: &str) -> Result<()> {
@@ -282,53 +375,35 @@ impl MarionetteConnection {
 
     /// Get the page url
     pub fn get_url(&mut self) -> Result<String> {
-        let resp: ResponseValue<_> = match self.compatibility {
-            Compatibility::Marionette => self.call("getCurrentUrl", Empty {})?,
-            Compatibility::Webdriver => self.call("WebDriver:GetCurrentURL", Empty {})?,
-        };
+        let resp = self.send::<commands::GetCurrentUrl>(&Empty {})?;
         Ok(resp.value)
     }
 
     /// Returns the handle for the current window
     pub fn get_window_handle(&mut self) -> Result<WindowHandle> {
-        let resp: ResponseValue<_> = match self.compatibility {
-            Compatibility::Marionette => self.call("getWindowHandle", Empty {})?,
-            Compatibility::Webdriver => self.call("WebDriver:GetWindowHandle", Empty {})?,
-        };
+        let resp = self.send::<commands::GetWindowHandle>(&Empty {})?;
         Ok(resp.value)
     }
 
     /// Returns a list of windows in the current context
     pub fn get_window_handles(&mut self) -> Result<Vec<WindowHandle>> {
-        match self.compatibility {
-            Compatibility::Marionette => self.call("getWindowHandles", Empty {}),
-            Compatibility::Webdriver => self.call("WebDriver:GetWindowHandles", Empty {}),
-        }
+        self.send::<commands::GetWindowHandles>(&Empty {})
     }
 
     /// Switch to the specified window
     pub fn switch_to_window(&mut self, win: &WindowHandle) -> Result<()> {
-        let _: Empty = match self.compatibility {
-            Compatibility::Marionette => self.call("switchToWindow", win)?,
-            Compatibility::Webdriver => self.call("WebDriver:SwitchToWindow", win)?,
-        };
+        self.send::<commands::SwitchToWindow>(win)?;
         Ok(())
     }
 
     pub fn get_context(&mut self) -> Result<Context> {
-        let resp = match self.compatibility {
-            Compatibility::Marionette => self.call("getContext", Empty {})?,
-            Compatibility::Webdriver => self.call("Marionette:GetContext", Empty {})?,
-        };
+        let resp = self.send::<commands::GetContext>(&Empty {})?;
         Context::from_value(resp)
     }
 
     pub fn set_context(&mut self, ctx: Context) -> Result<()> {
         let arg: ContextValue = ctx.into();
-        let _: Empty = match self.compatibility {
-            Compatibility::Marionette => self.call("setContext", arg)?,
-            Compatibility::Webdriver => self.call("Marionette:SetContext", arg)?,
-        };
+        self.send::<commands::SetContext>(&arg)?;
         Ok(())
     }
 
@@ -336,19 +411,13 @@ impl MarionetteConnection {
     ///
     /// The return value is any JSON type returned by the script
     pub fn execute_script(&mut self, script: &Script) -> Result<JsonValue> {
-        let resp: ResponseValue<_> = match self.compatibility {
-            Compatibility::Marionette => self.call("executeScript", script)?,
-            Compatibility::Webdriver => self.call("WebDriver:ExecuteScript", script)?,
-        };
+        let resp = self.send::<commands::ExecuteScript>(script)?;
         Ok(resp.value)
     }
 
     /// Sets global timeouts for various operations
     pub fn set_timeouts(&mut self, t: Timeouts) -> Result<()> {
-        let _: Empty = match self.compatibility {
-            Compatibility::Marionette => self.call("timeouts", t)?,
-            Compatibility::Webdriver => self.call("WebDriver:SetTimeouts", t)?,
-        };
+        self.send::<commands::SetTimeouts>(&t)?;
         self.timeouts = Some(t);
         Ok(())
     }
@@ -362,19 +431,13 @@ impl MarionetteConnection {
     /// Scripts executed this way can terminate with a result using the function
     /// `marionetteScriptFinished(result)`.
     pub fn execute_async_script(&mut self, script: &Script) -> Result<JsonValue> {
-        let resp: ResponseValue<_> = match self.compatibility {
-            Compatibility::Marionette => self.call("executeAsyncScript", script)?,
-            Compatibility::Webdriver => self.call("WebDriver:ExecuteAsyncScript", script)?,
-        };
+        let resp = self.send::<commands::ExecuteAsyncScript>(script)?;
         Ok(resp.value)
     }
 
     /// Returns the page source
     pub fn get_page_source(&mut self) -> Result<String> {
-        let resp: ResponseValue<_> = match self.compatibility {
-            Compatibility::Marionette => self.call("getPageSource", Empty {})?,
-            Compatibility::Webdriver => self.call("WebDriver:GetPageSource", Empty {})?,
-        };
+        let resp = self.send::<commands::GetPageSource>(&Empty {})?;
         Ok(resp.value)
     }
 
@@ -385,10 +448,7 @@ impl MarionetteConnection {
             using: method,
             element: inside.map(|elem| elem.reference.to_owned()),
         };
-        match self.compatibility {
-            Compatibility::Marionette => self.call("findElements", query),
-            Compatibility::Webdriver => self.call("WebDriver:FindElements", query),
-        }
+        self.send::<commands::FindElements>(&query)
     }
 
     pub fn get_element_attribute(&mut self, elem: &ElementRef, attrname: &str) -> Result<Option<String>> {
@@ -396,10 +456,7 @@ impl MarionetteConnection {
             id: elem.reference.to_owned(),
             name: Some(attrname.to_owned()),
         };
-        let resp: ResponseValue<_> = match self.compatibility {
-            Compatibility::Marionette => self.call("getElementAttribute", arg)?,
-            Compatibility::Webdriver => self.call("WebDriver:GetElementAttribute", arg)?,
-        };
+        let resp = self.send::<commands::GetElementAttribute>(&arg)?;
         Ok(resp.value)
     }
 
@@ -408,10 +465,7 @@ impl MarionetteConnection {
             id: elem.reference.to_owned(),
             name: Some(propname.to_owned()),
         };
-        let resp: ResponseValue<_> = match self.compatibility {
-            Compatibility::Marionette => self.call("getElementProperty", arg)?,
-            Compatibility::Webdriver => self.call("WebDriver:GetElementProperty", arg)?,
-        };
+        let resp = self.send::<commands::GetElementProperty>(&arg)?;
         Ok(resp.value)
     }
 
@@ -420,50 +474,35 @@ impl MarionetteConnection {
             id: elem.reference.to_owned(),
             name: None,
         };
-        let resp: ResponseValue<_> = match self.compatibility {
-            Compatibility::Marionette => self.call("getElementText", arg)?,
-            Compatibility::Webdriver => self.call("WebDriver:GetElementText", arg)?,
-        };
+        let resp = self.send::<commands::GetElementText>(&arg)?;
         Ok(resp.value)
     }
 
     pub fn get_active_frame(&mut self) -> Result<Option<ElementRef>> {
-        let resp: ResponseValue<_> = match self.compatibility {
-            Compatibility::Marionette => self.call("getActiveFrame", Empty {})?,
-            Compatibility::Webdriver => self.call("WebDriver:GetActiveFrame", Empty {})?,
-        };
+        let resp = self.send::<commands::GetActiveFrame>(&Empty {})?;
         Ok(resp.value)
     }
 
     /// Switch to the given frame. If None switches to the top frame
     pub fn switch_to_frame(&mut self, elem: Option<ElementRef>) -> Result<()> {
         let arg = FrameSwitch::from_element(false, elem);
-        let _: Empty = match self.compatibility {
-            Compatibility::Marionette => self.call("switchToFrame", arg)?,
-            Compatibility::Webdriver => self.call("WebDriver:SwitchToFrame", arg)?,
-        };
+        self.send::<commands::SwitchToFrame>(&arg)?;
         Ok(())
     }
 
     pub fn switch_to_parent_frame(&mut self) -> Result<()> {
-        let _: Empty = match self.compatibility {
-            Compatibility::Marionette => self.call("switchToParentFrame", Empty {})?,
-            Compatibility::Webdriver => self.call("WebDriver:SwitchToParentFrame", Empty {})?,
-        };
+        self.send::<commands::SwitchToParentFrame>(&Empty {})?;
         Ok(())
     }
 
     /// Close the application
     pub fn quit(mut self) -> Result<()> {
-        let _: Empty = match self.compatibility {
-            Compatibility::Marionette => self.call("quitApplication", Empty {})?,
-            Compatibility::Webdriver => self.call("Marionette:Quit", Empty {})?,
-        };
+        self.send::<commands::Quit>(&Empty {})?;
         Ok(())
     }
 
     /// Install XPI from the given path
-    pub fn addon_install(mut self, path: &Path) -> Result<()> {
+    pub fn addon_install(&mut self, path: &Path) -> Result<()> {
         let abspath = if path.is_relative() {
             let mut absolute_path = env::current_dir()?;
             absolute_path.push(path);
@@ -473,14 +512,13 @@ impl MarionetteConnection {
         };
 
         let arg = AddonInstall { path: &abspath };
-        let _: Empty = match self.compatibility {
-            Compatibility::Marionette => self.call("addon:install", arg)?,
-            Compatibility::Webdriver => self.call("Addon:Install", arg)?,
-        };
+        self.send::<commands::InstallAddon>(&arg)?;
         Ok(())
     }
 
-    fn with_context<T, F>(&mut self, ctx: Context, f: F) -> Result<T>
+    /// Run `f` in another context, e.g. `Context::Chrome` for the browser
+    /// UI. The previous context is restored afterwards, also when `f` fails.
+    pub fn with_context<T, F>(&mut self, ctx: Context, f: F) -> Result<T>
             where F: FnOnce(&mut MarionetteConnection) -> Result<T> {
         let prev = self.get_context()?;
         self.set_context(ctx)?;
@@ -523,12 +561,12 @@ impl MarionetteConnection {
     }
 
     pub fn add_cookie(&mut self, cookie: &Cookie) -> Result<Empty> {
-        self.call("WebDriver:AddCookie", AddCookie { cookie: cookie })
+        self.send::<commands::AddCookieCommand>(&AddCookie { cookie })
     }
 
     /// Get a list of cookies
     pub fn get_cookies(&mut self) -> Result<Vec<Cookie>> {
-        self.call("WebDriver:GetCookies", Empty {})
+        self.send::<commands::GetCookies>(&Empty {})
     }
 
 }
@@ -596,13 +634,22 @@ impl Into<ContextValue> for Context {
     }
 }
 
+/// Errors that mean the other side went away
+fn connection_error(err: io::Error) -> MarionetteError {
+    match err.kind() {
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe => MarionetteError::ConnectionLost(err),
+        _ => MarionetteError::Io(err),
+    }
+}
+
 /// Read data in the format `length:data`. The entire frame must be valid UTF8.
-fn readframe<R: BufRead>(r: &mut R) -> io::Result<String> {
+pub fn readframe<R: BufRead>(r: &mut R) -> io::Result<String> {
     let mut lenbuf = Vec::new();
     // Read length prefix
     let bytes = r.read_until(b':', &mut lenbuf)?;
     if bytes == 0 {
-        return Err(Error::new(ErrorKind::InvalidData, "Invalid frame"));
+        return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed"));
     }
 
     let len_str = str::from_utf8(&lenbuf[..bytes-1])
@@ -618,9 +665,9 @@ fn readframe<R: BufRead>(r: &mut R) -> io::Result<String> {
 }
 
 /// Prepend string with length: and push it down the wire
-fn sendframe<W: Write>(w: &mut W, data: &str) -> io::Result<()> {
+pub fn sendframe<W: Write>(w: &mut W, data: &str) -> io::Result<()> {
     debug!("-> {}", data);
-    w.write_all(format!("{}:", data.len()).as_bytes())?;
-    w.write_all(data.as_bytes())?;
+    // A single write, so the frame is not held back waiting for an ack
+    w.write_all(format!("{}:{}", data.len(), data).as_bytes())?;
     Ok(())
 }
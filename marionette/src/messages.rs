@@ -13,12 +13,12 @@ use serde::de::{Visitor, MapAccess};
 use serde::de::Error as DeError;
 use super::MarionetteError;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct ServerInfo {
     pub marionetteProtocol: u64,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct ErrorObject {
     pub error: String,
     pub message: String,
@@ -37,6 +37,9 @@ pub struct CapabilityRequest {
 #[derive(Deserialize, Debug)]
 pub struct Capabilities {
     pub timeouts: Option<Timeouts>,
+    /// Older versions call it `version`
+    #[serde(alias = "version")]
+    pub browserVersion: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -162,6 +165,8 @@ pub type ContextValue = ResponseValue<String>;
 pub struct Script {
     script: String,
     sandbox: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    newSandbox: bool,
     args: Value,
     scriptTimeout: Option<u64>,
 }
@@ -171,6 +176,7 @@ impl Script {
         Script {
             script: src.to_owned(),
             sandbox: "default".to_owned(),
+            newSandbox: false,
             // execute_script accepts null here, but execute_async_script does not
             // default to an empty array
             args: Value::Array(Vec::new()),
@@ -185,11 +191,17 @@ impl Script {
         Ok(())
     }
 
-    /// Execute the script in a named sandbox
+    /// Execute the script in a named sandbox. Sandboxes keep their globals
+    /// until the page is unloaded.
     pub fn sandbox(&mut self, name: &str) {
         self.sandbox = name.to_owned()
     }
 
+    /// Replace the named sandbox with a fresh one before the script runs
+    pub fn new_sandbox(&mut self, new: bool) {
+        self.newSandbox = new
+    }
+
     /// Set execution timeout for script (ms)
     ///
     /// This value overrides the global scriptTimeout.
@@ -368,7 +380,7 @@ pub struct AddonInstall<'a> {
     pub path: &'a Path,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Cookie {
     pub name: String,
     pub value: String,
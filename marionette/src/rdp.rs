@@ -0,0 +1,417 @@
+//! Firefox devtools remote debugging protocol (RDP)
+//!
+//! Firefox started with `--start-debugger-server PORT` accepts devtools
+//! clients, using the same `length:json` framing as marionette. Packets are
+//! sent to an actor, `{"to": actor, "type": request, ...}`, which replies
+//! with `{"from": actor, ...}`. Actors also send notifications, these carry
+//! a `type` and are kept until `next_event()` reads them.
+//!
+//! The devtools actors give access to things marionette cannot do, such as
+//! object grips of the web console, applied styles and network monitoring.
+//!
+//! ```no_run
+//! use marionette::rdp::RdpConnection;
+//!
+//! let mut rdp = RdpConnection::connect(6000).unwrap();
+//! let target = rdp.selected_target().unwrap();
+//! let grip = rdp.evaluate(&target, "document.title").unwrap();
+//! println!("{}", rdp.grip_value(&grip, 1).unwrap());
+//! ```
+
+use std::collections::VecDeque;
+use std::io::{self, BufReader};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use serde_json;
+
+use super::{readframe, sendframe, JsonValue, MarionetteError, Result};
+use messages::ErrorObject;
+
+/// A notification sent by an actor
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// The actor that sent the notification
+    pub from: String,
+    /// e.g. `evaluationResult` or `resources-available-array`
+    pub kind: String,
+    pub packet: JsonValue,
+}
+
+impl Event {
+    /// The resources of a `resources-available-array` notification of a
+    /// watcher, for a resource type such as `network-event`
+    pub fn resources(&self, resource_type: &str) -> Vec<&JsonValue> {
+        if self.kind != "resources-available-array" {
+            return Vec::new();
+        }
+        self.packet["array"].as_array().into_iter().flatten()
+            .filter(|entry| entry[0] == resource_type)
+            .flat_map(|entry| entry[1].as_array().into_iter().flatten())
+            .collect()
+    }
+}
+
+/// A tab of the browser, as listed by the root actor
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tab {
+    /// The tab descriptor actor
+    pub actor: String,
+    pub url: String,
+    pub title: String,
+    pub selected: bool,
+    pub form: JsonValue,
+}
+
+/// The actors debugging the content of a tab
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub actor: String,
+    pub console_actor: String,
+    pub inspector_actor: String,
+    pub form: JsonValue,
+}
+
+/// A value returned by the debugger, objects and long strings are only
+/// references to an actor
+#[derive(Debug, Clone, PartialEq)]
+pub enum Grip {
+    /// Strings, numbers, booleans and null
+    Value(JsonValue),
+    Undefined,
+    /// Numbers without a JSON form: `NaN`, `Infinity`, `-Infinity`, `-0`
+    Number(String),
+    LongString { actor: String, initial: String, length: u64 },
+    Object { actor: String, class: String, preview: JsonValue },
+    /// Grips of any other type, e.g. symbols
+    Other(JsonValue),
+}
+
+impl Grip {
+    pub fn from_json(value: JsonValue) -> Grip {
+        let kind = match value["type"].as_str() {
+            Some(kind) if value.is_object() => kind.to_owned(),
+            _ => return Grip::Value(value),
+        };
+        let field = |name: &str| value[name].as_str().unwrap_or("").to_owned();
+        match kind.as_str() {
+            "undefined" => Grip::Undefined,
+            "null" => Grip::Value(JsonValue::Null),
+            "NaN" | "Infinity" | "-Infinity" | "-0" => Grip::Number(kind),
+            "longString" => Grip::LongString {
+                actor: field("actor"),
+                initial: field("initial"),
+                length: value["length"].as_u64().unwrap_or(0),
+            },
+            "object" => Grip::Object {
+                actor: field("actor"),
+                class: field("class"),
+                preview: value["preview"].clone(),
+            },
+            _ => Grip::Other(value),
+        }
+    }
+}
+
+/// The style of an element found by `RdpConnection::inspect()`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeStyle {
+    /// e.g. `DIV`
+    pub node_name: String,
+    /// Rules that apply to the element, most specific first, as
+    /// `(selector, css text)`. The style attribute has the selector
+    /// `element`.
+    pub rules: Vec<(String, String)>,
+    /// Computed values by property name
+    pub computed: Vec<(String, String)>,
+}
+
+pub struct RdpConnection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    /// Notifications read while waiting for a reply
+    events: VecDeque<Event>,
+    greeting: JsonValue,
+}
+
+impl RdpConnection {
+    /// Connect to the devtools server of firefox on `port`
+    pub fn connect(port: u16) -> Result<Self> {
+        let stream = TcpStream::connect(("127.0.0.1", port))?;
+        let writer = stream.try_clone()?;
+        let mut conn = RdpConnection {
+            reader: BufReader::new(stream),
+            writer,
+            events: VecDeque::new(),
+            greeting: JsonValue::Null,
+        };
+        conn.greeting = conn.read_packet()?;
+        if conn.greeting["from"] != "root" {
+            return Err(MarionetteError::UnexpectedType);
+        }
+        Ok(conn)
+    }
+
+    /// The first packet of the root actor, with the application type and
+    /// the traits of the server
+    pub fn greeting(&self) -> &JsonValue {
+        &self.greeting
+    }
+
+    /// Fail reads that take longer than `timeout`, `None` waits forever
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.writer.set_read_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Send a request to an actor and wait for its reply. The fields of
+    /// `params` are added to the packet.
+    pub fn request(&mut self, to: &str, kind: &str, params: JsonValue) -> Result<JsonValue> {
+        let mut packet = match params {
+            JsonValue::Object(map) => JsonValue::Object(map),
+            JsonValue::Null => serde_json::json!({}),
+            _ => return Err(MarionetteError::UnexpectedType),
+        };
+        packet["to"] = to.into();
+        packet["type"] = kind.into();
+        sendframe(&mut self.writer, &packet.to_string())?;
+
+        loop {
+            let packet = self.read_packet()?;
+            if packet["from"] != to {
+                self.queue(packet);
+            } else if packet.get("error").is_some() {
+                let field = |name: &str| packet[name].as_str().unwrap_or("").to_owned();
+                return Err(MarionetteError::Call(ErrorObject {
+                    error: field("error"),
+                    message: field("message"),
+                    stacktrace: String::new(),
+                }));
+            } else if packet.get("type").is_some() {
+                self.queue(packet);
+            } else {
+                return Ok(packet);
+            }
+        }
+    }
+
+    /// The next notification, waits for one unless some were received
+    /// while waiting for replies
+    pub fn next_event(&mut self) -> Result<Event> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            let packet = self.read_packet()?;
+            self.queue(packet);
+        }
+    }
+
+    /// Wait for a notification matching `pred`, others are kept for
+    /// `next_event()`
+    fn wait_event<F: Fn(&Event) -> bool>(&mut self, pred: F) -> Result<Event> {
+        if let Some(pos) = self.events.iter().position(&pred) {
+            return Ok(self.events.remove(pos).unwrap());
+        }
+        loop {
+            let packet = self.read_packet()?;
+            let event = event_from(packet);
+            if pred(&event) {
+                return Ok(event);
+            }
+            self.events.push_back(event);
+        }
+    }
+
+    fn queue(&mut self, packet: JsonValue) {
+        self.events.push_back(event_from(packet));
+    }
+
+    fn read_packet(&mut self) -> Result<JsonValue> {
+        let frame = readframe(&mut self.reader).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => MarionetteError::ConnectionLost(err),
+            _ => MarionetteError::Io(err),
+        })?;
+        debug!("<- {}", frame);
+        Ok(serde_json::from_str(&frame)?)
+    }
+
+    /// The open tabs
+    pub fn list_tabs(&mut self) -> Result<Vec<Tab>> {
+        let reply = self.request("root", "listTabs", JsonValue::Null)?;
+        let tabs = reply["tabs"].as_array().ok_or(MarionetteError::UnexpectedType)?;
+        Ok(tabs.iter().map(|form| {
+            let field = |name: &str| form[name].as_str().unwrap_or("").to_owned();
+            Tab {
+                actor: field("actor"),
+                url: field("url"),
+                title: field("title"),
+                selected: form["selected"].as_bool().unwrap_or(false),
+                form: form.clone(),
+            }
+        }).collect())
+    }
+
+    /// The actors for the content of a tab
+    pub fn target(&mut self, tab: &Tab) -> Result<Target> {
+        // Older versions list the target actors with the tab
+        let form = match tab.form.get("consoleActor") {
+            Some(_) => tab.form.clone(),
+            None => self.request(&tab.actor, "getTarget", JsonValue::Null)?["frame"].take(),
+        };
+        let field = |name: &str| form[name].as_str().map(str::to_owned).ok_or(MarionetteError::UnexpectedType);
+        Ok(Target {
+            actor: field("actor")?,
+            console_actor: field("consoleActor")?,
+            inspector_actor: field("inspectorActor")?,
+            form: form.clone(),
+        })
+    }
+
+    /// The actors for the selected tab
+    pub fn selected_target(&mut self) -> Result<Target> {
+        let tabs = self.list_tabs()?;
+        let tab = tabs.iter().find(|tab| tab.selected).or(tabs.first())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No open tab"))?;
+        self.target(tab)
+    }
+
+    /// Evaluate an expression in the web console of a tab. Exceptions are
+    /// returned as a `javascript error`.
+    pub fn evaluate(&mut self, target: &Target, text: &str) -> Result<Grip> {
+        let console = target.console_actor.as_str();
+        let reply = self.request(console, "evaluateJSAsync", serde_json::json!({"text": text}))?;
+        let id = reply["resultID"].clone();
+        let mut event = self.wait_event(|event| {
+            event.from == console && event.kind == "evaluationResult" && event.packet["resultID"] == id
+        })?;
+        let result = &mut event.packet;
+        if result.get("exception").is_some_and(|exception| !exception.is_null()) {
+            let message = match result["exceptionMessage"].take() {
+                JsonValue::String(message) => message,
+                other => other.to_string(),
+            };
+            return Err(MarionetteError::Call(ErrorObject {
+                error: "javascript error".to_owned(),
+                message,
+                stacktrace: String::new(),
+            }));
+        }
+        Ok(Grip::from_json(result["result"].take()))
+    }
+
+    /// The own properties of an object grip, accessors are skipped
+    pub fn properties(&mut self, actor: &str) -> Result<Vec<(String, Grip)>> {
+        let mut reply = self.request(actor, "prototypeAndProperties", JsonValue::Null)?;
+        let props = match reply["ownProperties"].take() {
+            JsonValue::Object(props) => props,
+            _ => return Err(MarionetteError::UnexpectedType),
+        };
+        Ok(props.into_iter()
+            .filter_map(|(name, mut desc)| desc.get_mut("value").map(|value| (name, Grip::from_json(value.take()))))
+            .collect())
+    }
+
+    /// The full text of a long string grip
+    pub fn long_string(&mut self, actor: &str, length: u64) -> Result<String> {
+        let reply = self.request(actor, "substring", serde_json::json!({"start": 0, "end": length}))?;
+        reply["substring"].as_str().map(str::to_owned).ok_or(MarionetteError::UnexpectedType)
+    }
+
+    /// Convert a grip to JSON, the properties of objects are fetched up to
+    /// `depth` levels deep. Deeper objects are replaced by their class
+    /// name, values without a JSON form by their name.
+    pub fn grip_value(&mut self, grip: &Grip, depth: usize) -> Result<JsonValue> {
+        Ok(match grip {
+            Grip::Value(value) => value.clone(),
+            Grip::Undefined => JsonValue::Null,
+            Grip::Number(name) => name.as_str().into(),
+            Grip::LongString { actor, length, .. } => self.long_string(actor, *length)?.into(),
+            Grip::Object { class, .. } if depth == 0 => format!("[object {}]", class).into(),
+            Grip::Object { actor, class, .. } => {
+                let props = self.properties(actor)?;
+                let mut map = serde_json::Map::new();
+                for (name, grip) in &props {
+                    map.insert(name.clone(), self.grip_value(grip, depth - 1)?);
+                }
+                if class == "Array" {
+                    let len = map.get("length").and_then(JsonValue::as_u64).unwrap_or(0);
+                    (0..len).map(|i| map.remove(&i.to_string()).unwrap_or(JsonValue::Null)).collect()
+                } else {
+                    JsonValue::Object(map)
+                }
+            }
+            Grip::Other(value) => value.clone(),
+        })
+    }
+
+    /// The applied rules and computed style of the first element matching
+    /// a CSS selector
+    pub fn inspect(&mut self, target: &Target, selector: &str) -> Result<NodeStyle> {
+        let inspector = target.inspector_actor.as_str();
+        let reply = self.request(inspector, "getWalker", serde_json::json!({"options": {}}))?;
+        let walker = &reply["walker"];
+        let (walker, root) = match (walker["actor"].as_str(), walker["root"]["actor"].as_str()) {
+            (Some(walker), Some(root)) => (walker.to_owned(), root.to_owned()),
+            _ => return Err(MarionetteError::UnexpectedType),
+        };
+        let reply = self.request(&walker, "querySelector", serde_json::json!({"node": root, "selector": selector}))?;
+        let node = &reply["node"];
+        let node_actor = node["actor"].as_str().ok_or_else(|| MarionetteError::Call(ErrorObject {
+            error: "no such element".to_owned(),
+            message: format!("No element matches {}", selector),
+            stacktrace: String::new(),
+        }))?;
+
+        let reply = self.request(inspector, "getPageStyle", JsonValue::Null)?;
+        let page_style = reply["pageStyle"]["actor"].as_str().ok_or(MarionetteError::UnexpectedType)?.to_owned();
+        let applied = self.request(&page_style, "getApplied", serde_json::json!({
+            "node": node_actor, "inherited": false, "matchedSelectors": true, "filter": "user",
+        }))?;
+        let rules = applied["entries"].as_array().into_iter().flatten()
+            .map(|entry| {
+                let rule = &entry["rule"];
+                let selector = match rule["selectors"].as_array() {
+                    Some(selectors) => selectors.iter().filter_map(JsonValue::as_str).collect::<Vec<_>>().join(", "),
+                    None => "element".to_owned(),
+                };
+                let text = rule["cssText"].as_str().or(rule["authoredText"].as_str()).unwrap_or("");
+                (selector, text.trim().to_owned())
+            })
+            .collect();
+        let computed = self.request(&page_style, "getComputed", serde_json::json!({
+            "node": node_actor, "markMatched": false, "onlyMatched": false, "filter": "user",
+        }))?;
+        let computed = computed["computed"].as_object().into_iter().flatten()
+            .filter_map(|(name, prop)| Some((name.clone(), prop["value"].as_str()?.to_owned())))
+            .collect();
+
+        Ok(NodeStyle {
+            node_name: node["nodeName"].as_str().unwrap_or("").to_owned(),
+            rules,
+            computed,
+        })
+    }
+
+    /// Receive the resources of a tab as `resources-available-array`
+    /// notifications, e.g. `network-event` for network monitoring, see
+    /// `Event::resources()`. Returns the watcher actor.
+    pub fn watch_resources(&mut self, tab: &Tab, resource_types: &[&str]) -> Result<String> {
+        let reply = self.request(&tab.actor, "getWatcher", JsonValue::Null)?;
+        let watcher = reply["actor"].as_str().ok_or(MarionetteError::UnexpectedType)?.to_owned();
+        self.request(&watcher, "watchResources", serde_json::json!({"resourceTypes": resource_types}))?;
+        Ok(watcher)
+    }
+
+    /// Stop the notifications started by `watch_resources()`
+    pub fn unwatch_resources(&mut self, watcher: &str, resource_types: &[&str]) -> Result<()> {
+        self.request(watcher, "unwatchResources", serde_json::json!({"resourceTypes": resource_types}))?;
+        Ok(())
+    }
+}
+
+fn event_from(mut packet: JsonValue) -> Event {
+    let from = packet["from"].as_str().unwrap_or("").to_owned();
+    let kind = packet["type"].as_str().unwrap_or("").to_owned();
+    Event { from, kind, packet: packet.take() }
+}
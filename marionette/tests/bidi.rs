@@ -0,0 +1,86 @@
+#![cfg(feature = "bidi")]
+
+extern crate marionette;
+extern crate serde_json;
+extern crate tungstenite;
+
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use marionette::MarionetteError;
+use marionette::bidi::{BidiConnection, Event};
+use serde_json::{json, Value};
+use tungstenite::Message;
+
+/// A remote agent for a single client. Subscriptions are answered with an
+/// event before the response, `script.evaluate` fails and other commands
+/// return their method and params. The connection is closed on
+/// `browser.close`.
+fn fake_remote_agent() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut socket = tungstenite::accept(stream).unwrap();
+        while let Ok(Message::Text(text)) = socket.read() {
+            let msg: Value = serde_json::from_str(&text).unwrap();
+            let send = |socket: &mut tungstenite::WebSocket<_>, value: Value| {
+                socket.send(Message::Text(value.to_string())).unwrap();
+            };
+            let resp = match msg["method"].as_str().unwrap() {
+                "session.new" => json!({"type": "success", "id": msg["id"],
+                                        "result": {"sessionId": "fake", "capabilities": {}}}),
+                "session.subscribe" => {
+                    for event in msg["params"]["events"].as_array().unwrap() {
+                        send(&mut socket, json!({"type": "event", "method": event, "params": {"text": "hello"}}));
+                    }
+                    json!({"type": "success", "id": msg["id"], "result": {}})
+                }
+                "script.evaluate" => json!({"type": "error", "id": msg["id"], "error": "javascript error",
+                                            "message": "boom"}),
+                "browser.close" => break,
+                method => json!({"type": "success", "id": msg["id"],
+                                 "result": {"method": method, "params": msg["params"]}}),
+            };
+            send(&mut socket, resp);
+        }
+    });
+    port
+}
+
+#[test]
+fn commands_and_events() {
+    let port = fake_remote_agent();
+    let mut bidi = BidiConnection::connect(port).unwrap();
+    assert_eq!(bidi.session_id(), Some("fake"));
+
+    let result = bidi.send("browsingContext.getTree", json!({"maxDepth": 0})).unwrap();
+    assert_eq!(result, json!({"method": "browsingContext.getTree", "params": {"maxDepth": 0}}));
+
+    bidi.subscribe(&["log.entryAdded", "network"], None).unwrap();
+    let events: Vec<Event> = bidi.events().try_iter().collect();
+    let methods: Vec<&str> = events.iter().map(|event| event.method.as_str()).collect();
+    assert_eq!(methods, vec!["log.entryAdded", "network"]);
+    assert_eq!(events[0].params, json!({"text": "hello"}));
+
+    match bidi.send("script.evaluate", json!({})) {
+        Err(MarionetteError::Call(err)) => {
+            assert_eq!(err.error, "javascript error");
+            assert_eq!(err.message, "boom");
+        }
+        res => panic!("Unexpected result {:?}", res),
+    }
+}
+
+#[test]
+fn connection_lost() {
+    let port = fake_remote_agent();
+    let mut bidi = BidiConnection::connect(port).unwrap();
+    match bidi.send("browser.close", json!({})) {
+        Err(MarionetteError::ConnectionLost(_)) => (),
+        res => panic!("Unexpected result {:?}", res),
+    }
+    assert!(bidi.events().recv_timeout(Duration::from_secs(5)).is_err());
+    assert!(bidi.send("session.status", json!({})).is_err());
+}
@@ -0,0 +1,39 @@
+//! A marionette server for tests that do not need a browser, shared by the
+//! tests of the marionette and ff crates
+
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::thread;
+
+use marionette::{readframe, sendframe};
+use serde_json::{self, Value};
+
+/// Listen on a free port and serve clients one after the other. `handler`
+/// gets the name and parameters of each command and returns the response:
+/// an object with an `error` field is sent as an error, `null` closes the
+/// connection and anything else is the value.
+pub fn fake_marionette<F>(mut handler: F) -> u16
+        where F: FnMut(&str, &Value) -> Value + Send + 'static {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            sendframe(&mut writer, r#"{"applicationType":"gecko","marionetteProtocol":3}"#).unwrap();
+            while let Ok(frame) = readframe(&mut reader) {
+                let msg: Value = serde_json::from_str(&frame).unwrap();
+                let resp = match handler(msg[2].as_str().unwrap(), &msg[3]) {
+                    Value::Null => break,
+                    err if err.get("error").is_some() => serde_json::json!([1, msg[1], err, null]),
+                    value => serde_json::json!([1, msg[1], null, value]),
+                };
+                if sendframe(&mut writer, &resp.to_string()).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    port
+}
@@ -0,0 +1,96 @@
+extern crate marionette;
+extern crate serde_json;
+
+mod common;
+
+use std::sync::mpsc;
+
+use marionette::*;
+use marionette::commands::Command;
+use marionette::messages::Empty;
+
+use common::fake_marionette;
+
+/// A marionette server for a firefox version, legacy versions only know the
+/// unprefixed commands. The names of the commands received are sent to the
+/// returned channel.
+fn fake_firefox(version: &'static str) -> (u16, mpsc::Receiver<String>) {
+    let (tx, rx) = mpsc::channel();
+    let legacy = Compatibility::from_version(version) == Some(Compatibility::Marionette);
+    let port = fake_marionette(move |name, _| {
+        let _ = tx.send(name.to_owned());
+        if legacy == name.contains(':') {
+            serde_json::json!({"error": "unknown command", "message": name, "stacktrace": ""})
+        } else if name.ends_with("ewSession") {
+            serde_json::json!({"sessionId": "fake", "capabilities": {"browserVersion": version}})
+        } else {
+            serde_json::json!({"value": name})
+        }
+    });
+    (port, rx)
+}
+
+#[test]
+fn dialect_from_version() {
+    assert_eq!(Compatibility::from_version("115.0.2esr"), Some(Compatibility::Webdriver));
+    assert_eq!(Compatibility::from_version("56.0"), Some(Compatibility::Webdriver));
+    assert_eq!(Compatibility::from_version("52.9.0esr"), Some(Compatibility::Marionette));
+    assert_eq!(Compatibility::from_version("unknown"), None);
+}
+
+#[test]
+fn legacy_browser() {
+    let (port, commands) = fake_firefox("52.0");
+    let mut conn = MarionetteConnection::connect(port).unwrap();
+    assert_eq!(conn.compatibility(), Compatibility::Marionette);
+    assert_eq!(conn.browser_version(), Some("52.0"));
+    assert_eq!(conn.get_title().unwrap(), "getTitle");
+
+    assert!(!conn.supports(&commands::GetCookies::INFO));
+    match conn.get_cookies() {
+        Err(MarionetteError::Unsupported(msg)) => assert!(msg.contains("WebDriver:GetCookies"), "{}", msg),
+        res => panic!("Unexpected result {:?}", res),
+    }
+    let sent: Vec<String> = commands.try_iter().collect();
+    assert_eq!(sent, vec!["WebDriver:NewSession", "newSession", "getTitle", "getTitle"]);
+}
+
+#[test]
+fn known_dialect() {
+    let (port, commands) = fake_firefox("52.0");
+    let conn = MarionetteConnection::connect_as(port, Compatibility::Marionette).unwrap();
+    assert_eq!(conn.compatibility(), Compatibility::Marionette);
+    assert_eq!(commands.recv().unwrap(), "newSession");
+
+    let (port, _) = fake_firefox("115.0");
+    let mut conn = MarionetteConnection::connect(port).unwrap();
+    assert_eq!(conn.compatibility(), Compatibility::Webdriver);
+    assert_eq!(conn.get_url().unwrap(), "WebDriver:GetCurrentURL");
+}
+
+#[test]
+fn typed_and_raw_commands() {
+    let (port, commands) = fake_firefox("115.0");
+    let mut conn = MarionetteConnection::connect(port).unwrap();
+    let title = conn.send::<commands::GetTitle>(&Empty {}).unwrap();
+    assert_eq!(title.value, "WebDriver:GetTitle");
+
+    let value = conn.call_raw("Marionette:GetScreenOrientation", serde_json::json!({})).unwrap();
+    assert_eq!(value, serde_json::json!({"value": "Marionette:GetScreenOrientation"}));
+    let sent: Vec<String> = commands.try_iter().collect();
+    assert_eq!(sent, vec!["WebDriver:NewSession", "WebDriver:GetTitle", "WebDriver:GetTitle",
+                          "Marionette:GetScreenOrientation"]);
+}
+
+#[test]
+fn session_errors_are_not_hidden() {
+    let port = fake_marionette(|_, _| {
+        serde_json::json!({"error": "session not created", "message": "Session already started", "stacktrace": ""})
+    });
+
+    match MarionetteConnection::connect(port) {
+        Err(MarionetteError::Call(err)) => assert_eq!(err.error, "session not created"),
+        Err(err) => panic!("Unexpected error {}", err),
+        Ok(_) => panic!("Unexpected session"),
+    }
+}
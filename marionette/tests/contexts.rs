@@ -65,3 +65,17 @@ fn set_pref_fails_but_restores_ctx() {
     assert!(conn.set_pref("startup.homepage_welcome_url", JsonValue::from_str("42").unwrap()).is_err());
     assert_eq!(conn.get_context().unwrap(), Context::Content);
 }
+
+#[test]
+fn with_context() {
+    let _ = env_logger::init();
+    let mut conn = MarionetteConnection::connect(2828).unwrap();
+
+    let uri = conn.with_context(Context::Chrome, |conn| {
+        assert_eq!(conn.get_context().unwrap(), Context::Chrome);
+        conn.execute_script(&Script::new("return document.documentURI"))
+    }).unwrap();
+    // browser.xul before firefox 69, browser.xhtml later
+    assert!(uri.as_str().unwrap().starts_with("chrome://browser/content/browser."), "{}", uri);
+    assert_eq!(conn.get_context().unwrap(), Context::Content);
+}
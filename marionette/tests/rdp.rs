@@ -0,0 +1,139 @@
+extern crate marionette;
+extern crate serde_json;
+
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::thread;
+
+use marionette::{readframe, sendframe, MarionetteError};
+use marionette::rdp::{Grip, RdpConnection};
+use serde_json::{json, Value};
+
+/// A devtools server with a single tab. Evaluations of `throw` fail, other
+/// expressions return an array. A notification of another actor is sent
+/// before each reply.
+fn fake_devtools() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut send = move |value: Value| sendframe(&mut writer, &value.to_string()).unwrap();
+        send(json!({"from": "root", "applicationType": "browser", "traits": {}}));
+        while let Ok(frame) = readframe(&mut reader) {
+            let msg: Value = serde_json::from_str(&frame).unwrap();
+            let to = msg["to"].as_str().unwrap();
+            send(json!({"from": "other", "type": "tabListChanged"}));
+            let reply = match (to, msg["type"].as_str().unwrap()) {
+                ("root", "listTabs") => json!({"tabs": [
+                    {"actor": "tab1", "url": "about:blank", "title": "Blank", "selected": false},
+                    {"actor": "tab2", "url": "https://example.com/", "title": "Example", "selected": true},
+                ]}),
+                (tab, "getTarget") => json!({"frame": {
+                    "actor": format!("{}-target", tab), "consoleActor": "console", "inspectorActor": "inspector",
+                }}),
+                ("console", "evaluateJSAsync") => {
+                    send(json!({"from": "console", "resultID": "r1"}));
+                    let result = match msg["text"].as_str().unwrap() {
+                        "throw" => json!({"exception": {"type": "object"}, "exceptionMessage": "Error: boom"}),
+                        _ => json!({"result": {"type": "object", "class": "Array", "actor": "array"}}),
+                    };
+                    let mut event = json!({"from": "console", "type": "evaluationResult", "resultID": "r1"});
+                    event.as_object_mut().unwrap().extend(result.as_object().unwrap().clone());
+                    send(event);
+                    continue;
+                }
+                ("array", "prototypeAndProperties") => json!({"ownProperties": {
+                    "0": {"value": 1}, "1": {"value": {"type": "undefined"}},
+                    "2": {"value": {"type": "longString", "actor": "long", "initial": "ab", "length": 4}},
+                    "length": {"value": 3},
+                }}),
+                ("long", "substring") => json!({"substring": "abcd"}),
+                ("inspector", "getWalker") => json!({"walker": {"actor": "walker", "root": {"actor": "doc"}}}),
+                ("walker", "querySelector") if msg["selector"] == "p" => {
+                    json!({"node": {"actor": "node", "nodeName": "P"}})
+                }
+                ("walker", "querySelector") => json!({}),
+                ("inspector", "getPageStyle") => json!({"pageStyle": {"actor": "style"}}),
+                ("style", "getApplied") => json!({"entries": [
+                    {"rule": {"cssText": "color: red;"}},
+                    {"rule": {"selectors": ["p", ".note"], "cssText": " margin: 0; "}},
+                ]}),
+                ("style", "getComputed") => json!({"computed": {"color": {"value": "rgb(255, 0, 0)"}}}),
+                ("tab2", "getWatcher") => json!({"actor": "watcher"}),
+                ("watcher", "watchResources") => {
+                    send(json!({"from": "watcher", "type": "resources-available-array", "array": [
+                        ["network-event", [{"url": "https://example.com/", "method": "GET"}]],
+                    ]}));
+                    json!({})
+                }
+                (_, kind) => json!({"error": "unrecognizedPacketType", "message": kind}),
+            };
+            let mut reply = reply;
+            reply["from"] = to.into();
+            send(reply);
+        }
+    });
+    port
+}
+
+#[test]
+fn tabs_and_evaluation() {
+    let mut rdp = RdpConnection::connect(fake_devtools()).unwrap();
+    assert_eq!(rdp.greeting()["applicationType"], "browser");
+
+    let tabs = rdp.list_tabs().unwrap();
+    assert_eq!(tabs.len(), 2);
+    assert_eq!(tabs[1].url, "https://example.com/");
+    let target = rdp.selected_target().unwrap();
+    assert_eq!(target.actor, "tab2-target");
+
+    let grip = rdp.evaluate(&target, "[1, undefined, 'abcd']").unwrap();
+    assert_eq!(grip, Grip::Object { actor: "array".into(), class: "Array".into(), preview: Value::Null });
+    assert_eq!(rdp.grip_value(&grip, 0).unwrap(), json!("[object Array]"));
+    assert_eq!(rdp.grip_value(&grip, 1).unwrap(), json!([1, null, "abcd"]));
+
+    match rdp.evaluate(&target, "throw") {
+        Err(MarionetteError::Call(err)) => {
+            assert_eq!(err.error, "javascript error");
+            assert_eq!(err.message, "Error: boom");
+        }
+        res => panic!("Unexpected result {:?}", res),
+    }
+    match rdp.request("nobody", "ping", Value::Null) {
+        Err(MarionetteError::Call(err)) => assert_eq!(err.error, "unrecognizedPacketType"),
+        res => panic!("Unexpected result {:?}", res),
+    }
+
+    // Notifications of other actors are kept
+    let event = rdp.next_event().unwrap();
+    assert_eq!((event.from.as_str(), event.kind.as_str()), ("other", "tabListChanged"));
+}
+
+#[test]
+fn styles_and_network() {
+    let mut rdp = RdpConnection::connect(fake_devtools()).unwrap();
+    let target = rdp.selected_target().unwrap();
+    let style = rdp.inspect(&target, "p").unwrap();
+    assert_eq!(style.node_name, "P");
+    assert_eq!(style.rules, vec![("element".to_owned(), "color: red;".to_owned()),
+                                 ("p, .note".to_owned(), "margin: 0;".to_owned())]);
+    assert_eq!(style.computed, vec![("color".to_owned(), "rgb(255, 0, 0)".to_owned())]);
+    match rdp.inspect(&target, "div") {
+        Err(MarionetteError::Call(err)) => assert_eq!(err.error, "no such element"),
+        res => panic!("Unexpected result {:?}", res),
+    }
+
+    let tab = rdp.list_tabs().unwrap().into_iter().find(|tab| tab.selected).unwrap();
+    assert_eq!(rdp.watch_resources(&tab, &["network-event"]).unwrap(), "watcher");
+    let event = loop {
+        let event = rdp.next_event().unwrap();
+        if event.from == "watcher" {
+            break event;
+        }
+    };
+    let requests = event.resources("network-event");
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0]["method"], "GET");
+}